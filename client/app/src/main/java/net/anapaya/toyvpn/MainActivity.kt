@@ -52,6 +52,7 @@ class MainActivity : AppCompatActivity() {
 
     private var isConnected = false
     private var isConnecting = false
+    private var isReconnecting = false
     private val VPN_REQUEST_CODE = 0x0F
 
     private val requestPermissionLauncher = registerForActivityResult(
@@ -78,6 +79,14 @@ class MainActivity : AppCompatActivity() {
                     cardError.visibility = View.VISIBLE
                     updateUI()
                 }
+                ToyVpnService.ACTION_STATE_CHANGED -> {
+                    // Names of the library's VpnState values.
+                    val state = intent.getStringExtra(ToyVpnService.EXTRA_STATE)
+                    isConnecting = state == "HANDSHAKING"
+                    isConnected = state == "CONNECTED" || state == "RECONNECTING"
+                    isReconnecting = state == "RECONNECTING"
+                    updateUI()
+                }
                 ToyVpnService.ACTION_STATS_UPDATE -> {
                     val duration = intent.getLongExtra(ToyVpnService.EXTRA_STATS_DURATION, 0L)
 
@@ -228,6 +237,7 @@ class MainActivity : AppCompatActivity() {
             addAction(ToyVpnService.ACTION_STATS_UPDATE)
             addAction(ToyVpnService.ACTION_VPN_ESTABLISHED)
             addAction(ToyVpnService.ACTION_VPN_FAILED)
+            addAction(ToyVpnService.ACTION_STATE_CHANGED)
         }
         if (android.os.Build.VERSION.SDK_INT >= android.os.Build.VERSION_CODES.TIRAMISU) {
              registerReceiver(statsReceiver, filter, Context.RECEIVER_NOT_EXPORTED)
@@ -310,7 +320,11 @@ class MainActivity : AppCompatActivity() {
             layoutLogin.visibility = View.GONE
             layoutSetup.visibility = View.GONE
             layoutConnected.visibility = View.VISIBLE
-            tvConnectedServer.text = "Connected to ${etServerAddressFull.text}"
+            tvConnectedServer.text = if (isReconnecting) {
+                "Reconnecting to ${etServerAddressFull.text}…"
+            } else {
+                "Connected to ${etServerAddressFull.text}"
+            }
         } else {
             layoutConnected.visibility = View.GONE
             val prefs = getSharedPreferences("toyvpn_prefs", Context.MODE_PRIVATE)
@@ -3,26 +3,51 @@ package net.anapaya.toyvpn
 import android.app.NotificationChannel
 import android.app.NotificationManager
 import android.app.PendingIntent
+import android.content.BroadcastReceiver
+import android.content.Context
 import android.content.Intent
+import android.content.IntentFilter
+import android.net.ConnectivityManager
+import android.net.Network
+import android.net.NetworkCapabilities
 import android.net.VpnService
+import android.os.BatteryManager
 import android.os.Build
 import android.os.ParcelFileDescriptor
+import android.os.PowerManager
 import android.util.Log
 import androidx.core.app.NotificationCompat
 import kotlinx.coroutines.*
+import kotlinx.coroutines.sync.Mutex
+import kotlinx.coroutines.sync.withLock
+import java.io.File
 import java.net.InetSocketAddress
 import java.nio.channels.DatagramChannel
 
 // Import UniFFI generated bindings
+import uniffi.toyvpn_client.NetworkType
+import uniffi.toyvpn_client.Route
+import uniffi.toyvpn_client.RouteVerifier
+import uniffi.toyvpn_client.StateListener
+import uniffi.toyvpn_client.StopCode
+import uniffi.toyvpn_client.StopInfo
 import uniffi.toyvpn_client.ToyVpnClient
 import uniffi.toyvpn_client.VpnCallback
+import uniffi.toyvpn_client.VpnClientConfig
+import uniffi.toyvpn_client.VpnException
+import uniffi.toyvpn_client.VpnState
+import uniffi.toyvpn_client.recommendedTunReadStrategy
 
 class ToyVpnService : VpnService() {
 
     private var interfacePfd: ParcelFileDescriptor? = null
     private var job: Job? = null
     private var vpnClient: ToyVpnClient? = null
+    private var networkCallback: ConnectivityManager.NetworkCallback? = null
+    private var powerReceiver: BroadcastReceiver? = null
     private val scope = CoroutineScope(Dispatchers.IO)
+    /** Serializes interface rebuilds, so the newest routes win. */
+    private val interfaceLock = Mutex()
 
     companion object {
         const val ACTION_CONNECT = "net.anapaya.toyvpn.CONNECT"
@@ -35,6 +60,9 @@ class ToyVpnService : VpnService() {
         const val EXTRA_SNAP_TOKEN = "snap_token"
         const val EXTRA_ENDHOST_API = "endhost_api"
         const val EXTRA_EDGETUN_HOST = "edgetun_host"
+        /** DNS for the tunnel as provisioned for the server, string array lists. */
+        const val EXTRA_DNS_SERVERS = "dns_servers"
+        const val EXTRA_SEARCH_DOMAINS = "search_domains"
 
         const val EXTRA_STATS_DURATION = "stats_duration"
         const val EXTRA_STATS_TX_BYTES = "stats_tx_bytes"
@@ -47,9 +75,12 @@ class ToyVpnService : VpnService() {
         const val ACTION_VPN_ESTABLISHED = "net.anapaya.toyvpn.VPN_ESTABLISHED"
         const val ACTION_VPN_FAILED = "net.anapaya.toyvpn.VPN_FAILED"
         const val ACTION_PROBE = "net.anapaya.toyvpn.PROBE"
+        const val ACTION_STATE_CHANGED = "net.anapaya.toyvpn.STATE_CHANGED"
+        const val EXTRA_STATE = "state"
         const val EXTRA_ERROR_MESSAGE = "error_message"
 
         private const val CHANNEL_ID = "ToyVpnChannel"
+        private const val ROUTE_CHECK_INTERVAL_MS = 30_000u
     }
 
     override fun onStartCommand(intent: Intent?, flags: Int, startId: Int): Int {
@@ -64,7 +95,10 @@ class ToyVpnService : VpnService() {
             val endhostApi = intent.getStringExtra(EXTRA_ENDHOST_API) ?: "http://s01.choeg2.snap.anapaya.net:5001"
             val edgetunHost = intent.getStringExtra(EXTRA_EDGETUN_HOST) ?: "[64-2:0:a7,10.0.0.2]:9000"
 
-            startVpn(snapToken, endhostApi, edgetunHost)
+            val dnsServers = intent.getStringArrayListExtra(EXTRA_DNS_SERVERS) ?: emptyList()
+            val searchDomains = intent.getStringArrayListExtra(EXTRA_SEARCH_DOMAINS) ?: emptyList()
+
+            startVpn(snapToken, endhostApi, edgetunHost, dnsServers, searchDomains)
             return START_STICKY
         } else if (intent?.action == ACTION_PROBE) {
             if (job != null && job!!.isActive) {
@@ -84,7 +118,13 @@ class ToyVpnService : VpnService() {
         return START_NOT_STICKY
     }
 
-    private fun startVpn(snapToken: String, endhostApi: String, edgetunHost: String) {
+    private fun startVpn(
+        snapToken: String,
+        endhostApi: String,
+        edgetunHost: String,
+        dnsServers: List<String>,
+        searchDomains: List<String>,
+    ) {
         if (job != null) return
 
         createNotificationChannel()
@@ -106,13 +146,36 @@ class ToyVpnService : VpnService() {
 
         // Initialize Rust Client
         try {
-            vpnClient = ToyVpnClient()
+            vpnClient = ToyVpnClient.create().apply {
+                setStatePath(File(filesDir, "toyvpn.state").path)
+                // Set before the handshake, so the UI also sees HANDSHAKING.
+                setStateListener(object : StateListener {
+                    override fun onStateChange(state: VpnState) {
+                        Log.d("ToyVPN", "Connection state: $state")
+                        sendBroadcast(Intent(ACTION_STATE_CHANGED).apply {
+                            setPackage(packageName)
+                            putExtra(EXTRA_STATE, state.name)
+                        })
+                    }
+                })
+                val strategy = recommendedTunReadStrategy(Build.VERSION.SDK_INT.toUInt())
+                setTransportOptions(transportOptions().copy(tunReadStrategy = strategy))
+                // Handed back in the config for the interface.
+                setTunnelDns(dnsServers, searchDomains)
+            }
         } catch (e: Exception) {
             Log.e("ToyVPN", "Failed to load Rust client", e)
+            sendBroadcast(Intent(ACTION_VPN_FAILED).apply {
+                setPackage(packageName)
+                putExtra(EXTRA_ERROR_MESSAGE, e.message ?: "Failed to initialize VPN client")
+            })
             stopSelf()
             return
         }
 
+        registerNetworkMonitor()
+        registerPowerMonitor()
+
         job = scope.launch {
             try {
                 runVpn(snapToken, endhostApi, edgetunHost)
@@ -122,7 +185,7 @@ class ToyVpnService : VpnService() {
                 Log.e("ToyVPN", "VPN Error", e)
                 sendBroadcast(Intent(ACTION_VPN_FAILED).apply {
                     setPackage(packageName)
-                    putExtra(EXTRA_ERROR_MESSAGE, e.message ?: "Unknown error")
+                    putExtra(EXTRA_ERROR_MESSAGE, errorMessage(e))
                 })
             } finally {
                 if (isActive) stopVpn()
@@ -130,10 +193,24 @@ class ToyVpnService : VpnService() {
         }
     }
 
+    private fun errorMessage(e: Exception): String = when (e) {
+        is VpnException.InvalidServerAddress -> "Invalid server address"
+        is VpnException.EndhostApiUnreachable -> "Could not reach the SNAP, check your connection"
+        is VpnException.TlsError -> "Secure connection to the server failed"
+        is VpnException.AuthRejected, is VpnException.AuthFailed -> "Your login was rejected, please log in again"
+        is VpnException.NoAddressAssigned -> "The server did not assign an address"
+        is VpnException.AlreadyRunning -> "VPN is already running"
+        is VpnException.Timeout -> "Connection timed out, the server may be unreachable"
+        is VpnException.Cancelled -> "Connection cancelled"
+        else -> e.message ?: "Unknown error"
+    }
+
     private fun stopVpn() {
         try {
             Log.d("ToyVPN", "Stopping VPN...")
             vpnClient?.stop()
+            unregisterNetworkMonitor()
+            unregisterPowerMonitor()
 
             interfacePfd?.close()
             interfacePfd = null
@@ -156,7 +233,8 @@ class ToyVpnService : VpnService() {
     private suspend fun runVpn(snapToken: String, endhostApi: String, edgetunHost: String) {
         Log.d("ToyVPN", "Performing handshake...")
         val config = try {
-            vpnClient?.handshake(snapToken, endhostApi, edgetunHost)
+            // Suspends instead of blocking; cancelling the job cancels the handshake.
+            vpnClient?.handshakeAsync(snapToken, endhostApi, listOf(edgetunHost))
         } catch (e: Exception) {
             Log.e("ToyVPN", "Handshake failed", e)
             throw e
@@ -168,37 +246,13 @@ class ToyVpnService : VpnService() {
         Log.d("ToyVPN", "Handshake successful. IP: ${config.clientIp}")
 
         // 3. Setup TUN interface
-        Log.d("ToyVPN", "Setting up VPN interface")
-        val builder = Builder()
-        builder.setSession("ToyVPN")
-        builder.addAddress(config.clientIp, 24)
-        builder.addDisallowedApplication(packageName)
-
-        for (route in config.routes) {
-             builder.addRoute(route.destination, route.prefixLength)
-        }
-        builder.setMtu(1500)
-
-        try {
-            interfacePfd = builder.establish()
-        } catch (e: Exception) {
-            Log.e("ToyVPN", "Builder establish failed", e)
-            throw e
-        }
-
-        if (interfacePfd == null) {
-             throw IllegalStateException("Could not establish VPN")
-        }
-
-        // Detach TUN FD to pass ownership to Rust
-        val tunFd = interfacePfd!!.detachFd()
-        Log.d("ToyVPN", "VPN Interface TUN FD: $tunFd")
-        interfacePfd = null
+        val tunFd = establishInterface(config, emptyList())
 
         val startTime = System.currentTimeMillis()
         var lastTxBytes = 0L
         var lastRxBytes = 0L
         var lastUpdateTime = startTime
+        var sessionConfig = config
 
         // Create Callback
         val callback = object : VpnCallback {
@@ -225,8 +279,8 @@ class ToyVpnService : VpnService() {
                     putExtra(EXTRA_STATS_RX_BYTES, rx)
                     putExtra(EXTRA_STATS_TX_RATE, txRate)
                     putExtra(EXTRA_STATS_RX_RATE, rxRate)
-                    putExtra(EXTRA_ASSIGNED_IP, config.clientIp)
-                    val routesStr = config.routes.joinToString("\n") { "${it.destination}/${it.prefixLength}" }
+                    putExtra(EXTRA_ASSIGNED_IP, sessionConfig.clientIp)
+                    val routesStr = sessionConfig.routes.joinToString("\n") { "${it.destination}/${it.prefixLength}" }
                     putExtra(EXTRA_ROUTES, routesStr)
                 }
                 sendBroadcast(intent)
@@ -234,17 +288,77 @@ class ToyVpnService : VpnService() {
                 updateNotification(tx, rx, txRate, rxRate)
             }
 
-            override fun onStop(reason: String) {
-                Log.d("ToyVPN", "Rust client stopped: $reason")
-                if (reason != "Stopped") {
-                     Log.e("ToyVPN", "Rust reported error: $reason")
+            // Broadcast by the state listener.
+            override fun onStateChange(state: VpnState) {}
+
+            override fun onStop(info: StopInfo) {
+                Log.d("ToyVPN", "Rust client stopped: ${info.code} ${info.detail}")
+                when (info.code) {
+                    StopCode.REVOKED -> {
+                        // Another VPN app took over; tell the user rather than retrying.
+                        sendBroadcast(Intent(ACTION_VPN_FAILED).apply {
+                            setPackage(packageName)
+                            putExtra(EXTRA_ERROR_MESSAGE, "VPN was taken over by another app")
+                        })
+                        stopVpn()
+                    }
+                    StopCode.SERVER_CLOSED -> {
+                        // The server ended the session for good, e.g. because our access was revoked.
+                        sendBroadcast(Intent(ACTION_VPN_FAILED).apply {
+                            setPackage(packageName)
+                            putExtra(EXTRA_ERROR_MESSAGE, "Server closed the session (${info.detail})")
+                        })
+                        stopVpn()
+                    }
+                    StopCode.ERROR -> {
+                        // E.g. reconnecting was given up on, or a new session needs the
+                        // interface rebuilt; the user starts over.
+                        Log.e("ToyVPN", "Rust reported error: ${info.errorChain.joinToString(": ")}")
+                        sendBroadcast(Intent(ACTION_VPN_FAILED).apply {
+                            setPackage(packageName)
+                            putExtra(EXTRA_ERROR_MESSAGE, info.detail)
+                        })
+                        stopVpn()
+                    }
+                    StopCode.STOPPED, StopCode.DETACHED -> {}
+                }
+            }
+
+            override fun onSessionRotated(config: VpnClientConfig) {
+                // Sessions with other addresses stop the client with an error instead.
+                Log.d("ToyVPN", "Session rotated. IP: ${config.clientIp}")
+                sessionConfig = config
+            }
+
+            override fun onMtuChanged(mtu: UInt) {
+                // The interface keeps its MTU; the client clamps new TCP connections to
+                // the path MTU, so only large non-TCP packets are affected.
+                Log.i("ToyVPN", "Path MTU changed: $mtu")
+            }
+
+            override fun onDnsLeakBlocked(resolver: String) {
+                Log.w("ToyVPN", "Blocked DNS query to unapproved resolver $resolver")
+            }
+
+            override fun onRoutingConflict(missingRoutes: List<Route>) {
+                val routes = missingRoutes.joinToString { "${it.destination}/${it.prefixLength}" }
+                Log.w("ToyVPN", "Routes no longer installed: $routes")
+            }
+
+            override fun onSplitTunnelRoutesChanged(routes: List<Route>) {
+                // Routes can't be added to an established interface, so build a new one
+                // and hand it over; the session itself carries on.
+                scope.launch {
+                    interfaceLock.withLock { rebuildInterface(sessionConfig, routes) }
                 }
             }
         }
 
+        vpnClient?.setRouteVerifier(vpnRouteVerifier(), ROUTE_CHECK_INTERVAL_MS)
+
         try {
             Log.d("ToyVPN", "Starting Rust client with tunFd=$tunFd")
-            vpnClient?.start(tunFd, callback)
+            vpnClient?.startAsync(tunFd, callback)
             Log.d("ToyVPN", "Rust client started")
 
             sendBroadcast(Intent(ACTION_VPN_ESTABLISHED).apply {
@@ -260,6 +374,127 @@ class ToyVpnService : VpnService() {
         }
     }
 
+    private fun rebuildInterface(config: VpnClientConfig, splitRoutes: List<Route>) {
+        var fd: Int? = null
+        try {
+            fd = establishInterface(config, splitRoutes)
+            vpnClient?.replaceTun(fd)
+            Log.d("ToyVPN", "Interface rebuilt with ${splitRoutes.size} split tunnel routes")
+        } catch (e: Exception) {
+            Log.e("ToyVPN", "Failed to rebuild interface for split tunnel routes", e)
+            fd?.let { ParcelFileDescriptor.adoptFd(it).close() }
+        }
+    }
+
+    /**
+     * Establishes the VPN interface for [config] plus [extraRoutes] and returns its fd,
+     * whose ownership passes to the caller. Establishing again replaces the previous
+     * interface, which then no longer carries traffic.
+     */
+    private fun establishInterface(config: VpnClientConfig, extraRoutes: List<Route>): Int {
+        Log.d("ToyVPN", "Setting up VPN interface")
+        val builder = Builder()
+        builder.setSession("ToyVPN")
+        config.assignedAddresses.zip(config.assignedPrefixLengths).forEach { (address, prefixLength) ->
+            builder.addAddress(address, prefixLength)
+        }
+        builder.addDisallowedApplication(packageName)
+
+        for (route in config.routes + extraRoutes) {
+             builder.addRoute(route.destination, route.prefixLength)
+        }
+        for (server in config.dnsServers) {
+            builder.addDnsServer(server)
+        }
+        for (domain in config.searchDomains) {
+            builder.addSearchDomain(domain)
+        }
+        builder.setMtu(1500)
+
+        val pfd = try {
+            builder.establish()
+        } catch (e: Exception) {
+            Log.e("ToyVPN", "Builder establish failed", e)
+            throw e
+        } ?: throw IllegalStateException("Could not establish VPN")
+
+        // Detach TUN FD to pass ownership to Rust
+        val tunFd = pfd.detachFd()
+        Log.d("ToyVPN", "VPN Interface TUN FD: $tunFd")
+        return tunFd
+    }
+
+    private fun registerNetworkMonitor() {
+        val connectivityManager = getSystemService(ConnectivityManager::class.java)
+        val callback = object : ConnectivityManager.NetworkCallback() {
+            override fun onCapabilitiesChanged(network: Network, caps: NetworkCapabilities) {
+                val type = when {
+                    caps.hasTransport(NetworkCapabilities.TRANSPORT_WIFI) -> NetworkType.WIFI
+                    caps.hasTransport(NetworkCapabilities.TRANSPORT_CELLULAR) -> NetworkType.CELLULAR
+                    caps.hasTransport(NetworkCapabilities.TRANSPORT_ETHERNET) -> NetworkType.ETHERNET
+                    else -> NetworkType.OTHER
+                }
+                val metered = !caps.hasCapability(NetworkCapabilities.NET_CAPABILITY_NOT_METERED)
+                vpnClient?.setNetworkType(type, metered)
+            }
+        }
+        // Our own traffic is excluded from the VPN, so the default network is the underlying one.
+        connectivityManager.registerDefaultNetworkCallback(callback)
+        networkCallback = callback
+    }
+
+    private fun unregisterNetworkMonitor() {
+        networkCallback?.let {
+            getSystemService(ConnectivityManager::class.java).unregisterNetworkCallback(it)
+        }
+        networkCallback = null
+    }
+
+    /** Reports the routes of our VPN network as the system sees them. */
+    private fun vpnRouteVerifier() = object : RouteVerifier {
+        override fun installedRoutes(): List<Route> {
+            val connectivityManager = getSystemService(ConnectivityManager::class.java)
+            return connectivityManager.allNetworks
+                .filter {
+                    connectivityManager.getNetworkCapabilities(it)
+                        ?.hasTransport(NetworkCapabilities.TRANSPORT_VPN) == true
+                }
+                .flatMap { connectivityManager.getLinkProperties(it)?.routes.orEmpty() }
+                .map { Route(it.destination.address.hostAddress ?: "", it.destination.prefixLength) }
+        }
+    }
+
+    private fun registerPowerMonitor() {
+        val receiver = object : BroadcastReceiver() {
+            override fun onReceive(context: Context, intent: Intent) = reportPowerState()
+        }
+        val filter = IntentFilter().apply {
+            addAction(Intent.ACTION_SCREEN_ON)
+            addAction(Intent.ACTION_SCREEN_OFF)
+            addAction(Intent.ACTION_POWER_CONNECTED)
+            addAction(Intent.ACTION_POWER_DISCONNECTED)
+            addAction(PowerManager.ACTION_POWER_SAVE_MODE_CHANGED)
+        }
+        registerReceiver(receiver, filter)
+        powerReceiver = receiver
+        reportPowerState()
+    }
+
+    private fun reportPowerState() {
+        val powerManager = getSystemService(PowerManager::class.java)
+        val batteryManager = getSystemService(BatteryManager::class.java)
+        vpnClient?.setPowerState(
+            powerManager.isInteractive,
+            powerManager.isPowerSaveMode,
+            batteryManager.isCharging
+        )
+    }
+
+    private fun unregisterPowerMonitor() {
+        powerReceiver?.let { unregisterReceiver(it) }
+        powerReceiver = null
+    }
+
     private fun createNotificationChannel() {
         if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.O) {
             val serviceChannel = NotificationChannel(
@@ -0,0 +1,118 @@
+/*
+ * C API of the ToyVPN client core, for embedders that can't use the UniFFI bindings.
+ *
+ * All functions are thread-safe. Strings are NUL-terminated UTF-8. Strings and
+ * configs returned by the library must be released with toyvpn_string_free()
+ * and toyvpn_config_free() respectively.
+ */
+#ifndef TOYVPN_CLIENT_H
+#define TOYVPN_CLIENT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ToyVpnClient ToyVpnClient;
+
+typedef struct {
+    char *destination;
+    int32_t prefix_length;
+} ToyVpnRoute;
+
+typedef struct {
+    char *client_ip; /* the first of assigned_addresses */
+    char **assigned_addresses;
+    size_t assigned_addresses_len;
+    ToyVpnRoute *routes;
+    size_t routes_len;
+    char **dns_servers; /* as set with toyvpn_client_set_tunnel_dns(), if any */
+    size_t dns_servers_len;
+    char **search_domains;
+    size_t search_domains_len;
+    int32_t *assigned_prefix_lengths; /* one per assigned address, assigned_addresses_len long */
+} ToyVpnConfig;
+
+#define TOYVPN_STOP_STOPPED 0
+#define TOYVPN_STOP_REVOKED 1       /* the TUN fd was taken away, e.g. by another VPN app */
+#define TOYVPN_STOP_DETACHED 2
+#define TOYVPN_STOP_SERVER_CLOSED 3 /* the server ended the session, detail says why */
+#define TOYVPN_STOP_ERROR 4
+
+typedef struct {
+    int32_t code; /* one of TOYVPN_STOP_* */
+    const char *detail; /* for logs, not localized */
+    bool retriable; /* a new handshake and start may succeed without user intervention */
+    const char *const *error_chain; /* for TOYVPN_STOP_ERROR, outermost error first */
+    size_t error_chain_len;
+} ToyVpnStopInfo;
+
+#define TOYVPN_STATE_IDLE 0
+#define TOYVPN_STATE_HANDSHAKING 1
+#define TOYVPN_STATE_CONNECTED 2
+#define TOYVPN_STATE_RECONNECTING 3 /* the session failed, a new one is being established */
+#define TOYVPN_STATE_STOPPING 4
+#define TOYVPN_STATE_STOPPED 5      /* reported right after on_stop */
+
+/*
+ * Callbacks may be invoked from any thread and may be NULL. The info passed to
+ * on_stop, the config passed to on_session_rotated, the resolver passed to
+ * on_dns_leak_blocked and the routes passed to on_routing_conflict and
+ * on_split_tunnel_routes_changed are only valid for the duration of the call.
+ */
+typedef struct {
+    void *context;
+    void (*on_stats_update)(void *context, uint64_t tx_bytes, uint64_t rx_bytes);
+    void (*on_stop)(void *context, const ToyVpnStopInfo *info);
+    void (*on_session_rotated)(void *context, const ToyVpnConfig *config);
+    void (*on_mtu_changed)(void *context, uint32_t mtu);
+    void (*on_dns_leak_blocked)(void *context, const char *resolver);
+    void (*on_routing_conflict)(void *context, const ToyVpnRoute *missing_routes, size_t len);
+    void (*on_state_change)(void *context, int32_t state); /* one of TOYVPN_STATE_* */
+    /* The complete set of host routes learned for the split tunnel domains. */
+    void (*on_split_tunnel_routes_changed)(void *context, const ToyVpnRoute *routes, size_t len);
+} ToyVpnCallbacks;
+
+/* Returns NULL if the client could not be initialized. */
+ToyVpnClient *toyvpn_client_new(void);
+void toyvpn_client_free(ToyVpnClient *client);
+
+/* Return 0 on success, -1 on failure with *out_error set (if out_error is non-NULL). */
+int toyvpn_client_handshake(const ToyVpnClient *client,
+                            const char *snap_token,
+                            const char *endhost_api,
+                            const char *edgetun_server,
+                            ToyVpnConfig **out_config,
+                            char **out_error);
+/* Makes a toyvpn_client_handshake() in progress on another thread fail. */
+void toyvpn_client_cancel_handshake(const ToyVpnClient *client);
+int toyvpn_client_start(const ToyVpnClient *client,
+                        int tun_fd,
+                        ToyVpnCallbacks callbacks,
+                        char **out_error);
+/* Hands the running data plane a new TUN fd (ownership is taken), without a new
+ * handshake. The previous fd is closed. */
+int toyvpn_client_replace_tun(const ToyVpnClient *client, int tun_fd, char **out_error);
+/* Sets the DNS servers (IP addresses) and search domains reported in the configs of
+ * subsequent handshakes and rotations. The arrays may be NULL if empty. */
+int toyvpn_client_set_tunnel_dns(const ToyVpnClient *client,
+                                 const char *const *dns_servers,
+                                 size_t dns_servers_len,
+                                 const char *const *search_domains,
+                                 size_t search_domains_len,
+                                 char **out_error);
+void toyvpn_client_stop(const ToyVpnClient *client);
+/* Returns one of TOYVPN_STATE_*. */
+int toyvpn_client_state(const ToyVpnClient *client);
+
+void toyvpn_config_free(ToyVpnConfig *config);
+void toyvpn_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TOYVPN_CLIENT_H */
@@ -0,0 +1,149 @@
+//! Allocation accounting for the data plane's per-packet paths.
+//!
+//! With the `alloc-audit` feature, a counting global allocator is installed and every
+//! [`Scope`] asserts (in debug builds) that no heap allocation happened while it was
+//! alive; [`audit`] does the same for each poll of a future. Deliberate allocations on
+//! rare paths (answering neighbor discovery, clamping an MSS, logging) are wrapped in
+//! [`exempt`]. Without the feature, all of this compiles to nothing.
+
+use std::future::Future;
+
+/// Marks a stretch of per-packet work that must not allocate.
+#[must_use]
+pub struct Scope {
+    #[cfg(feature = "alloc-audit")]
+    what: &'static str,
+    #[cfg(feature = "alloc-audit")]
+    start: u64,
+}
+
+impl Scope {
+    #[cfg_attr(not(feature = "alloc-audit"), allow(unused_variables))]
+    pub fn enter(what: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "alloc-audit")]
+            what,
+            #[cfg(feature = "alloc-audit")]
+            start: counting::allocations(),
+        }
+    }
+}
+
+/// Runs `future`, checking each of its polls as a [`Scope`].
+///
+/// Per-packet work that awaits, e.g. on uplink backpressure, is audited this way; the
+/// time spent suspended is not part of any scope.
+#[cfg_attr(not(feature = "alloc-audit"), allow(unused_variables))]
+pub async fn audit<F: Future>(what: &'static str, future: F) -> F::Output {
+    #[cfg(feature = "alloc-audit")]
+    {
+        let mut future = std::pin::pin!(future);
+        std::future::poll_fn(|cx| {
+            let _scope = Scope::enter(what);
+            future.as_mut().poll(cx)
+        })
+        .await
+    }
+    #[cfg(not(feature = "alloc-audit"))]
+    future.await
+}
+
+/// Runs `f`, not counting its allocations against any enclosing scope.
+pub fn exempt<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "alloc-audit")]
+    {
+        counting::exempt(f)
+    }
+    #[cfg(not(feature = "alloc-audit"))]
+    f()
+}
+
+#[cfg(feature = "alloc-audit")]
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let n = counting::allocations() - self.start;
+        debug_assert!(n == 0, "{}: {n} allocation(s) per packet", self.what);
+    }
+}
+
+#[cfg(feature = "alloc-audit")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        // Const-initialized without a destructor, so it's usable from within the allocator.
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+        static EXEMPTED: Cell<u64> = const { Cell::new(0) };
+    }
+
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Number of allocations made by the current thread so far, not counting exempted
+    /// ones.
+    pub fn allocations() -> u64 {
+        ALLOCATIONS.with(Cell::get) - EXEMPTED.with(Cell::get)
+    }
+
+    pub fn exempt<R>(f: impl FnOnce() -> R) -> R {
+        let start = allocations();
+        let res = f();
+        // Nested exemptions are already excluded from `allocations()`.
+        let n = allocations() - start;
+        EXEMPTED.with(|e| e.set(e.get() + n));
+        res
+    }
+}
+
+#[cfg(all(test, feature = "alloc-audit"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exempt_allocations_dont_count() {
+        let _scope = Scope::enter("test");
+        let v = exempt(|| exempt(|| vec![1u8; 16]).repeat(2));
+        assert_eq!(v.len(), 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 allocation(s) per packet")]
+    fn allocation_in_scope_is_caught() {
+        let _scope = Scope::enter("test");
+        std::hint::black_box(vec![1u8; 16]);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "test poll: 1 allocation(s) per packet")]
+    async fn allocation_in_poll_is_caught() {
+        audit("test poll", async {
+            tokio::task::yield_now().await;
+            std::hint::black_box(vec![1u8; 16]);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,159 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::diagnostics::unix_time_ms;
+use crate::{AuthProvider, AuthToken, VpnError};
+
+/// Tokens are refreshed this long before they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Hands out SNAP tokens from an [`AuthProvider`], caching them until shortly before
+/// they expire, or a fixed token passed in by the app.
+pub struct TokenSource {
+    /// `None` for a fixed token, which never expires.
+    provider: Option<Box<dyn AuthProvider>>,
+    cached: Mutex<Option<AuthToken>>,
+    /// Whether the server turned down the cached token.
+    rejected: AtomicBool,
+}
+
+impl TokenSource {
+    pub fn new(provider: Box<dyn AuthProvider>) -> Self {
+        Self {
+            provider: Some(provider),
+            cached: Mutex::new(None),
+            rejected: AtomicBool::new(false),
+        }
+    }
+
+    /// Always hands out `token`, e.g. one passed to `handshake()`.
+    pub fn fixed(token: String) -> Self {
+        Self {
+            provider: None,
+            cached: Mutex::new(Some(AuthToken {
+                token,
+                expires_at_unix_ms: 0,
+            })),
+            rejected: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns a usable token, asking the provider if there is none cached or the cached
+    /// one is about to expire or was rejected. Blocks for as long as the provider does;
+    /// concurrent callers needing a new token may each ask it.
+    pub fn token(&self) -> Result<String, VpnError> {
+        let force_refresh = match &*self.cached.lock().unwrap() {
+            Some(token) if is_fresh(token) && !self.rejected.load(Ordering::Relaxed) => {
+                return Ok(token.token.clone())
+            }
+            Some(_) => true,
+            None => false,
+        };
+        let Some(provider) = &self.provider else {
+            return Err(VpnError::AuthFailed("No token".into()));
+        };
+
+        log::info!("Fetching SNAP token (force refresh: {force_refresh})");
+        let token = catch_unwind(AssertUnwindSafe(|| provider.fetch_token(force_refresh)))
+            .map_err(|_| VpnError::AuthFailed("Auth provider failed".into()))?
+            .filter(|t| !t.token.is_empty())
+            .ok_or_else(|| VpnError::AuthFailed("Auth provider returned no token".into()))?;
+        if !is_fresh(&token) {
+            log::warn!("Auth provider returned a token that is (about to be) expired");
+        }
+        *self.cached.lock().unwrap() = Some(token.clone());
+        self.rejected.store(false, Ordering::Relaxed);
+        Ok(token.token)
+    }
+
+    /// Notes that the server rejected the cached token, so the next [`token`](Self::token)
+    /// has the provider refresh it even if it hasn't expired yet. A fixed token is kept,
+    /// as there is nothing to replace it with.
+    pub fn invalidate(&self) {
+        if self.provider.is_some() {
+            log::info!("SNAP token rejected, refreshing it for the next session");
+            self.rejected.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn is_fresh(token: &AuthToken) -> bool {
+    token.expires_at_unix_ms == 0
+        || token.expires_at_unix_ms > unix_time_ms() + EXPIRY_MARGIN.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::Arc;
+
+    /// Hands out "token-N" for the Nth fetch, valid for `valid_ms`, and "forced-N" if
+    /// asked to refresh.
+    struct Counter {
+        fetches: Arc<AtomicU32>,
+        valid_ms: u64,
+    }
+
+    impl AuthProvider for Counter {
+        fn fetch_token(&self, force_refresh: bool) -> Option<AuthToken> {
+            let n = self.fetches.fetch_add(1, Ordering::Relaxed) + 1;
+            let kind = if force_refresh { "forced" } else { "token" };
+            Some(AuthToken {
+                token: format!("{kind}-{n}"),
+                expires_at_unix_ms: unix_time_ms() + self.valid_ms,
+            })
+        }
+    }
+
+    fn source(valid: Duration) -> (TokenSource, Arc<AtomicU32>) {
+        let fetches = Arc::new(AtomicU32::new(0));
+        let provider = Counter {
+            fetches: fetches.clone(),
+            valid_ms: valid.as_millis() as u64,
+        };
+        (TokenSource::new(Box::new(provider)), fetches)
+    }
+
+    #[test]
+    fn fixed_token_is_handed_out_as_is() {
+        let source = TokenSource::fixed("secret".into());
+        assert_eq!(source.token().unwrap(), "secret");
+        assert_eq!(source.token().unwrap(), "secret");
+    }
+
+    #[test]
+    fn fresh_token_is_cached() {
+        let (source, fetches) = source(Duration::from_secs(3600));
+        assert_eq!(source.token().unwrap(), "token-1");
+        assert_eq!(source.token().unwrap(), "token-1");
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn expiring_token_is_refreshed() {
+        let (source, fetches) = source(EXPIRY_MARGIN / 2);
+        assert_eq!(source.token().unwrap(), "token-1");
+        assert_eq!(source.token().unwrap(), "forced-2");
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn rejected_token_is_refreshed_once() {
+        let (source, fetches) = source(Duration::from_secs(3600));
+        assert_eq!(source.token().unwrap(), "token-1");
+        source.invalidate();
+        assert_eq!(source.token().unwrap(), "forced-2");
+        assert_eq!(source.token().unwrap(), "forced-2");
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn fixed_token_survives_rejection() {
+        let source = TokenSource::fixed("secret".into());
+        source.invalidate();
+        assert_eq!(source.token().unwrap(), "secret");
+    }
+}
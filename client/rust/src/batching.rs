@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::packet::is_tcp_syn;
+
+/// Window over which uplink activity is measured.
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(1);
+/// Packets per window above which traffic is considered interactive.
+const INTERACTIVE_PACKETS: u32 = 8;
+/// Bytes per window above which traffic is considered interactive.
+const INTERACTIVE_BYTES: usize = 4096;
+/// How long to stay in immediate mode after interactive traffic was seen.
+const INTERACTIVE_HOLD: Duration = Duration::from_secs(5);
+
+/// Coalesces uplink packets into periodic batches while only background traffic
+/// (keepalives, sync pings) is flowing, so the cellular radio wakes up less often.
+///
+/// As soon as the traffic looks interactive (a burst above the thresholds or a new
+/// TCP connection) packets are sent immediately again.
+pub struct UplinkBatcher {
+    max_delay: Duration,
+    pending: Vec<Bytes>,
+    flush_at: Option<Instant>,
+    window_start: Instant,
+    window_packets: u32,
+    window_bytes: usize,
+    interactive_until: Instant,
+}
+
+impl UplinkBatcher {
+    /// A `max_delay` of zero disables batching.
+    pub fn new(max_delay: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            max_delay,
+            pending: Vec::new(),
+            flush_at: None,
+            window_start: now,
+            window_packets: 0,
+            window_bytes: 0,
+            interactive_until: now,
+        }
+    }
+
+    /// Changes the batching delay, e.g. after a profile switch; zero disables batching.
+    /// Packets already held keep their deadline.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
+    /// Holds back `packet` if in batching mode, otherwise returns it to be sent right away.
+    pub fn hold(&mut self, packet: Bytes) -> Option<Bytes> {
+        if self.max_delay.is_zero() {
+            return Some(packet);
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.window_start) > ACTIVITY_WINDOW {
+            self.window_start = now;
+            self.window_packets = 0;
+            self.window_bytes = 0;
+        }
+        self.window_packets += 1;
+        self.window_bytes += packet.len();
+        if self.window_packets > INTERACTIVE_PACKETS
+            || self.window_bytes > INTERACTIVE_BYTES
+            || is_tcp_syn(&packet)
+        {
+            if now >= self.interactive_until {
+                log::debug!("Interactive uplink traffic, leaving batching mode");
+            }
+            self.interactive_until = now + INTERACTIVE_HOLD;
+        }
+
+        if now < self.interactive_until {
+            return Some(packet);
+        }
+        self.flush_at.get_or_insert(now + self.max_delay);
+        self.pending.push(packet);
+        None
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Deadline by which pending packets must be sent.
+    pub fn flush_at(&self) -> Instant {
+        self.flush_at
+            .unwrap_or_else(|| Instant::now() + self.max_delay)
+    }
+
+    /// Takes all pending packets, in order. The batch storage is kept for reuse.
+    pub fn take(&mut self) -> std::vec::Drain<'_, Bytes> {
+        self.flush_at = None;
+        self.pending.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    const DELAY: Duration = Duration::from_millis(200);
+
+    /// An IPv4/UDP packet of `len` bytes whose last byte is `tag`.
+    fn packet(len: usize, tag: u8) -> Bytes {
+        let payload = vec![tag; len.max(29) - 28];
+        Bytes::from(fixtures::udp(
+            fixtures::client(),
+            fixtures::server(),
+            &payload,
+        ))
+    }
+
+    fn syn() -> Bytes {
+        Bytes::from(fixtures::syn(fixtures::client(), fixtures::server(), None))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_delay_passes_packets_through() {
+        let mut batcher = UplinkBatcher::new(Duration::ZERO);
+        for tag in 0..3 {
+            assert!(batcher.hold(packet(100, tag)).is_some());
+        }
+        assert!(!batcher.has_pending());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_background_traffic_until_the_first_deadline() {
+        let mut batcher = UplinkBatcher::new(DELAY);
+        let start = Instant::now();
+        assert!(batcher.hold(packet(100, 1)).is_none());
+        assert!(batcher.has_pending());
+        assert_eq!(batcher.flush_at(), start + DELAY);
+
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(batcher.hold(packet(100, 2)).is_none());
+        assert_eq!(batcher.flush_at(), start + DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn syn_switches_to_immediate_mode() {
+        let mut batcher = UplinkBatcher::new(DELAY);
+        assert!(batcher.hold(syn()).is_some());
+        assert!(batcher.hold(packet(100, 1)).is_some());
+
+        tokio::time::advance(INTERACTIVE_HOLD - Duration::from_millis(1)).await;
+        assert!(batcher.hold(packet(100, 2)).is_some());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(batcher.hold(packet(100, 3)).is_none());
+        assert_eq!(batcher.take().count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packet_burst_switches_to_immediate_mode() {
+        let mut batcher = UplinkBatcher::new(DELAY);
+        for tag in 0..INTERACTIVE_PACKETS as u8 {
+            assert!(batcher.hold(packet(100, tag)).is_none());
+        }
+        assert!(batcher.hold(packet(100, 0xff)).is_some());
+
+        tokio::time::advance(INTERACTIVE_HOLD).await;
+        assert!(batcher.hold(packet(100, 0)).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn byte_burst_switches_to_immediate_mode() {
+        let mut batcher = UplinkBatcher::new(DELAY);
+        let len = INTERACTIVE_BYTES / 4;
+        for tag in 0..4 {
+            assert!(batcher.hold(packet(len, tag)).is_none());
+        }
+        assert!(batcher.hold(packet(1, 4)).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn take_preserves_order() {
+        let mut batcher = UplinkBatcher::new(DELAY);
+        for tag in 1..=3 {
+            assert!(batcher.hold(packet(100, tag)).is_none());
+        }
+        let tags: Vec<_> = batcher.take().map(|p| p[p.len() - 1]).collect();
+        assert_eq!(tags, [1, 2, 3]);
+        assert!(!batcher.has_pending());
+
+        // The next batch gets a fresh deadline.
+        tokio::time::advance(DELAY).await;
+        assert!(batcher.hold(packet(100, 4)).is_none());
+        assert_eq!(batcher.flush_at(), Instant::now() + DELAY);
+    }
+}
@@ -0,0 +1,148 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::{Route, StopInfo, VpnCallback, VpnClientConfig, VpnState};
+
+/// Consecutive panicking invocations after which a callback is no longer called.
+const MAX_CALLBACK_FAILURES: u32 = 3;
+
+/// Wraps the embedder-provided callback so a panic (e.g. an exception thrown on the
+/// Kotlin side) never unwinds into the data plane tasks.
+///
+/// After [`MAX_CALLBACK_FAILURES`] consecutive failures the callback is disabled;
+/// only `on_stop` is still attempted since it is the final notification.
+pub struct GuardedCallback {
+    inner: Box<dyn VpnCallback>,
+    failures: AtomicU32,
+    disabled: AtomicBool,
+}
+
+impl GuardedCallback {
+    pub fn new(inner: Box<dyn VpnCallback>) -> Self {
+        Self {
+            inner,
+            failures: AtomicU32::new(0),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    fn invoke(&self, name: &str, force: bool, f: impl FnOnce(&dyn VpnCallback)) {
+        if !force && self.disabled.load(Ordering::Relaxed) {
+            return;
+        }
+        match catch_unwind(AssertUnwindSafe(|| f(self.inner.as_ref()))) {
+            Ok(()) => self.failures.store(0, Ordering::Relaxed),
+            Err(panic) => {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("<non-string panic>");
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                log::error!("Callback {name} panicked ({failures} in a row): {msg}");
+                if failures >= MAX_CALLBACK_FAILURES && !self.disabled.swap(true, Ordering::Relaxed)
+                {
+                    log::error!("Disabling VPN callback after {failures} consecutive failures");
+                }
+            }
+        }
+    }
+}
+
+impl VpnCallback for GuardedCallback {
+    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64) {
+        self.invoke("on_stats_update", false, |cb| {
+            cb.on_stats_update(tx_bytes, rx_bytes)
+        });
+    }
+
+    fn on_state_change(&self, state: VpnState) {
+        self.invoke("on_state_change", false, |cb| cb.on_state_change(state));
+    }
+
+    fn on_stop(&self, info: StopInfo) {
+        self.invoke("on_stop", true, |cb| cb.on_stop(info));
+    }
+
+    fn on_session_rotated(&self, config: VpnClientConfig) {
+        self.invoke("on_session_rotated", false, |cb| {
+            cb.on_session_rotated(config)
+        });
+    }
+
+    fn on_mtu_changed(&self, mtu: u32) {
+        self.invoke("on_mtu_changed", false, |cb| cb.on_mtu_changed(mtu));
+    }
+
+    fn on_dns_leak_blocked(&self, resolver: String) {
+        self.invoke("on_dns_leak_blocked", false, |cb| {
+            cb.on_dns_leak_blocked(resolver)
+        });
+    }
+
+    fn on_routing_conflict(&self, missing_routes: Vec<Route>) {
+        self.invoke("on_routing_conflict", false, |cb| {
+            cb.on_routing_conflict(missing_routes)
+        });
+    }
+
+    fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>) {
+        self.invoke("on_split_tunnel_routes_changed", false, |cb| {
+            cb.on_split_tunnel_routes_changed(routes)
+        });
+    }
+}
+
+/// Forwards every notification to several callbacks, in order.
+pub struct Fanout(pub Vec<Arc<dyn VpnCallback>>);
+
+impl VpnCallback for Fanout {
+    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64) {
+        for cb in &self.0 {
+            cb.on_stats_update(tx_bytes, rx_bytes);
+        }
+    }
+
+    fn on_state_change(&self, state: VpnState) {
+        for cb in &self.0 {
+            cb.on_state_change(state);
+        }
+    }
+
+    fn on_stop(&self, info: StopInfo) {
+        for cb in &self.0 {
+            cb.on_stop(info.clone());
+        }
+    }
+
+    fn on_session_rotated(&self, config: VpnClientConfig) {
+        for cb in &self.0 {
+            cb.on_session_rotated(config.clone());
+        }
+    }
+
+    fn on_mtu_changed(&self, mtu: u32) {
+        for cb in &self.0 {
+            cb.on_mtu_changed(mtu);
+        }
+    }
+
+    fn on_dns_leak_blocked(&self, resolver: String) {
+        for cb in &self.0 {
+            cb.on_dns_leak_blocked(resolver.clone());
+        }
+    }
+
+    fn on_routing_conflict(&self, missing_routes: Vec<Route>) {
+        for cb in &self.0 {
+            cb.on_routing_conflict(missing_routes.clone());
+        }
+    }
+
+    fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>) {
+        for cb in &self.0 {
+            cb.on_split_tunnel_routes_changed(routes.clone());
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Plain C API for embedders without UniFFI support (e.g. Swift via a C module map).
+//!
+//! See `include/toyvpn_client.h` for the corresponding declarations. Strings returned
+//! to the caller must be released with `toyvpn_string_free`, configs with
+//! `toyvpn_config_free`.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+
+use crate::{
+    Route, StopCode, StopInfo, ToyVpnClient, VpnCallback, VpnClientConfig, VpnError, VpnState,
+};
+
+#[repr(C)]
+pub struct ToyVpnCallbacks {
+    /// Opaque pointer passed back to every callback.
+    pub context: *mut c_void,
+    pub on_stats_update: Option<extern "C" fn(context: *mut c_void, tx_bytes: u64, rx_bytes: u64)>,
+    pub on_stop: Option<extern "C" fn(context: *mut c_void, info: *const ToyVpnStopInfo)>,
+    pub on_session_rotated:
+        Option<extern "C" fn(context: *mut c_void, config: *const ToyVpnConfig)>,
+    pub on_mtu_changed: Option<extern "C" fn(context: *mut c_void, mtu: u32)>,
+    pub on_dns_leak_blocked: Option<extern "C" fn(context: *mut c_void, resolver: *const c_char)>,
+    pub on_routing_conflict:
+        Option<extern "C" fn(context: *mut c_void, missing_routes: *const ToyVpnRoute, len: usize)>,
+    // Later additions go last, so existing positional initializers stay valid.
+    pub on_state_change: Option<extern "C" fn(context: *mut c_void, state: i32)>,
+    pub on_split_tunnel_routes_changed:
+        Option<extern "C" fn(context: *mut c_void, routes: *const ToyVpnRoute, len: usize)>,
+}
+
+/// `VpnState` as passed to `on_state_change` and returned by `toyvpn_client_state`.
+pub const TOYVPN_STATE_IDLE: i32 = 0;
+pub const TOYVPN_STATE_HANDSHAKING: i32 = 1;
+pub const TOYVPN_STATE_CONNECTED: i32 = 2;
+pub const TOYVPN_STATE_RECONNECTING: i32 = 3;
+pub const TOYVPN_STATE_STOPPING: i32 = 4;
+pub const TOYVPN_STATE_STOPPED: i32 = 5;
+
+/// `StopCode` as passed in `ToyVpnStopInfo::code`.
+pub const TOYVPN_STOP_STOPPED: i32 = 0;
+pub const TOYVPN_STOP_REVOKED: i32 = 1;
+pub const TOYVPN_STOP_DETACHED: i32 = 2;
+pub const TOYVPN_STOP_SERVER_CLOSED: i32 = 3;
+pub const TOYVPN_STOP_ERROR: i32 = 4;
+
+#[repr(C)]
+pub struct ToyVpnStopInfo {
+    pub code: i32,
+    pub detail: *const c_char,
+    pub retriable: bool,
+    pub error_chain: *const *const c_char,
+    pub error_chain_len: usize,
+}
+
+#[repr(C)]
+pub struct ToyVpnRoute {
+    pub destination: *mut c_char,
+    pub prefix_length: i32,
+}
+
+#[repr(C)]
+pub struct ToyVpnConfig {
+    pub client_ip: *mut c_char,
+    pub assigned_addresses: *mut *mut c_char,
+    pub assigned_addresses_len: usize,
+    pub routes: *mut ToyVpnRoute,
+    pub routes_len: usize,
+    pub dns_servers: *mut *mut c_char,
+    pub dns_servers_len: usize,
+    pub search_domains: *mut *mut c_char,
+    pub search_domains_len: usize,
+    // Later additions go last, so existing fields keep their offsets.
+    pub assigned_prefix_lengths: *mut i32,
+}
+
+struct CCallback(ToyVpnCallbacks);
+
+// SAFETY: the embedder guarantees that the callbacks and their context may be used
+// from any thread, as documented in the header.
+unsafe impl Send for CCallback {}
+unsafe impl Sync for CCallback {}
+
+impl VpnCallback for CCallback {
+    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64) {
+        if let Some(f) = self.0.on_stats_update {
+            f(self.0.context, tx_bytes, rx_bytes);
+        }
+    }
+
+    fn on_state_change(&self, state: VpnState) {
+        if let Some(f) = self.0.on_state_change {
+            f(self.0.context, state_code(state));
+        }
+    }
+
+    fn on_stop(&self, info: StopInfo) {
+        if let Some(f) = self.0.on_stop {
+            let detail = to_c_string(info.detail);
+            let chain: Vec<*mut c_char> = info.error_chain.into_iter().map(to_c_string).collect();
+            let c_info = ToyVpnStopInfo {
+                code: stop_code(info.code),
+                detail,
+                retriable: info.retriable,
+                error_chain: chain.as_ptr() as *const *const c_char,
+                error_chain_len: chain.len(),
+            };
+            f(self.0.context, &c_info);
+            // SAFETY: created by `to_c_string` above, only borrowed by the callback.
+            unsafe {
+                toyvpn_string_free(detail);
+                for s in chain {
+                    toyvpn_string_free(s);
+                }
+            }
+        }
+    }
+
+    fn on_session_rotated(&self, config: VpnClientConfig) {
+        if let Some(f) = self.0.on_session_rotated {
+            let config = into_c_config(config);
+            f(self.0.context, config);
+            // SAFETY: created by `into_c_config` above, only borrowed by the callback.
+            unsafe { toyvpn_config_free(config) };
+        }
+    }
+
+    fn on_mtu_changed(&self, mtu: u32) {
+        if let Some(f) = self.0.on_mtu_changed {
+            f(self.0.context, mtu);
+        }
+    }
+
+    fn on_dns_leak_blocked(&self, resolver: String) {
+        if let Some(f) = self.0.on_dns_leak_blocked {
+            let resolver = to_c_string(resolver);
+            f(self.0.context, resolver);
+            // SAFETY: created by `to_c_string` above, only borrowed by the callback.
+            unsafe { toyvpn_string_free(resolver) };
+        }
+    }
+
+    fn on_routing_conflict(&self, missing_routes: Vec<Route>) {
+        if let Some(f) = self.0.on_routing_conflict {
+            let routes: Vec<ToyVpnRoute> = missing_routes.into_iter().map(into_c_route).collect();
+            f(self.0.context, routes.as_ptr(), routes.len());
+            for route in routes {
+                // SAFETY: created by `into_c_route` above, only borrowed by the callback.
+                unsafe { toyvpn_string_free(route.destination) };
+            }
+        }
+    }
+
+    fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>) {
+        if let Some(f) = self.0.on_split_tunnel_routes_changed {
+            let routes: Vec<ToyVpnRoute> = routes.into_iter().map(into_c_route).collect();
+            f(self.0.context, routes.as_ptr(), routes.len());
+            for route in routes {
+                // SAFETY: created by `into_c_route` above, only borrowed by the callback.
+                unsafe { toyvpn_string_free(route.destination) };
+            }
+        }
+    }
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    CString::new(s.replace('\0', ""))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Reads a caller-provided, NUL-terminated UTF-8 string.
+unsafe fn from_c_str(s: *const c_char) -> Result<String, VpnError> {
+    if s.is_null() {
+        return Err(VpnError::InvalidConfig("NULL string argument".into()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(str::to_owned)
+        .map_err(|e| VpnError::InvalidConfig(format!("Invalid UTF-8 argument: {e}")))
+}
+
+/// Reads a caller-provided array of `len` strings, which may be NULL if `len` is 0.
+unsafe fn from_c_strs(strings: *const *const c_char, len: usize) -> Result<Vec<String>, VpnError> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if strings.is_null() {
+        return Err(VpnError::InvalidConfig("NULL array argument".into()));
+    }
+    std::slice::from_raw_parts(strings, len)
+        .iter()
+        .map(|s| from_c_str(*s))
+        .collect()
+}
+
+unsafe fn report(res: Result<(), VpnError>, out_error: *mut *mut c_char) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            if !out_error.is_null() {
+                *out_error = to_c_string(e.to_string());
+            }
+            -1
+        }
+    }
+}
+
+fn stop_code(code: StopCode) -> i32 {
+    match code {
+        StopCode::Stopped => TOYVPN_STOP_STOPPED,
+        StopCode::Revoked => TOYVPN_STOP_REVOKED,
+        StopCode::Detached => TOYVPN_STOP_DETACHED,
+        StopCode::ServerClosed => TOYVPN_STOP_SERVER_CLOSED,
+        StopCode::Error => TOYVPN_STOP_ERROR,
+    }
+}
+
+fn state_code(state: VpnState) -> i32 {
+    match state {
+        VpnState::Idle => TOYVPN_STATE_IDLE,
+        VpnState::Handshaking => TOYVPN_STATE_HANDSHAKING,
+        VpnState::Connected => TOYVPN_STATE_CONNECTED,
+        VpnState::Reconnecting => TOYVPN_STATE_RECONNECTING,
+        VpnState::Stopping => TOYVPN_STATE_STOPPING,
+        VpnState::Stopped => TOYVPN_STATE_STOPPED,
+    }
+}
+
+fn into_c_route(route: Route) -> ToyVpnRoute {
+    ToyVpnRoute {
+        destination: to_c_string(route.destination),
+        prefix_length: route.prefix_length,
+    }
+}
+
+/// Returns an array of the strings and its length, to be freed with `free_c_strings`.
+fn into_c_strings(strings: Vec<String>) -> (*mut *mut c_char, usize) {
+    let strings: Box<[*mut c_char]> = strings.into_iter().map(to_c_string).collect();
+    let len = strings.len();
+    (Box::into_raw(strings) as *mut *mut c_char, len)
+}
+
+/// # Safety
+/// `strings` and `len` must have been returned by `into_c_strings`.
+unsafe fn free_c_strings(strings: *mut *mut c_char, len: usize) {
+    let strings = Box::from_raw(ptr::slice_from_raw_parts_mut(strings, len));
+    for s in strings.iter() {
+        toyvpn_string_free(*s);
+    }
+}
+
+fn into_c_config(config: VpnClientConfig) -> *mut ToyVpnConfig {
+    let routes: Box<[ToyVpnRoute]> = config.routes.into_iter().map(into_c_route).collect();
+    let routes_len = routes.len();
+    let (assigned_addresses, assigned_addresses_len) = into_c_strings(config.assigned_addresses);
+    let (dns_servers, dns_servers_len) = into_c_strings(config.dns_servers);
+    let (search_domains, search_domains_len) = into_c_strings(config.search_domains);
+    let prefix_lengths: Box<[i32]> = config.assigned_prefix_lengths.into();
+    debug_assert_eq!(prefix_lengths.len(), assigned_addresses_len);
+    Box::into_raw(Box::new(ToyVpnConfig {
+        client_ip: to_c_string(config.client_ip),
+        assigned_addresses,
+        assigned_addresses_len,
+        routes: Box::into_raw(routes) as *mut ToyVpnRoute,
+        routes_len,
+        dns_servers,
+        dns_servers_len,
+        search_domains,
+        search_domains_len,
+        assigned_prefix_lengths: Box::into_raw(prefix_lengths) as *mut i32,
+    }))
+}
+
+/// Creates a client. Returns NULL if the client could not be initialized.
+#[no_mangle]
+pub extern "C" fn toyvpn_client_new() -> *mut ToyVpnClient {
+    match ToyVpnClient::create() {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => {
+            log::error!("toyvpn_client_new failed: {e}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `client` must have been returned by `toyvpn_client_new` and not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_free(client: *mut ToyVpnClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Performs the handshake. On success stores the config in `out_config` and returns 0,
+/// otherwise returns -1 and stores an error message in `out_error` (if non-NULL).
+///
+/// # Safety
+/// `client` must be valid, string arguments NUL-terminated, and `out_config` writable.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_handshake(
+    client: *const ToyVpnClient,
+    snap_token: *const c_char,
+    endhost_api: *const c_char,
+    edgetun_server: *const c_char,
+    out_config: *mut *mut ToyVpnConfig,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let res = (|| {
+        let client = client
+            .as_ref()
+            .ok_or_else(|| VpnError::InvalidConfig("NULL client".into()))?;
+        if out_config.is_null() {
+            return Err(VpnError::InvalidConfig("NULL out_config".into()));
+        }
+        let config = client.handshake(
+            from_c_str(snap_token)?,
+            from_c_str(endhost_api)?,
+            vec![from_c_str(edgetun_server)?],
+        )?;
+        *out_config = into_c_config(config);
+        Ok(())
+    })();
+    report(res, out_error)
+}
+
+/// Makes a `toyvpn_client_handshake` in progress on another thread fail as cancelled.
+///
+/// # Safety
+/// `client` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_cancel_handshake(client: *const ToyVpnClient) {
+    if let Some(client) = client.as_ref() {
+        client.cancel_handshake();
+    }
+}
+
+/// Starts the data plane on `tun_fd`. Returns 0 on success, -1 on failure.
+///
+/// # Safety
+/// `client` must be valid. The callbacks must stay callable from any thread until
+/// `on_stop` has been invoked.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_start(
+    client: *const ToyVpnClient,
+    tun_fd: c_int,
+    callbacks: ToyVpnCallbacks,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let res = match client.as_ref() {
+        Some(client) => client.start(tun_fd, Some(Box::new(CCallback(callbacks)))),
+        None => Err(VpnError::InvalidConfig("NULL client".into())),
+    };
+    report(res, out_error)
+}
+
+/// Hands the running data plane a new TUN fd, e.g. an interface rebuilt with the
+/// split tunnel routes. Returns 0 on success, -1 on failure.
+///
+/// # Safety
+/// `client` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_replace_tun(
+    client: *const ToyVpnClient,
+    tun_fd: c_int,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let res = match client.as_ref() {
+        Some(client) => client.replace_tun(tun_fd),
+        None => Err(VpnError::InvalidConfig("NULL client".into())),
+    };
+    report(res, out_error)
+}
+
+/// Sets the DNS servers and search domains that configs from subsequent handshakes and
+/// rotations report. Returns 0 on success, -1 on failure, e.g. for a server that isn't
+/// an IP address.
+///
+/// # Safety
+/// `client` must be valid, and the arrays hold the given number of NUL-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_set_tunnel_dns(
+    client: *const ToyVpnClient,
+    dns_servers: *const *const c_char,
+    dns_servers_len: usize,
+    search_domains: *const *const c_char,
+    search_domains_len: usize,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let res = (|| {
+        let client = client
+            .as_ref()
+            .ok_or_else(|| VpnError::InvalidConfig("NULL client".into()))?;
+        client.set_tunnel_dns(
+            from_c_strs(dns_servers, dns_servers_len)?,
+            from_c_strs(search_domains, search_domains_len)?,
+        )
+    })();
+    report(res, out_error)
+}
+
+/// # Safety
+/// `client` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_stop(client: *const ToyVpnClient) {
+    if let Some(client) = client.as_ref() {
+        client.stop();
+    }
+}
+
+/// Returns one of the `TOYVPN_STATE_*` constants, `TOYVPN_STATE_IDLE` for a NULL client.
+///
+/// # Safety
+/// `client` must be valid or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_client_state(client: *const ToyVpnClient) -> c_int {
+    client
+        .as_ref()
+        .map_or(TOYVPN_STATE_IDLE, |client| state_code(client.state()))
+}
+
+/// # Safety
+/// `config` must have been returned by `toyvpn_client_handshake`.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_config_free(config: *mut ToyVpnConfig) {
+    if config.is_null() {
+        return;
+    }
+    let config = Box::from_raw(config);
+    toyvpn_string_free(config.client_ip);
+    free_c_strings(config.assigned_addresses, config.assigned_addresses_len);
+    free_c_strings(config.dns_servers, config.dns_servers_len);
+    free_c_strings(config.search_domains, config.search_domains_len);
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        config.assigned_prefix_lengths,
+        config.assigned_addresses_len,
+    )));
+    let routes = Box::from_raw(ptr::slice_from_raw_parts_mut(
+        config.routes,
+        config.routes_len,
+    ));
+    for route in routes.iter() {
+        toyvpn_string_free(route.destination);
+    }
+}
+
+/// # Safety
+/// `s` must be a string returned by this library, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn toyvpn_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
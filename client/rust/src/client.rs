@@ -1,149 +1,477 @@
-use crate::{ToyVpnClientConnection, VpnCallback};
+use crate::alloc_audit;
+use crate::batching::UplinkBatcher;
+use crate::close::{self, CloseReason};
+use crate::connect::TunnelDns;
+use crate::diagnostics::Diagnostics;
+use crate::dns_guard::{DnsGuard, Verdict};
+use crate::downlink_buffer::DownlinkBuffer;
+use crate::events::EventQueue;
+use crate::mss::{MssClamp, UplinkMss};
+use crate::mtu::{self, MtuFallback};
+use crate::ndp;
+use crate::rng::SystemRng;
+use crate::rotation::{self, Rotation};
+use crate::routes::{self, RouteCheck};
+use crate::split_dns::DomainRoutes;
+use crate::state::RunState;
+use crate::stats::Stats;
+use crate::tun::{self, TunBackend, TunThreads};
+use crate::uplink::Uplink;
+use crate::uplink_buffer::UplinkBuffer;
+use crate::{
+    RouteOverride, StopCode, StopInfo, ToyVpnClientConnection, TransportOptions, VpnCallback,
+    VpnError,
+};
 use bytes::Bytes;
-use std::io::{Read, Write};
-use std::os::fd::FromRawFd;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use tokio::io::unix::AsyncFd;
-use tokio::sync::Notify;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::JoinError;
 
-const BUFFER_SIZE: usize = 4096;
+/// How long the data plane waits for its TUN threads to exit when stopping.
+const TUN_THREAD_JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Everything the data plane shares with the owning `ToyVpnClient`.
+pub struct RunContext {
+    pub callback: Arc<dyn VpnCallback>,
+    pub stop_signal: Arc<Notify>,
+    /// Notified by `cancel_handshake()`, see `Rotation::handshake_cancel`.
+    pub handshake_cancel: Arc<Notify>,
+    pub domain_routes: Arc<DomainRoutes>,
+    pub dns_guard: Arc<DnsGuard>,
+    pub stats: Arc<Stats>,
+    pub options: watch::Receiver<TransportOptions>,
+    pub diagnostics: Arc<Diagnostics>,
+    pub route_overrides: Arc<Mutex<Vec<RouteOverride>>>,
+    pub tunnel_dns: Arc<Mutex<TunnelDns>>,
+    pub events: Arc<EventQueue>,
+    pub state: RunState,
+    pub route_check: Option<RouteCheck>,
+    pub current_quic: Arc<Mutex<Option<quinn::Connection>>>,
+    /// Replacement TUN backends handed over by `replace_tun()`.
+    pub new_tuns: mpsc::Receiver<TunBackend>,
+    /// Whether scheduled session rotation is deferred to save battery.
+    pub rotation_deferred: watch::Receiver<bool>,
+    /// Told once the TUN is open, or why it couldn't be.
+    pub started: oneshot::Sender<Result<(), String>>,
+}
+
+/// Why the data plane stopped.
+#[derive(Debug)]
+pub enum StopReason {
+    /// `stop()` was called or a task ended.
+    Stopped,
+    /// The TUN fd was taken away, e.g. because another VPN app took over.
+    Revoked,
+    /// `detach()` was called; the TUN fd lives on in the handover.
+    Detached,
+    /// The server closed the session for a reason that a new session won't help with.
+    Closed(CloseReason),
+    /// The TUN interface failed, or a task using it panicked.
+    Error(anyhow::Error),
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stopped => write!(f, "Stopped"),
+            Self::Revoked => write!(f, "Revoked"),
+            Self::Detached => write!(f, "Detached"),
+            Self::Closed(reason) => write!(f, "Closed: {reason}"),
+            Self::Error(e) => write!(f, "Error: {e:#}"),
+        }
+    }
+}
+
+impl From<StopReason> for StopInfo {
+    fn from(reason: StopReason) -> Self {
+        let (code, retriable) = match &reason {
+            StopReason::Stopped => (StopCode::Stopped, false),
+            StopReason::Revoked => (StopCode::Revoked, false),
+            StopReason::Detached => (StopCode::Detached, false),
+            StopReason::Closed(close) => (StopCode::ServerClosed, close.is_retriable()),
+            StopReason::Error(e) => return stop_info_for_error(e),
+        };
+        let detail = match reason {
+            StopReason::Closed(close) => close.to_string(),
+            reason => reason.to_string(),
+        };
+        StopInfo {
+            code,
+            detail,
+            retriable,
+            error_chain: Vec::new(),
+        }
+    }
+}
+
+/// Describes a data plane failure. Configuration, credential and TLS problems won't go
+/// away by themselves; anything else, e.g. a network error, may.
+pub fn stop_info_for_error(e: &anyhow::Error) -> StopInfo {
+    StopInfo {
+        code: StopCode::Error,
+        detail: e.to_string(),
+        retriable: is_retriable(e),
+        error_chain: e.chain().map(ToString::to_string).collect(),
+    }
+}
+
+/// Whether `e` may go away by itself, so trying again is worthwhile.
+pub fn is_retriable(e: &anyhow::Error) -> bool {
+    !matches!(
+        e.downcast_ref::<VpnError>(),
+        Some(
+            VpnError::InvalidConfig(_)
+                | VpnError::AuthFailed(_)
+                | VpnError::InvalidServerAddress(_)
+                | VpnError::TlsError(_)
+                | VpnError::AuthRejected(_)
+        )
+    )
+}
 
 pub async fn run_vpn(
-    tun_fd: i32,
+    tun: TunBackend,
     edgetun: ToyVpnClientConnection,
-    callback: Arc<dyn VpnCallback>,
-    stop_signal: Arc<Notify>,
-) -> anyhow::Result<()> {
-    log::info!("run_vpn starting with tun_fd={tun_fd}");
+    ctx: RunContext,
+) -> anyhow::Result<StopReason> {
+    let RunContext {
+        callback,
+        stop_signal,
+        handshake_cancel,
+        domain_routes,
+        dns_guard,
+        stats,
+        mut options,
+        diagnostics,
+        route_overrides,
+        tunnel_dns,
+        events,
+        state,
+        route_check,
+        current_quic,
+        mut new_tuns,
+        rotation_deferred,
+        started,
+    } = ctx;
 
-    // 1. Prepare TUN device
-    // Set to non-blocking mode for AsyncFd
-    set_nonblocking(tun_fd)?;
+    log::info!("run_vpn starting with {tun}");
 
-    // Create File from raw fd. unsafe because we assume ownership of fd.
-    // We wrap it in AsyncFd to use with tokio
-    let tun_file = unsafe { std::fs::File::from_raw_fd(tun_fd) };
-    let tun = Arc::new(AsyncFd::new(tun_file)?);
+    // 1. Prepare TUN device
+    let read_strategy = options.borrow().tun_read_strategy;
+    let tun_threads = TunThreads::default();
+    let (mut tun_reader, mut tun_writer) = match tun::open(tun, read_strategy, &tun_threads) {
+        Ok(tun) => {
+            // Nobody may be waiting, e.g. after a plain `start()`.
+            let _ = started.send(Ok(()));
+            tun
+        }
+        Err(e) => {
+            let _ = started.send(Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
 
     // 3. Stats
-    let total_tx = Arc::new(AtomicU64::new(0));
-    let total_rx = Arc::new(AtomicU64::new(0));
+    stats.reset();
 
     // 4. Spawn Tasks
 
     let ToyVpnClientConnection {
         mut edge_read,
-        mut edge_write,
-        ctrl: _ctrl,
+        edge_write,
+        ctrl,
+        quic,
+        params,
+        ..
     } = edgetun;
 
+    let sources = ctrl.assigned_addresses();
+
+    // Task: session rotation, handing new sessions to the Tx and Rx tasks
+    let (uplink_tx, mut new_uplinks) = mpsc::channel(1);
+    let (downlink_tx, mut new_downlinks) = mpsc::channel(1);
+    let reconnect = Arc::new(Notify::new());
+    let mut rx_quic = quic.clone();
+    let (quic_tx, quic_rx) = watch::channel(quic);
+    let mut session_task = tokio::spawn(rotation::rotate_sessions(
+        ctrl,
+        params,
+        Rotation {
+            uplink: uplink_tx,
+            quic: quic_tx,
+            downlink: downlink_tx,
+            callback: callback.clone(),
+            diagnostics: diagnostics.clone(),
+            route_overrides,
+            tunnel_dns,
+            events,
+            state,
+            options: options.clone(),
+            reconnect: reconnect.clone(),
+            stop: stop_signal.clone(),
+            rotation_deferred,
+            current_quic,
+            expected_routes: route_check.as_ref().map(|check| check.expected.clone()),
+            rng: Arc::new(SystemRng::default()),
+            handshake_cancel,
+        },
+    ));
+
+    // Shared by both directions: blackholing seen on the uplink clamps both.
+    let mss = Arc::new(MssClamp::new(diagnostics));
+
+    // Task: path MTU monitoring
+    let mtu_fallback = Arc::new(MtuFallback::default());
+    let mtu_task = tokio::spawn(mtu::monitor(
+        quic_rx,
+        mtu_fallback.clone(),
+        mss.clone(),
+        callback.clone(),
+    ));
+
+    // Task: verifying that the routes are still installed
+    let route_task =
+        route_check.map(|check| tokio::spawn(routes::verify_installed(check, callback.clone())));
+
+    // Task: reporting the routes learned for the split tunnel domains
+    let split_task = tokio::spawn(domain_routes.clone().report_changes(callback.clone()));
+
     // Task: TUN -> UDP (Uplink)
-    let tun_reader = tun.clone();
-    let tx_stats = total_tx.clone();
+    let mut tx_mss = UplinkMss::new(mss.clone());
+    let tx_stats = stats.clone();
     let stop_tx = stop_signal.clone();
+    let rx_reconnect = reconnect.clone();
+    let tx_callback = callback.clone();
+    let mut tx_tun_writer = tun_writer.clone();
+    let (tun_writer_tx, mut new_tun_writers) = mpsc::channel(1);
+    let mut tx_options = options.clone();
+    let tx_tun_threads = tun_threads.clone();
+    let (mut uplink, mut batcher) = {
+        let options = options.borrow();
+        (
+            Uplink::new(
+                edge_write,
+                sources,
+                UplinkBuffer::new(
+                    options.uplink_buffer_per_flow_bytes as usize,
+                    options.uplink_buffer_total_bytes as usize,
+                ),
+                stats.clone(),
+                reconnect,
+                mtu_fallback,
+            ),
+            UplinkBatcher::new(Duration::from_millis(
+                options.uplink_batch_max_delay_ms.into(),
+            )),
+        )
+    };
 
-    let tx_task = tokio::spawn(async move {
+    let mut tx_task = tokio::spawn(async move {
         log::info!("Tx task started");
-        let mut buf = [0u8; BUFFER_SIZE];
         loop {
             tokio::select! {
                 _ = stop_tx.notified() => break,
-                guard = tun_reader.readable() => {
-                    match guard {
-                        Ok(mut guard) => {
-                            match guard.try_io(|inner| inner.get_ref().read(&mut buf)) {
-                                Ok(Ok(n)) => {
-                                    if n == 0 {
-                                        log::info!("TUN read EOF");
-                                        break;
+                Some((write, sources)) = new_uplinks.recv() => {
+                    log::info!("Uplink switched to rotated session");
+                    uplink.replace(write, sources).await;
+                }
+                Ok(()) = tx_options.changed() => {
+                    let delay = tx_options.borrow_and_update().uplink_batch_max_delay_ms;
+                    batcher.set_max_delay(Duration::from_millis(delay.into()));
+                }
+                Some(tun) = new_tuns.recv() => {
+                    log::info!("Switching to new TUN: {tun}");
+                    let strategy = tx_options.borrow().tun_read_strategy;
+                    let (reader, writer) = tun::open(tun, strategy, &tx_tun_threads).inspect_err(|e| {
+                        log::error!("Failed to open replacement TUN: {e}");
+                    })?;
+                    // The previous TUN is closed once the Rx task has switched, too.
+                    tun_reader = reader;
+                    tx_tun_writer = writer.clone();
+                    let _ = tun_writer_tx.send(writer).await;
+                }
+                _ = tokio::time::sleep_until(batcher.flush_at()), if batcher.has_pending() => {
+                    for packet in batcher.take() {
+                        uplink.send(packet).await;
+                    }
+                }
+                res = tun_reader.read() => {
+                    match res {
+                        Ok(packet) => {
+                            if packet.is_empty() {
+                                log::info!("TUN read EOF");
+                                break;
+                            }
+                            alloc_audit::audit("uplink packet", async {
+                                match ndp::handle(&packet, uplink.sources()) {
+                                    ndp::Action::Forward => {}
+                                    ndp::Action::Drop => return,
+                                    ndp::Action::Reply(reply) => {
+                                        let reply = alloc_audit::exempt(|| Bytes::from(reply));
+                                        if let Err(e) = tx_tun_writer.write(reply).await {
+                                            alloc_audit::exempt(|| {
+                                                log::warn!("Failed to answer neighbor discovery: {e}")
+                                            });
+                                        }
+                                        return;
+                                    }
+                                }
+                                if !uplink.is_valid_source(&packet) {
+                                    Stats::add(&tx_stats.uplink.tx_invalid_source_packets, 1);
+                                    return;
+                                }
+                                match dns_guard.check(&packet) {
+                                    Verdict::Allow => {}
+                                    Verdict::Drop => {
+                                        Stats::add(&tx_stats.uplink.tx_dns_blocked_packets, 1);
+                                        return;
                                     }
-                                    tx_stats.fetch_add(n as u64, Ordering::Relaxed);
-                                    if let Err(e) = edge_write.send_wait(Bytes::copy_from_slice(&buf[..n])).await {
-                                        log::error!("UDP send error: {e}");
+                                    Verdict::DropAndReport(resolver) => {
+                                        Stats::add(&tx_stats.uplink.tx_dns_blocked_packets, 1);
+                                        alloc_audit::exempt(|| {
+                                            tx_callback.on_dns_leak_blocked(resolver.to_string())
+                                        });
+                                        return;
                                     }
                                 }
-                                Ok(Err(e)) => {
-                                    log::error!("TUN read error: {e}");
-                                    break;
+                                let packet = tx_mss.inspect(packet);
+                                Stats::add(&tx_stats.uplink.tx_bytes, packet.len());
+                                tx_stats.latency.inspect_uplink(&packet);
+                                let Some(packet) = batcher.hold(packet) else {
+                                    return;
+                                };
+                                // Keep ordering: anything batched so far goes out first.
+                                for pending in batcher.take() {
+                                    uplink.send(pending).await;
                                 }
-                                Err(_would_block) => continue,
-                            }
+                                uplink.send(packet).await;
+                            })
+                            .await;
                         }
                         Err(e) => {
-                            log::error!("TUN readable error: {e}");
-                            break;
+                            log::error!("TUN read error: {e}");
+                            return Err(e);
                         }
                     }
                 }
             }
         }
         log::info!("Tx task exiting");
+        Ok(StopReason::Stopped)
     });
 
     // Task: UDP -> TUN (Downlink)
-    let tun_writer = tun.clone();
-    let rx_stats = total_rx.clone();
+    let rx_stats = stats.clone();
     let stop_rx = stop_signal.clone();
+    let downlink_buffer_bytes = options.borrow().downlink_buffer_bytes as usize;
 
-    let rx_task = tokio::spawn(async move {
+    let mut rx_task = tokio::spawn(async move {
         log::info!("Rx task started");
+        // Set while the session is gone and a new one is awaited from the rotation task.
+        let mut closed = false;
+        // Packets waiting for a stalled TUN; while there are any, new ones queue up behind.
+        let mut stalled = DownlinkBuffer::new(downlink_buffer_bytes);
         loop {
+            let next = stalled.front().cloned();
             tokio::select! {
                 _ = stop_rx.notified() => break,
-                res = edge_read.receive() => {
+                Some((read, quic)) = new_downlinks.recv() => {
+                    log::info!("Downlink switched to rotated session");
+                    edge_read = read;
+                    rx_quic = quic;
+                    closed = false;
+                }
+                Some(writer) = new_tun_writers.recv() => {
+                    log::info!("Downlink switched to new TUN");
+                    tun_writer = writer;
+                }
+                res = tun_writer.write(next.clone().unwrap_or_default()), if next.is_some() => {
+                    if let Err(e) = res {
+                        log::error!("TUN write error: {e}");
+                        return Err(e);
+                    }
+                    stalled.pop();
+                    if stalled.is_empty() {
+                        log::info!("TUN writable again, buffered downlink flushed");
+                    }
+                }
+                res = edge_read.receive(), if !closed => {
                     match res {
                         Ok(buf) => {
-                            rx_stats.fetch_add(buf.len() as u64, Ordering::Relaxed);
-
-                            // Write to TUN
-                            // We loop until we can write or error
-                            loop {
-                                let mut guard = match tun_writer.writable().await {
-                                    Ok(g) => g,
-                                    Err(e) => {
-                                        log::error!("TUN writable error: {e}");
-                                        return;
-                                    }
-                                };
+                            alloc_audit::audit("downlink packet", async {
+                                let buf = mss.inspect_downlink(buf);
+                                Stats::add(&rx_stats.downlink.rx_bytes, buf.len());
+                                domain_routes.inspect_downlink(&buf);
+                                rx_stats.latency.inspect_downlink(&buf);
 
-                                match guard.try_io(|inner| inner.get_ref().write(&buf)) {
-                                    Ok(Ok(_)) => break,
-                                    Ok(Err(e)) => {
-                                        log::error!("TUN write error: {e}");
-                                        return;
+                                if stalled.is_empty() {
+                                    match tun_writer.try_write(&buf) {
+                                        Ok(()) => return Ok(()),
+                                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                            alloc_audit::exempt(|| {
+                                                log::warn!("TUN stalled, buffering downlink")
+                                            });
+                                        }
+                                        Err(e) => {
+                                            alloc_audit::exempt(|| {
+                                                log::error!("TUN write error: {e}")
+                                            });
+                                            return Err(e);
+                                        }
                                     }
-                                    Err(_would_block) => continue,
                                 }
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("UDP recv error: {e}");
-                            break;
+                                // Buffering is the exception; its queue grows as needed.
+                                let dropped = alloc_audit::exempt(|| stalled.push(buf));
+                                Stats::add(&rx_stats.downlink.rx_dropped_packets, dropped);
+                                Ok(())
+                            })
+                            .await?;
                         }
+                        Err(e) => match close::close_reason(&rx_quic) {
+                            // We closed it ourselves after switching over to a replacement,
+                            // which is on its way.
+                            Some(CloseReason::Local) => closed = true,
+                            Some(reason) if !reason.is_retriable() => {
+                                log::error!("Session closed by server ({reason}): {e}");
+                                return Ok(StopReason::Closed(reason));
+                            }
+                            reason => {
+                                match reason {
+                                    Some(reason) => log::warn!(
+                                        "Session closed by server ({reason}), reconnecting"
+                                    ),
+                                    None => log::warn!("Downlink failed ({e}), reconnecting"),
+                                }
+                                closed = true;
+                                rx_reconnect.notify_one();
+                            }
+                        },
                     }
                 }
             }
         }
         log::info!("Rx task exiting");
+        Ok(StopReason::Stopped)
     });
 
     // Task: Stats
-    let stats_tx = total_tx.clone();
-    let stats_rx = total_rx.clone();
+    let stats_cb = stats.clone();
     let stop_stats = stop_signal.clone();
     let cb = callback.clone();
 
     let stats_task = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        let mut interval = stats_interval(&options);
         loop {
             tokio::select! {
                 _ = stop_stats.notified() => break,
+                Ok(()) = options.changed() => {
+                    interval = stats_interval(&options);
+                }
                 _ = interval.tick() => {
-                    cb.on_stats_update(
-                        stats_tx.load(Ordering::Relaxed),
-                        stats_rx.load(Ordering::Relaxed)
-                    );
+                    let (tx_bytes, rx_bytes) = stats_cb.bytes();
+                    cb.on_stats_update(tx_bytes, rx_bytes);
                 }
             }
         }
@@ -151,38 +479,93 @@ pub async fn run_vpn(
     });
 
     // Wait for stop signal or any task failure
+    let mut reason = StopReason::Stopped;
+    let mut failure = None;
     tokio::select! {
         _ = stop_signal.notified() => {
             log::info!("Stop signal received in main loop");
         }
-        _ = tx_task => {
+        res = &mut tx_task => {
             log::info!("Tx task finished unexpectedly");
+            reason = tun_stop_reason(res);
         }
-        _ = rx_task => {
+        res = &mut rx_task => {
             log::info!("Rx task finished unexpectedly");
+            reason = tun_stop_reason(res);
         }
         _ = stats_task => {
             log::info!("Stats task finished unexpectedly");
         }
+        res = &mut session_task => {
+            log::info!("Session task finished unexpectedly");
+            if let Ok(Err(e)) = res {
+                failure = Some(e);
+            }
+        }
     }
 
     // Ensure all tasks are cleaned up
     stop_signal.notify_waiters();
+    session_task.abort();
+    mtu_task.abort();
+    split_task.abort();
+    if let Some(route_task) = route_task {
+        route_task.abort();
+    }
+    // Dropping the TUN readers and writers with the tasks lets the TUN threads exit.
+    tx_task.abort();
+    rx_task.abort();
+    tun_threads.join(TUN_THREAD_JOIN_TIMEOUT).await;
 
     log::info!("VPN run_vpn completed");
-    Ok(())
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(reason),
+    }
 }
 
-fn set_nonblocking(fd: i32) -> anyhow::Result<()> {
-    unsafe {
-        let flags = libc::fcntl(fd, libc::F_GETFL);
-        if flags < 0 {
-            anyhow::bail!("fcntl F_GETFL failed");
-        }
-        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
-            anyhow::bail!("fcntl F_SETFL failed to set nonblocking");
+/// Tells from how a task using the TUN ended why the data plane stops.
+fn tun_stop_reason(res: Result<io::Result<StopReason>, JoinError>) -> StopReason {
+    match res {
+        Ok(Ok(reason)) => reason,
+        Ok(Err(e)) if tun::is_revoked(&e) => {
+            log::warn!("TUN fd was revoked: {e}");
+            StopReason::Revoked
         }
+        Ok(Err(e)) => StopReason::Error(e.into()),
+        Err(e) => StopReason::Error(anyhow::Error::new(e).context("Data plane task failed")),
+    }
+}
+
+fn stats_interval(options: &watch::Receiver<TransportOptions>) -> tokio::time::Interval {
+    let ms = options.borrow().stats_interval_ms.max(100);
+    tokio::time::interval(std::time::Duration::from_millis(ms.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tun_errors_are_reported_with_their_chain() {
+        let e = io::Error::new(io::ErrorKind::InvalidData, "bad packet");
+        let info = StopInfo::from(tun_stop_reason(Ok(Err(e))));
+        assert_eq!(info.code, StopCode::Error);
+        assert!(info.retriable);
+        assert_eq!(info.error_chain, ["bad packet"]);
+
+        let revoked = io::Error::from_raw_os_error(libc::EBADF);
+        let info = StopInfo::from(tun_stop_reason(Ok(Err(revoked))));
+        assert_eq!(info.code, StopCode::Revoked);
+    }
+
+    #[tokio::test]
+    async fn task_panics_are_errors() {
+        let task = tokio::spawn(async { panic!("boom") });
+        let res: Result<io::Result<StopReason>, JoinError> = task.await;
+        let info = StopInfo::from(tun_stop_reason(res));
+        assert_eq!(info.code, StopCode::Error);
+        assert_eq!(info.error_chain.len(), 2);
+        assert_eq!(info.error_chain[0], "Data plane task failed");
     }
-    log::info!("Set fd {fd} to non-blocking mode");
-    Ok(())
 }
@@ -0,0 +1,74 @@
+use quinn::ConnectionError;
+
+/// Why a session's QUIC connection was closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// We closed it ourselves, e.g. after switching over to a replacement session.
+    Local,
+    /// The server closed the session. The edgetun server's close codes aren't part of
+    /// its interface, so they are passed on as received rather than interpreted; a
+    /// server that won't take us back anymore turns down the next handshake instead.
+    Server { code: u64, reason: String },
+    /// The server stopped responding or lost the session, e.g. after a restart.
+    Lost,
+    /// Any other close, with the details reported by QUIC.
+    Other(String),
+}
+
+impl CloseReason {
+    /// Whether establishing a new session is worth trying.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, Self::Server { .. } | Self::Lost)
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local => write!(f, "closed locally"),
+            Self::Server { code, reason } if reason.is_empty() => {
+                write!(f, "closed by server with code {code}")
+            }
+            Self::Server { code, reason } => {
+                write!(f, "closed by server with code {code}: {reason}")
+            }
+            Self::Lost => write!(f, "connection lost"),
+            Self::Other(details) => write!(f, "{details}"),
+        }
+    }
+}
+
+/// Returns why `quic` was closed, or `None` while it is still open.
+pub fn close_reason(quic: &quinn::Connection) -> Option<CloseReason> {
+    let reason = match quic.close_reason()? {
+        ConnectionError::LocallyClosed => CloseReason::Local,
+        ConnectionError::ApplicationClosed(close) => CloseReason::Server {
+            code: close.error_code.into_inner(),
+            reason: String::from_utf8_lossy(&close.reason).into_owned(),
+        },
+        ConnectionError::TimedOut | ConnectionError::Reset => CloseReason::Lost,
+        e => CloseReason::Other(e.to_string()),
+    };
+    Some(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_server_closes_and_lost_sessions_are_retried() {
+        let server = CloseReason::Server {
+            code: 7,
+            reason: "maintenance".into(),
+        };
+        assert!(server.is_retriable());
+        assert!(CloseReason::Lost.is_retriable());
+        assert!(!CloseReason::Local.is_retriable());
+        assert!(!CloseReason::Other("version mismatch".into()).is_retriable());
+        assert_eq!(
+            server.to_string(),
+            "closed by server with code 7: maintenance"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use edge_token::dummy_edge_app_token;
+use edge_tun::client::{ClientBuilder, Control, Incoming, Outgoing};
+use edge_tun::PSEUDO_SECURE_SERVER_SECRET;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{EndpointConfig, MtuDiscoveryConfig};
+use rustls::ClientConfig;
+use scion_proto::address::SocketAddr as ScionSocketAddr;
+use scion_stack::scionstack::{ScionStack, ScionStackBuilder};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use url::Url;
+
+use crate::auth::TokenSource;
+use crate::diagnostics::Diagnostics;
+use crate::persist::{self, Store};
+use crate::{
+    routes, Route, RouteOverride, ToyVpnClientConnection, TransportOptions, VpnClientConfig,
+    VpnError,
+};
+
+/// Delay between starting connection attempts to consecutive servers.
+const ATTEMPT_STAGGER: Duration = Duration::from_millis(250);
+
+/// The edgetun halves plus the underlying QUIC connection, kept to observe its path MTU.
+pub type EdgetunConnection = (Incoming, Outgoing, Control, quinn::Connection);
+
+/// What is needed to establish an edgetun session, kept so the session can be
+/// re-established later on.
+#[derive(Clone)]
+pub struct SessionParams {
+    pub endhost_api: Url,
+    pub edgetun_servers: Vec<ScionSocketAddr>,
+    /// Where the token for each new session comes from.
+    pub auth: Arc<TokenSource>,
+    /// Where server health is kept; without it, servers are tried in the given order.
+    pub store: Option<Arc<Store>>,
+}
+
+impl SessionParams {
+    /// Establishes a fresh session over a new SCION stack, i.e. with new credentials
+    /// towards the endhost API and new QUIC keys.
+    pub async fn connect(
+        &self,
+        options: &TransportOptions,
+        diagnostics: &Diagnostics,
+    ) -> anyhow::Result<ToyVpnClientConnection> {
+        let auth = self.auth.clone();
+        let snap_token = tokio::task::spawn_blocking(move || auth.token()).await??;
+        let scion_stack = build_scion_stack(self.endhost_api.clone(), snap_token)
+            .await
+            .inspect_err(|e| self.check_rejection(e))?;
+        self.connect_with(scion_stack, options, diagnostics).await
+    }
+
+    /// Establishes a session over an already built SCION stack.
+    pub async fn connect_with(
+        &self,
+        scion_stack: ScionStack,
+        options: &TransportOptions,
+        diagnostics: &Diagnostics,
+    ) -> anyhow::Result<ToyVpnClientConnection> {
+        let (server, (edge_read, edge_write, ctrl, quic)) = race(
+            Arc::new(scion_stack),
+            self.ranked_servers(diagnostics),
+            options,
+            diagnostics,
+            self.store.as_deref(),
+        )
+        .await
+        .inspect_err(|e| self.check_rejection(e))?;
+
+        log::info!("edgetun client connection established to {server}");
+        log::info!("Advertised routes: {:?}", ctrl.advertised_routes());
+
+        Ok(ToyVpnClientConnection {
+            edge_read,
+            edge_write,
+            ctrl,
+            quic,
+            params: self.clone(),
+        })
+    }
+
+    /// Has the token refreshed for the next session if the server turned it down.
+    fn check_rejection(&self, e: &anyhow::Error) {
+        if let Some(VpnError::AuthRejected(_)) = e.downcast_ref::<VpnError>() {
+            self.auth.invalidate();
+        }
+    }
+
+    /// Orders the servers by health, healthiest first. Servers with the same score,
+    /// e.g. all of them on first use, keep their configured order.
+    fn ranked_servers(&self, diagnostics: &Diagnostics) -> Vec<ScionSocketAddr> {
+        let mut servers = self.edgetun_servers.clone();
+        let Some(store) = &self.store else {
+            return servers;
+        };
+        let health = store.server_health();
+        let score = |server: &ScionSocketAddr| {
+            health
+                .get(&server.to_string())
+                .copied()
+                .unwrap_or(persist::DEFAULT_SERVER_HEALTH)
+        };
+        servers.sort_by_key(|s| Reverse(score(s)));
+        if servers != self.edgetun_servers {
+            let order: Vec<_> = servers
+                .iter()
+                .map(|s| format!("{s} ({}%)", score(s)))
+                .collect();
+            diagnostics.record(
+                "connect",
+                format!("Server order by health: {}", order.join(", ")),
+            );
+        }
+        servers
+    }
+}
+
+/// Bounds a handshake by the `handshake_timeout_ms` of `options`, failing with
+/// `VpnError::Timeout` once it has passed.
+pub async fn with_timeout<T>(
+    options: &TransportOptions,
+    handshake: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    if options.handshake_timeout_ms == 0 {
+        return handshake.await;
+    }
+    let timeout = Duration::from_millis(options.handshake_timeout_ms.into());
+    tokio::time::timeout(timeout, handshake)
+        .await
+        .unwrap_or_else(|_| Err(VpnError::Timeout(format!("No session after {timeout:?}")).into()))
+}
+
+/// Builds the SCION stack, connecting to the given SNAP's endhost API.
+pub async fn build_scion_stack(
+    endhost_api_addr: Url,
+    auth_token: String,
+) -> anyhow::Result<ScionStack> {
+    ScionStackBuilder::new(endhost_api_addr)
+        .with_auth_token(auth_token)
+        .build()
+        .await
+        .map_err(|e| stack_error(e).into())
+}
+
+/// How the endhost API's HTTP client reports it turning the SNAP token down. The stack
+/// only hands its errors on as `anyhow::Error`, so there is no status code to downcast to.
+const TOKEN_REJECTED: [&str; 2] = [
+    "HTTP status client error (401 ",
+    "HTTP status client error (403 ",
+];
+
+/// Categorizes a failed stack build. The endhost API turning the SNAP token down needs
+/// a new token rather than a retry; anything else is taken for the API being out of
+/// reach.
+fn stack_error(e: anyhow::Error) -> VpnError {
+    let message = format!("{e:#}");
+    if TOKEN_REJECTED.iter().any(|m| message.contains(m)) {
+        VpnError::AuthRejected(message)
+    } else {
+        VpnError::EndhostApiUnreachable(message)
+    }
+}
+
+/// The tunnel's DNS configuration, see `ToyVpnClient::set_tunnel_dns`.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelDns {
+    pub servers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+}
+
+/// Builds the configuration handed to the app from what the server assigned and advertised,
+/// plus the tunnel's `dns`.
+pub fn client_config(
+    ctrl: &Control,
+    overrides: &[RouteOverride],
+    dns: &TunnelDns,
+) -> anyhow::Result<VpnClientConfig> {
+    let addresses = ctrl.assigned_addresses();
+    let ip = addresses
+        .first()
+        .cloned()
+        .ok_or(VpnError::NoAddressAssigned)?;
+
+    let advertised: Vec<_> = ctrl
+        .advertised_routes()
+        .iter()
+        .map(|r| (r.network(), r.prefix_len()))
+        .collect();
+    let prefix_lengths = addresses
+        .iter()
+        .map(|a| routes::assigned_prefix_length(*a, &advertised))
+        .collect();
+
+    let mut routes = Vec::new();
+
+    for route in ctrl.advertised_routes() {
+        // Without an address of the route's family, its traffic would be blackholed.
+        let network = route.network();
+        if !addresses.iter().any(|a| a.is_ipv4() == network.is_ipv4()) {
+            log::warn!(
+                "Ignoring route {network}/{} without an assigned address of its family",
+                route.prefix_len()
+            );
+            continue;
+        }
+        routes.push(Route {
+            destination: route.network().to_string(),
+            prefix_length: route.prefix_len() as i32,
+        });
+    }
+    let routes = routes::apply_overrides(routes, overrides);
+
+    // Like routes, a resolver of a family we have no address of would be unreachable.
+    let dns_servers = dns
+        .servers
+        .iter()
+        .filter(|r| addresses.iter().any(|a| a.is_ipv4() == r.is_ipv4()))
+        .map(ToString::to_string)
+        .collect();
+
+    Ok(VpnClientConfig {
+        client_ip: ip.to_string(),
+        assigned_addresses: addresses.iter().map(ToString::to_string).collect(),
+        assigned_prefix_lengths: prefix_lengths,
+        routes,
+        dns_servers,
+        search_domains: dns.search_domains.clone(),
+    })
+}
+
+/// Races edgetun connection attempts to `servers`, happy-eyeballs style: attempts are
+/// started in list order, [`ATTEMPT_STAGGER`] apart, and the first one to succeed wins.
+/// The outcome of every finished attempt is recorded in `diagnostics` and, as server
+/// health, in `store`.
+async fn race(
+    scion_stack: Arc<ScionStack>,
+    servers: Vec<ScionSocketAddr>,
+    options: &TransportOptions,
+    diagnostics: &Diagnostics,
+    store: Option<&Store>,
+) -> anyhow::Result<(ScionSocketAddr, EdgetunConnection)> {
+    let mut attempts = JoinSet::new();
+    // Which server each attempt is for, so that an attempt that panicked can still be
+    // attributed.
+    let mut attempt_servers = HashMap::new();
+    let race_started = Instant::now();
+    for (i, server) in servers.into_iter().enumerate() {
+        let scion_stack = scion_stack.clone();
+        let options = options.clone();
+        let handle = attempts.spawn(async move {
+            tokio::time::sleep(ATTEMPT_STAGGER * i as u32).await;
+            let started = Instant::now();
+            let res = connect(&scion_stack, server, &options).await;
+            (res, started.elapsed())
+        });
+        attempt_servers.insert(handle.id(), server);
+    }
+
+    let mut last_err = None;
+    while let Some(attempt) = attempts.join_next_with_id().await {
+        let (server, res, elapsed) = match attempt {
+            Ok((id, (res, elapsed))) => (attempt_servers[&id], res, elapsed),
+            Err(e) => {
+                let Some(&server) = attempt_servers.get(&e.id()) else {
+                    continue;
+                };
+                let err = anyhow!("Connection attempt panicked: {e}");
+                (server, Err(err), race_started.elapsed())
+            }
+        };
+        if let Some(store) = store {
+            store.record_handshake(&server.to_string(), res.is_ok());
+        }
+        match res {
+            Ok(conn) => {
+                diagnostics.record(
+                    "connect",
+                    format!("{server}: connected in {}ms", elapsed.as_millis()),
+                );
+                // Dropping the remaining attempts closes their endpoints.
+                attempts.abort_all();
+                return Ok((server, conn));
+            }
+            Err(e) => {
+                diagnostics.record(
+                    "connect",
+                    format!("{server}: failed after {}ms: {e:#}", elapsed.as_millis()),
+                );
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("No edgetun server given")))
+}
+
+/// Establishes an edgetun client connection to a single server.
+async fn connect(
+    scion_stack: &ScionStack,
+    server_addr: ScionSocketAddr,
+    options: &TransportOptions,
+) -> anyhow::Result<EdgetunConnection> {
+    let quic_conn = establish_quic_conn(scion_stack, server_addr, options)
+        .await
+        .context("Failed to establish QUIC connection to snap")?;
+
+    let (edge_read, edge_write, ctrl) = ClientBuilder::default()
+        .with_initial_mtu(1280)
+        .with_initial_auth_token(dummy_edge_app_token())
+        .connect(quic_conn.clone())
+        .await
+        .context("Failed to establish edgetun session")?;
+    Ok((edge_read, edge_write, ctrl, quic_conn))
+}
+
+/// Establishes a QUIC connection to the edge app server via the given SCION stack.
+async fn establish_quic_conn(
+    scion_stack: &ScionStack,
+    server_addr: ScionSocketAddr,
+    options: &TransportOptions,
+) -> anyhow::Result<quinn::Connection> {
+    let (cert_der, _server_config) = scion_sdk_utils::test::generate_cert(
+        PSEUDO_SECURE_SERVER_SECRET,
+        vec!["localhost".into()],
+        vec![b"edgetun".to_vec()],
+    );
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(cert_der)
+        .map_err(|e| VpnError::TlsError(format!("Invalid server certificate: {e}")))?;
+
+    let mut client_crypto = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = vec![b"edgetun".to_vec()];
+
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.keep_alive_interval(Some(Duration::from_millis(
+        options.keepalive_interval_ms.into(),
+    )));
+    transport_config.datagram_receive_buffer_size(Some(options.datagram_buffer_bytes as usize));
+    transport_config.datagram_send_buffer_size(options.datagram_buffer_bytes as usize);
+    // Probe the path MTU continuously; see `mtu::monitor`.
+    transport_config.mtu_discovery_config(Some(MtuDiscoveryConfig::default()));
+    let quic_crypto = QuicClientConfig::try_from(client_crypto)
+        .map_err(|e| VpnError::TlsError(format!("Unusable TLS configuration: {e}")))?;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(quic_crypto));
+    client_config.transport_config(Arc::new(transport_config));
+    let mut endpoint = scion_stack
+        .quic_endpoint(None, EndpointConfig::default(), None, None)
+        .await
+        .context("Failed to create QUIC endpoint")?;
+
+    endpoint.set_default_client_config(client_config);
+
+    log::info!("created quic endpoint, connecting to edge app server {server_addr}");
+
+    let conn = endpoint
+        .connect(server_addr, "localhost")
+        .context("Failed to initialize connection to edge app server")?
+        .await
+        .map_err(|e| {
+            if is_tls_error(&e) {
+                VpnError::TlsError(e.to_string()).into()
+            } else {
+                anyhow::Error::new(e).context("Failed to establish connection to edge app server")
+            }
+        })?;
+
+    Ok(conn)
+}
+
+/// Whether the QUIC handshake failed in TLS, on our side or the server's, e.g. over
+/// an untrusted certificate or a protocol mismatch.
+fn is_tls_error(e: &quinn::ConnectionError) -> bool {
+    let code = match e {
+        quinn::ConnectionError::TransportError(e) => e.code,
+        quinn::ConnectionError::ConnectionClosed(close) => close.error_code,
+        _ => return false,
+    };
+    // QUIC carries TLS alerts as the CRYPTO_ERROR range of transport error codes.
+    (0x100..0x200).contains(&u64::from(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_errors_tell_rejected_tokens_apart() {
+        let rejected = [
+            anyhow!("HTTP status client error (401 Unauthorized) for url (https://snap.example/)"),
+            anyhow!("HTTP status client error (403 Forbidden) for url (https://snap.example/)")
+                .context("Fetching paths"),
+        ];
+        for e in rejected {
+            assert!(matches!(stack_error(e), VpnError::AuthRejected(_)));
+        }
+        let unreachable = [
+            anyhow!("Connection refused"),
+            anyhow!("error sending request for url (https://snap.example:401/)"),
+            anyhow!("error sending request for url (https://snap.example/403)"),
+            anyhow!("HTTP status client error (404 Not Found) for url (https://snap.example/)"),
+            anyhow!("HTTP status server error (503 Service Unavailable)")
+                .context("Fetching https://snap.example:401/403"),
+        ];
+        for e in unreachable {
+            assert!(matches!(stack_error(e), VpnError::EndhostApiUnreachable(_)));
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::DiagnosticEvent;
+
+/// The current wall clock time in milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Number of events kept; older ones are discarded.
+const MAX_EVENTS: usize = 256;
+
+/// A bounded log of notable client events (link changes, connection attempts, ...)
+/// that the app can show on a support screen.
+#[derive(Default)]
+pub struct Diagnostics {
+    events: Mutex<VecDeque<DiagnosticEvent>>,
+}
+
+impl Diagnostics {
+    pub fn record(&self, category: &str, message: impl Into<String>) {
+        let event = DiagnosticEvent {
+            unix_time_ms: unix_time_ms(),
+            category: category.to_string(),
+            message: message.into(),
+        };
+        log::debug!("[{}] {}", event.category, event.message);
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub fn events(&self) -> Vec<DiagnosticEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
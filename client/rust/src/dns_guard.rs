@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::alloc_audit;
+use crate::packet;
+
+/// Plain DNS and DNS over TLS.
+const DNS_PORTS: [u16; 2] = [53, 853];
+
+/// A blocked resolver is reported at most once per this interval.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of blocked resolvers remembered for report throttling.
+const MAX_REPORTED: usize = 64;
+
+/// What to do with an uplink packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// A DNS query to a resolver that isn't allowed.
+    Drop,
+    /// Like `Drop`, and the embedder should be told about the resolver.
+    DropAndReport(IpAddr),
+}
+
+/// Enforces that uplink DNS only goes to the approved in-tunnel resolvers.
+///
+/// Disabled while no resolvers are configured.
+#[derive(Default)]
+pub struct DnsGuard {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    resolvers: Vec<IpAddr>,
+    /// When each blocked resolver was last reported.
+    reported: HashMap<IpAddr, Instant>,
+}
+
+impl DnsGuard {
+    /// Replaces the approved resolvers; an empty list disables enforcement.
+    pub fn set_resolvers(&self, resolvers: Vec<IpAddr>) {
+        let mut inner = self.inner.lock().unwrap();
+        log::info!("DNS enforcement resolvers: {resolvers:?}");
+        inner.resolvers = resolvers;
+        inner.reported.clear();
+    }
+
+    pub fn resolvers(&self) -> Vec<IpAddr> {
+        self.inner.lock().unwrap().resolvers.clone()
+    }
+
+    pub fn check(&self, packet: &[u8]) -> Verdict {
+        let key = packet::flow_key(packet);
+        let is_dns = (key.proto == libc::IPPROTO_UDP as u8 || key.proto == libc::IPPROTO_TCP as u8)
+            && DNS_PORTS.contains(&key.dst_port);
+        let Some(dst) = key.dst.filter(|_| is_dns) else {
+            return Verdict::Allow;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.resolvers.is_empty() || inner.resolvers.contains(&dst) {
+            return Verdict::Allow;
+        }
+        let now = Instant::now();
+        if inner
+            .reported
+            .get(&dst)
+            .is_some_and(|at| now.duration_since(*at) < REPORT_INTERVAL)
+        {
+            return Verdict::Drop;
+        }
+        alloc_audit::exempt(|| {
+            if inner.reported.len() >= MAX_REPORTED {
+                inner
+                    .reported
+                    .retain(|_, at| now.duration_since(*at) < REPORT_INTERVAL);
+            }
+            inner.reported.insert(dst, now);
+            log::warn!("Blocked DNS to {dst}, which is not an approved resolver");
+        });
+        Verdict::DropAndReport(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::fixtures;
+
+    const APPROVED: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 53));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+
+    fn guard() -> DnsGuard {
+        let guard = DnsGuard::default();
+        guard.set_resolvers(vec![APPROVED]);
+        guard
+    }
+
+    fn udp(dst: IpAddr, port: u16) -> Vec<u8> {
+        fixtures::udp(fixtures::client(), SocketAddr::new(dst, port), b"query")
+    }
+
+    fn tcp(dst: IpAddr, port: u16) -> Vec<u8> {
+        fixtures::syn(fixtures::client(), SocketAddr::new(dst, port), None)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_resolvers_allows_everything() {
+        let guard = DnsGuard::default();
+        assert_eq!(guard.check(&udp(OTHER, 53)), Verdict::Allow);
+        assert_eq!(guard.check(&tcp(OTHER, 853)), Verdict::Allow);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_dns_to_other_resolvers() {
+        for port in DNS_PORTS {
+            for packet in [udp(OTHER, port), tcp(OTHER, port)] {
+                assert_ne!(guard().check(&packet), Verdict::Allow, "port {port}");
+            }
+            assert_eq!(guard().check(&udp(APPROVED, port)), Verdict::Allow);
+            assert_eq!(guard().check(&tcp(APPROVED, port)), Verdict::Allow);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn non_dns_traffic_passes() {
+        let guard = guard();
+        assert_eq!(guard.check(&udp(OTHER, 443)), Verdict::Allow);
+        assert_eq!(guard.check(&tcp(OTHER, 443)), Verdict::Allow);
+        assert_eq!(
+            guard.check(&fixtures::syn(fixtures::client(), fixtures::server(), None)),
+            Verdict::Allow
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_each_resolver_once_per_interval() {
+        let guard = guard();
+        assert_eq!(guard.check(&udp(OTHER, 53)), Verdict::DropAndReport(OTHER));
+        tokio::time::advance(REPORT_INTERVAL - Duration::from_millis(1)).await;
+        assert_eq!(guard.check(&tcp(OTHER, 853)), Verdict::Drop);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(guard.check(&udp(OTHER, 53)), Verdict::DropAndReport(OTHER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_expired_reports_when_full() {
+        let guard = guard();
+        let resolver = |n: usize| IpAddr::V4(Ipv4Addr::new(192, 0, 2, n as u8));
+        for n in 0..MAX_REPORTED {
+            assert_eq!(
+                guard.check(&udp(resolver(n), 53)),
+                Verdict::DropAndReport(resolver(n))
+            );
+        }
+
+        // Nothing has expired yet, so nothing is evicted.
+        tokio::time::advance(REPORT_INTERVAL / 2).await;
+        let late = resolver(MAX_REPORTED);
+        assert_eq!(guard.check(&udp(late, 53)), Verdict::DropAndReport(late));
+        assert_eq!(guard.inner.lock().unwrap().reported.len(), MAX_REPORTED + 1);
+        assert_eq!(guard.check(&udp(resolver(0), 53)), Verdict::Drop);
+
+        // Once the first reports expire, the next report evicts them.
+        tokio::time::advance(REPORT_INTERVAL / 2).await;
+        let next = resolver(MAX_REPORTED + 1);
+        assert_eq!(guard.check(&udp(next, 53)), Verdict::DropAndReport(next));
+        let reported = &guard.inner.lock().unwrap().reported;
+        assert_eq!(reported.len(), 2);
+        assert!(reported.contains_key(&late) && reported.contains_key(&next));
+    }
+}
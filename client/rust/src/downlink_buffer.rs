@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use crate::packet;
+
+/// Packets up to this size are treated as interactive (ACKs, keystrokes, game state).
+const SMALL_PACKET: usize = 256;
+
+/// Holds downlink packets while the TUN can't take them, e.g. while the VPN interface
+/// is being rebuilt, so they can be written in order once it recovers.
+///
+/// The buffer holds at most `limit` bytes. When it is full, bulk packets are evicted
+/// oldest first to make room; interactive ones (DNS, ICMP, TCP handshakes and small
+/// packets) only give way to other interactive packets.
+///
+/// The two kinds are kept in separate queues so eviction is O(1); sequence numbers
+/// restore the arrival order when writing.
+pub struct DownlinkBuffer {
+    limit: usize,
+    total: usize,
+    interactive: VecDeque<(u64, Bytes)>,
+    bulk: VecDeque<(u64, Bytes)>,
+    next_seq: u64,
+}
+
+impl DownlinkBuffer {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            total: 0,
+            interactive: VecDeque::new(),
+            bulk: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interactive.is_empty() && self.bulk.is_empty()
+    }
+
+    /// The oldest buffered packet, which is the next one to write.
+    pub fn front(&self) -> Option<&Bytes> {
+        let queue = if self.bulk_is_oldest() {
+            &self.bulk
+        } else {
+            &self.interactive
+        };
+        queue.front().map(|(_, packet)| packet)
+    }
+
+    /// Buffers a packet, evicting others if needed. Returns the number of packets
+    /// dropped, which includes `packet` itself if no room could be made for it.
+    pub fn push(&mut self, packet: Bytes) -> usize {
+        let interactive = is_interactive(&packet);
+        let mut dropped = 0;
+        while self.total + packet.len() > self.limit {
+            // Evict bulk first; interactive packets only make room for each other.
+            let victim = if !self.bulk.is_empty() {
+                self.bulk.pop_front()
+            } else if interactive {
+                self.interactive.pop_front()
+            } else {
+                None
+            };
+            let Some((_, victim)) = victim else {
+                return dropped + 1;
+            };
+            self.total -= victim.len();
+            dropped += 1;
+        }
+        self.total += packet.len();
+        let queue = if interactive {
+            &mut self.interactive
+        } else {
+            &mut self.bulk
+        };
+        queue.push_back((self.next_seq, packet));
+        self.next_seq += 1;
+        dropped
+    }
+
+    /// Takes the oldest buffered packet.
+    pub fn pop(&mut self) -> Option<Bytes> {
+        let queue = if self.bulk_is_oldest() {
+            &mut self.bulk
+        } else {
+            &mut self.interactive
+        };
+        let (_, packet) = queue.pop_front()?;
+        self.total -= packet.len();
+        Some(packet)
+    }
+
+    /// Whether the oldest buffered packet is a bulk one.
+    fn bulk_is_oldest(&self) -> bool {
+        match (self.interactive.front(), self.bulk.front()) {
+            (Some((interactive, _)), Some((bulk, _))) => bulk < interactive,
+            (None, bulk) => bulk.is_some(),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+fn is_interactive(packet: &[u8]) -> bool {
+    if packet.len() <= SMALL_PACKET || packet::is_tcp_syn_ack(packet) {
+        return true;
+    }
+    let key = packet::flow_key(packet);
+    key.proto == libc::IPPROTO_ICMP as u8
+        || key.proto == libc::IPPROTO_ICMPV6 as u8
+        || key.src_port == 53
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{client, server, udp};
+
+    /// A bulk packet whose payload starts with `tag`.
+    fn bulk(tag: u8) -> Bytes {
+        udp(server(), client(), &[tag; 972]).into()
+    }
+
+    /// An interactive packet whose payload starts with `tag`.
+    fn small(tag: u8) -> Bytes {
+        udp(server(), client(), &[tag; 72]).into()
+    }
+
+    fn drain(buffer: &mut DownlinkBuffer) -> Vec<u8> {
+        std::iter::from_fn(|| buffer.pop()).map(|p| p[28]).collect()
+    }
+
+    #[test]
+    fn keeps_arrival_order() {
+        let mut buffer = DownlinkBuffer::new(64 * 1024);
+        for packet in [bulk(1), small(2), small(3), bulk(4), small(5)] {
+            assert_eq!(buffer.push(packet), 0);
+        }
+        assert_eq!(buffer.front().map(|p| p[28]), Some(1));
+        assert_eq!(drain(&mut buffer), [1, 2, 3, 4, 5]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_bulk_first() {
+        // Just enough room for the first four.
+        let mut buffer = DownlinkBuffer::new(3 * 1000 + 150);
+        for packet in [bulk(1), small(2), bulk(3), bulk(4)] {
+            assert_eq!(buffer.push(packet), 0);
+        }
+        assert_eq!(buffer.push(bulk(5)), 1);
+        assert_eq!(buffer.push(small(6)), 1);
+        assert_eq!(drain(&mut buffer), [2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn interactive_packets_only_give_way_to_each_other() {
+        let mut buffer = DownlinkBuffer::new(3 * 100);
+        for tag in 1..=3 {
+            assert_eq!(buffer.push(small(tag)), 0);
+        }
+        // No room is made for bulk, so it is dropped itself.
+        assert_eq!(buffer.push(bulk(4)), 1);
+        assert_eq!(buffer.push(small(5)), 1);
+        assert_eq!(drain(&mut buffer), [2, 3, 5]);
+    }
+
+    #[test]
+    fn drops_packets_larger_than_the_buffer() {
+        let mut buffer = DownlinkBuffer::new(500);
+        assert_eq!(buffer.push(small(1)), 0);
+        assert_eq!(buffer.push(bulk(2)), 1);
+        assert_eq!(drain(&mut buffer), [1]);
+    }
+}
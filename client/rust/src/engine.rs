@@ -0,0 +1,996 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use edge_tun::client::{Control, Incoming, Outgoing};
+use scion_proto::address::SocketAddr as ScionSocketAddr;
+use scion_stack::scionstack::ScionStack;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot, watch};
+use url::Url;
+
+use crate::auth::TokenSource;
+use crate::callback::{Fanout, GuardedCallback};
+use crate::connect::TunnelDns;
+use crate::diagnostics::{self, Diagnostics};
+use crate::dns_guard::DnsGuard;
+use crate::events::EventQueue;
+use crate::network::LinkInfo;
+use crate::persist::Store;
+use crate::power::PowerState;
+use crate::profile::Profile;
+use crate::routes::RouteCheck;
+use crate::session_info;
+use crate::split_dns::DomainRoutes;
+use crate::state::ConnectionState;
+use crate::stats::Stats;
+use crate::tun::{self, TunBackend};
+use crate::{
+    client, connect, logging, AuthProvider, ConnectionInfo, DiagnosticEvent, NetworkType,
+    PacketFlow, Route, RouteOverride, RouteVerifier, SessionHandover, SessionTotals, StateListener,
+    TransportOptions, VpnCallback, VpnClientConfig, VpnError, VpnEvent, VpnState, VpnStats,
+};
+
+/// The VPN client: establishes sessions with `handshake()` and runs the data plane
+/// with `start()`. All methods are synchronous and may be called from any thread; the
+/// client owns the Tokio runtime the session runs on.
+pub struct ToyVpnClient {
+    /// Notified by `cancel_handshake()`, also for handshakes replacing a running session.
+    handshake_cancel: Arc<tokio::sync::Notify>,
+    runtime: OnceLock<Runtime>,
+    connection: Arc<Mutex<Option<ToyVpnClientConnection>>>,
+    /// The QUIC connection of the most recent session, kept up to date across rotations.
+    current_quic: Arc<Mutex<Option<quinn::Connection>>>,
+    /// Key updates requested since the last handshake.
+    key_updates: Arc<AtomicU64>,
+    domain_routes: Arc<DomainRoutes>,
+    dns_guard: Arc<DnsGuard>,
+    stats: Arc<Stats>,
+    /// Options as configured by the app.
+    base_options: Mutex<TransportOptions>,
+    /// Effective options, i.e. `base_options` adjusted for the current link and power state.
+    options: watch::Sender<TransportOptions>,
+    link: Mutex<Option<LinkInfo>>,
+    power: Mutex<Option<PowerState>>,
+    /// Whether the power state defers scheduled session rotation.
+    rotation_deferred: watch::Sender<bool>,
+    route_overrides: Arc<Mutex<Vec<RouteOverride>>>,
+    /// See `set_tunnel_dns()`.
+    tunnel_dns: Arc<Mutex<TunnelDns>>,
+    diagnostics: Arc<Diagnostics>,
+    events: Arc<EventQueue>,
+    state: Arc<ConnectionState>,
+    prewarmed: Arc<Mutex<Option<PrewarmedStack>>>,
+    /// Handover state of the current session; `tun_fd` is -1 until it is started on an fd.
+    handover: Arc<Mutex<Option<SessionHandover>>>,
+    /// Set by `detach()` so the data plane reports why it stopped.
+    detached: Arc<AtomicBool>,
+    /// State persisted across restarts, once `set_state_path()` was called.
+    store: Mutex<Option<Arc<Store>>>,
+    /// Source of SNAP tokens, once `set_auth_provider()` was called.
+    auth: Mutex<Option<Arc<TokenSource>>>,
+    /// Verifier and interval for checking the installed routes, see `set_route_verifier()`.
+    route_verifier: Mutex<Option<(Arc<dyn RouteVerifier>, Duration)>>,
+    /// Hands TUN fds from `replace_tun()` to the running data plane.
+    new_tuns: Mutex<Option<mpsc::Sender<TunBackend>>>,
+}
+
+/// A SCION stack being built in the background by `prewarm()`.
+struct PrewarmedStack {
+    endhost_api: Url,
+    snap_token: String,
+    stack: tokio::task::JoinHandle<anyhow::Result<ScionStack>>,
+}
+
+/// Surfaces a `VpnError` behind `e` as is; anything else is a `StartFailed`.
+fn into_vpn_error(e: anyhow::Error) -> VpnError {
+    e.downcast::<VpnError>()
+        .unwrap_or_else(|e| VpnError::StartFailed(e.to_string()))
+}
+
+/// A handshake run for `handshake_async()`, aborted if its caller stops waiting for it.
+struct HandshakeTask {
+    state: Arc<ConnectionState>,
+    task: tokio::task::JoinHandle<Result<VpnClientConfig, VpnError>>,
+}
+
+impl Drop for HandshakeTask {
+    fn drop(&mut self) {
+        if !self.task.is_finished() {
+            log::info!("Handshake cancelled");
+            self.task.abort();
+            self.state.end_handshake(false);
+        }
+    }
+}
+
+/// What a handshake works with, shared with the client rather than borrowed from it,
+/// so that `handshake_async()` can run it as a task on the client's runtime without
+/// keeping the client alive: dropping the last reference there would drop the runtime
+/// on one of its own threads.
+struct Handshaker {
+    handshake_cancel: Arc<tokio::sync::Notify>,
+    state: Arc<ConnectionState>,
+    options: TransportOptions,
+    diagnostics: Arc<Diagnostics>,
+    store: Option<Arc<Store>>,
+    prewarmed: Arc<Mutex<Option<PrewarmedStack>>>,
+    route_overrides: Arc<Mutex<Vec<RouteOverride>>>,
+    tunnel_dns: Arc<Mutex<TunnelDns>>,
+    current_quic: Arc<Mutex<Option<quinn::Connection>>>,
+    key_updates: Arc<AtomicU64>,
+    connection: Arc<Mutex<Option<ToyVpnClientConnection>>>,
+    handover: Arc<Mutex<Option<SessionHandover>>>,
+}
+
+impl Handshaker {
+    async fn connect_session(
+        self,
+        auth: Arc<TokenSource>,
+        endhost_api: String,
+        edgetun_servers: Vec<String>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        // Created first, so a `cancel_handshake()` right after the state change counts.
+        let cancelled = self.handshake_cancel.notified();
+        if self.state.try_begin_handshake().is_err() {
+            return Err(VpnError::AlreadyRunning);
+        }
+        let res = tokio::select! {
+            res = self.establish_session(auth, endhost_api, edgetun_servers) => res,
+            _ = cancelled => {
+                log::info!("Handshake cancelled");
+                Err(VpnError::Cancelled)
+            }
+        };
+        self.state.end_handshake(res.is_ok());
+        res
+    }
+
+    async fn establish_session(
+        &self,
+        auth: Arc<TokenSource>,
+        endhost_api: String,
+        edgetun_servers: Vec<String>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        log::info!("Starting handshake");
+
+        let servers = edgetun_servers
+            .iter()
+            .map(|s| {
+                ScionSocketAddr::from_str(s)
+                    .map_err(|e| VpnError::InvalidServerAddress(format!("{s:?}: {e}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let endhost_url = Url::from_str(&endhost_api)
+            .map_err(|e| VpnError::InvalidConfig(format!("Invalid endhost API URL: {e}")))?;
+
+        let options = self.options.clone();
+        let (snap_token, connection) = connect::with_timeout(&options, async {
+            // The auth provider may block, e.g. while the app refreshes the token.
+            let source = auth.clone();
+            let snap_token = tokio::task::spawn_blocking(move || source.token()).await??;
+            let prewarmed = self
+                .prewarmed
+                .lock()
+                .unwrap()
+                .take()
+                .filter(|p| p.endhost_api == endhost_url && p.snap_token == snap_token);
+            let params = connect::SessionParams {
+                endhost_api: endhost_url,
+                edgetun_servers: servers,
+                auth,
+                store: self.store.clone(),
+            };
+            let scion_stack = match prewarmed {
+                Some(p) => match p.stack.await {
+                    Ok(Ok(stack)) => {
+                        log::info!("Using prewarmed SCION stack");
+                        Some(stack)
+                    }
+                    Ok(Err(e)) => {
+                        log::warn!("Prewarming failed, retrying: {e:?}");
+                        None
+                    }
+                    Err(e) => {
+                        log::warn!("Prewarm task failed, retrying: {e}");
+                        None
+                    }
+                },
+                None => None,
+            };
+            let connection = match scion_stack {
+                Some(stack) => {
+                    params
+                        .connect_with(stack, &options, &self.diagnostics)
+                        .await?
+                }
+                None => params.connect(&options, &self.diagnostics).await?,
+            };
+            Ok((snap_token, connection))
+        })
+        .await
+        .map_err(into_vpn_error)?;
+
+        let config = connect::client_config(
+            &connection.ctrl,
+            &self.route_overrides.lock().unwrap(),
+            &self.tunnel_dns.lock().unwrap(),
+        )
+        .map_err(into_vpn_error)?;
+
+        *self.current_quic.lock().unwrap() = Some(connection.quic.clone());
+        self.key_updates.store(0, Ordering::Relaxed);
+        self.connection.lock().unwrap().replace(connection);
+        *self.handover.lock().unwrap() = Some(SessionHandover {
+            tun_fd: -1,
+            snap_token,
+            endhost_api,
+            edgetun_servers,
+            config: config.clone(),
+            options: TransportOptions::default(),
+        });
+
+        Ok(config)
+    }
+}
+
+/// An established edgetun session, handed from `handshake()` to the data plane.
+pub(crate) struct ToyVpnClientConnection {
+    pub(crate) edge_read: Incoming,
+    pub(crate) edge_write: Outgoing,
+    pub(crate) ctrl: Control,
+    pub(crate) quic: quinn::Connection,
+    pub(crate) params: connect::SessionParams,
+}
+
+impl Default for ToyVpnClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ToyVpnClient {
+    pub fn new() -> Self {
+        logging::init();
+
+        let events = Arc::new(EventQueue::default());
+        Self {
+            handshake_cancel: Arc::new(tokio::sync::Notify::new()),
+            runtime: OnceLock::new(),
+            connection: Arc::new(Mutex::new(None)),
+            current_quic: Arc::new(Mutex::new(None)),
+            key_updates: Arc::new(AtomicU64::new(0)),
+            domain_routes: Arc::new(DomainRoutes::new()),
+            dns_guard: Arc::new(DnsGuard::default()),
+            stats: Arc::new(Stats::default()),
+            base_options: Mutex::new(TransportOptions::default()),
+            options: watch::channel(TransportOptions::default()).0,
+            link: Mutex::new(None),
+            power: Mutex::new(None),
+            rotation_deferred: watch::channel(false).0,
+            route_overrides: Arc::new(Mutex::new(Vec::new())),
+            tunnel_dns: Arc::new(Mutex::new(TunnelDns::default())),
+            diagnostics: Arc::new(Diagnostics::default()),
+            state: ConnectionState::new(events.clone()),
+            events,
+            prewarmed: Arc::new(Mutex::new(None)),
+            handover: Arc::new(Mutex::new(None)),
+            detached: Arc::new(AtomicBool::new(false)),
+            store: Mutex::new(None),
+            auth: Mutex::new(None),
+            route_verifier: Mutex::new(None),
+            new_tuns: Mutex::new(None),
+        }
+    }
+
+    /// Creates a client, failing instead of aborting if the Tokio runtime can't be built.
+    pub fn create() -> Result<Self, VpnError> {
+        let client = Self::new();
+        client.runtime()?;
+        Ok(client)
+    }
+
+    /// Returns the Tokio runtime, creating it on first use.
+    fn runtime(&self) -> Result<&Runtime, VpnError> {
+        if let Some(rt) = self.runtime.get() {
+            return Ok(rt);
+        }
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| {
+                log::error!("Failed to create Tokio runtime: {e}");
+                VpnError::RuntimeUnavailable(e.to_string())
+            })?;
+        Ok(self.runtime.get_or_init(|| rt))
+    }
+
+    /// Connects to the first reachable of `edgetun_servers` (raced, in order of preference)
+    /// and returns the tunnel configuration it assigned.
+    ///
+    /// With a state path set, the servers are reordered by how reliably they accepted
+    /// past handshakes, so a flaky one drops behind its fallbacks until it recovers.
+    ///
+    /// Failures are categorized where the cause is known, e.g. `EndhostApiUnreachable`
+    /// or `AuthRejected`; if all servers fail, the last server's error is returned.
+    pub fn handshake(
+        &self,
+        snap_token: String,
+        endhost_api: String,
+        edgetun_servers: Vec<String>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        self.runtime()?.block_on(self.handshaker().connect_session(
+            Arc::new(TokenSource::fixed(snap_token)),
+            endhost_api,
+            edgetun_servers,
+        ))
+    }
+
+    /// Like `handshake()`, but without blocking the calling thread. Dropping the returned
+    /// future, e.g. by cancelling the coroutine awaiting it, cancels the handshake.
+    pub async fn handshake_async(
+        self: Arc<Self>,
+        snap_token: String,
+        endhost_api: String,
+        edgetun_servers: Vec<String>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        // The handshake needs a Tokio context, which the embedder's executor polling
+        // this future doesn't provide, so it runs on the client's runtime.
+        let rt = self.runtime()?.handle().clone();
+        let mut handshake = HandshakeTask {
+            state: self.state.clone(),
+            task: rt.spawn(self.handshaker().connect_session(
+                Arc::new(TokenSource::fixed(snap_token)),
+                endhost_api,
+                edgetun_servers,
+            )),
+        };
+        (&mut handshake.task)
+            .await
+            .map_err(|e| VpnError::StartFailed(format!("Handshake task failed: {e}")))?
+    }
+
+    /// Aborts the handshake in progress, if any, which then fails with `Cancelled`. That
+    /// includes one replacing the running session: a scheduled rotation then keeps the
+    /// current session, while reconnecting is given up on and the data plane stops.
+    pub fn cancel_handshake(&self) {
+        self.handshake_cancel.notify_waiters();
+    }
+
+    /// Sets where SNAP tokens come from for `handshake_with_auth()`; `None` removes it.
+    pub fn set_auth_provider(&self, provider: Option<Box<dyn AuthProvider>>) {
+        *self.auth.lock().unwrap() = provider.map(|p| Arc::new(TokenSource::new(p)));
+    }
+
+    /// Like `handshake()`, but obtains the SNAP token from the auth provider, also when
+    /// the session is replaced later on. Fails with `AuthFailed` if no token could be
+    /// obtained, as opposed to `StartFailed` for network problems.
+    pub fn handshake_with_auth(
+        &self,
+        endhost_api: String,
+        edgetun_servers: Vec<String>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        let auth = self
+            .auth
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| VpnError::InvalidConfig("No auth provider set".into()))?;
+        // The token is fetched as part of the handshake, within its timeout.
+        self.runtime()?.block_on(self.handshaker().connect_session(
+            auth,
+            endhost_api,
+            edgetun_servers,
+        ))
+    }
+
+    fn handshaker(&self) -> Handshaker {
+        Handshaker {
+            handshake_cancel: self.handshake_cancel.clone(),
+            state: self.state.clone(),
+            options: self.options.borrow().clone(),
+            diagnostics: self.diagnostics.clone(),
+            store: self.store.lock().unwrap().clone(),
+            prewarmed: self.prewarmed.clone(),
+            route_overrides: self.route_overrides.clone(),
+            tunnel_dns: self.tunnel_dns.clone(),
+            current_quic: self.current_quic.clone(),
+            key_updates: self.key_updates.clone(),
+            connection: self.connection.clone(),
+            handover: self.handover.clone(),
+        }
+    }
+
+    /// Starts building the SCION stack (including resolving the endhost API host) in
+    /// the background, so a subsequent `handshake()` with the same parameters completes faster.
+    pub fn prewarm(&self, snap_token: String, endhost_api: String) -> Result<(), VpnError> {
+        let endhost_api = Url::from_str(&endhost_api)
+            .map_err(|e| VpnError::InvalidConfig(format!("Invalid endhost API URL: {e}")))?;
+
+        log::info!("Prewarming SCION stack for {endhost_api}");
+        let stack = self.runtime()?.spawn(connect::build_scion_stack(
+            endhost_api.clone(),
+            snap_token.clone(),
+        ));
+        if let Some(previous) = self.prewarmed.lock().unwrap().replace(PrewarmedStack {
+            endhost_api,
+            snap_token,
+            stack,
+        }) {
+            previous.stack.abort();
+        }
+        Ok(())
+    }
+
+    /// Starts the data plane on `tun_fd`. Without a callback, events are only available
+    /// through `poll_event()`.
+    pub fn start(
+        &self,
+        tun_fd: i32,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<(), VpnError> {
+        self.start_fd(tun_fd, callback)?;
+        Ok(())
+    }
+
+    fn start_fd(
+        &self,
+        tun_fd: i32,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<oneshot::Receiver<Result<(), String>>, VpnError> {
+        let started = self.start_backend(TunBackend::Fd(tun_fd), callback)?;
+        if let Some(handover) = self.handover.lock().unwrap().as_mut() {
+            handover.tun_fd = tun_fd;
+        }
+        Ok(started)
+    }
+
+    /// Switches the running data plane to `tun_fd` without a new handshake, e.g. after
+    /// the interface was rebuilt with the routes from `on_split_tunnel_routes_changed`.
+    /// Ownership of `tun_fd` is taken on success; the previous fd is closed.
+    pub fn replace_tun(&self, tun_fd: i32) -> Result<(), VpnError> {
+        let new_tuns = self.new_tuns.lock().unwrap();
+        let sender = new_tuns
+            .as_ref()
+            .filter(|_| self.is_running())
+            .ok_or_else(|| VpnError::InvalidConfig("No running session".into()))?;
+        sender
+            .try_send(TunBackend::Fd(tun_fd))
+            .map_err(|e| VpnError::InvalidConfig(format!("Can't replace TUN: {e}")))?;
+        log::info!("Replacing TUN with fd {tun_fd}");
+        if let Some(handover) = self.handover.lock().unwrap().as_mut() {
+            handover.tun_fd = tun_fd;
+        }
+        Ok(())
+    }
+
+    /// Like `start()`, but exchanges packets through embedder callbacks instead of a
+    /// TUN fd, for platforms such as iOS that don't hand out a raw descriptor.
+    pub fn start_with_packet_flow(
+        &self,
+        flow: Box<dyn PacketFlow>,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<(), VpnError> {
+        self.start_backend(TunBackend::Flow(Arc::from(flow)), callback)?;
+        Ok(())
+    }
+
+    /// `start()` for async callers. Completes once the data plane has opened `tun_fd`,
+    /// failing with `StartFailed` if it couldn't, so errors `start()` only reports
+    /// through `on_stop` reach the caller.
+    pub async fn start_async(
+        &self,
+        tun_fd: i32,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<(), VpnError> {
+        match self.start_fd(tun_fd, callback)?.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(VpnError::StartFailed(format!("Can't open TUN: {e}"))),
+            Err(_) => Err(VpnError::StartFailed("Data plane ended early".into())),
+        }
+    }
+
+    /// Starts the data plane on `tun`, returning where it reports whether `tun` opened.
+    fn start_backend(
+        &self,
+        tun: TunBackend,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<oneshot::Receiver<Result<(), String>>, VpnError> {
+        if self.is_running() {
+            return Err(VpnError::AlreadyRunning);
+        }
+        let mut callbacks: Vec<Arc<dyn VpnCallback>> = vec![self.events.clone()];
+        if let Some(callback) = callback {
+            callbacks.push(Arc::new(GuardedCallback::new(callback)));
+        }
+        let callback: Arc<dyn VpnCallback> = Arc::new(Fanout(callbacks));
+        let events = self.events.clone();
+        let detached = self.detached.clone();
+        let stats = self.stats.clone();
+        let store = self.store.lock().unwrap().clone();
+
+        // Take the connection
+        let connection = self
+            .connection
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(VpnError::StartFailed(
+                "VPN connection not established. Call handshake() first.".into(),
+            ))?;
+        let rt = self.runtime()?.handle().clone();
+
+        let state = match self.state.start_run(callback.clone()) {
+            Ok(state) => state,
+            Err(current) => {
+                // Keep the session for a `start()` once the state allows it.
+                *self.connection.lock().unwrap() = Some(connection);
+                return Err(match current {
+                    VpnState::Connected | VpnState::Reconnecting => VpnError::AlreadyRunning,
+                    state => VpnError::StartFailed(format!("Can't start while {state:?}")),
+                });
+            }
+        };
+        let (new_tuns_tx, new_tuns) = mpsc::channel(1);
+        let (started_tx, started) = oneshot::channel();
+        *self.new_tuns.lock().unwrap() = Some(new_tuns_tx);
+        let ctx = client::RunContext {
+            callback: callback.clone(),
+            stop_signal: state.stop_signal().clone(),
+            handshake_cancel: self.handshake_cancel.clone(),
+            domain_routes: self.domain_routes.clone(),
+            dns_guard: self.dns_guard.clone(),
+            stats: self.stats.clone(),
+            options: self.options.subscribe(),
+            rotation_deferred: self.rotation_deferred.subscribe(),
+            diagnostics: self.diagnostics.clone(),
+            route_overrides: self.route_overrides.clone(),
+            tunnel_dns: self.tunnel_dns.clone(),
+            events: self.events.clone(),
+            state: state.clone(),
+            route_check: self.route_check(),
+            current_quic: self.current_quic.clone(),
+            new_tuns,
+            started: started_tx,
+        };
+
+        std::thread::spawn(move || {
+            rt.block_on(async move {
+                log::info!("Rust VPN Thread started");
+                let res = client::run_vpn(tun, connection, ctx).await;
+                if let Some(store) = store {
+                    let stats = stats.snapshot();
+                    let totals = SessionTotals {
+                        unix_time_ms: diagnostics::unix_time_ms(),
+                        tx_bytes: stats.tx_bytes,
+                        rx_bytes: stats.rx_bytes,
+                    };
+                    store.record_session(totals);
+                }
+                match res {
+                    Ok(client::StopReason::Error(e)) | Err(e) => {
+                        log::error!("VPN Loop Error: {e:?}");
+                        events.error(e.to_string());
+                        callback.on_stop(client::stop_info_for_error(&e));
+                    }
+                    Ok(mut reason) => {
+                        if detached.swap(false, Ordering::Relaxed) {
+                            reason = client::StopReason::Detached;
+                        }
+                        log::info!("VPN Loop finished cleanly: {reason}");
+                        callback.on_stop(reason.into());
+                    }
+                }
+                // Only now, so `stop_async()` returns after `on_stop`.
+                state.set(VpnState::Stopped);
+            });
+        });
+
+        Ok(started)
+    }
+
+    pub fn stop(&self) {
+        log::info!("Stop signal received");
+        self.state.stop();
+    }
+
+    /// Like `stop()`, but completes only once the data plane has stopped and `on_stop`
+    /// has been called.
+    pub async fn stop_async(&self) {
+        self.stop();
+        self.state.stopped().await;
+    }
+
+    pub fn state(&self) -> VpnState {
+        self.state.get()
+    }
+
+    /// Reports every state change to `listener`, including `Handshaking`, which happens
+    /// before a callback could be passed to `start()`; `None` removes it.
+    pub fn set_state_listener(&self, listener: Option<Box<dyn StateListener>>) {
+        self.state.set_listener(listener.map(Arc::from));
+    }
+
+    /// Whether a data plane is running that hasn't been asked to stop.
+    fn is_running(&self) -> bool {
+        matches!(
+            self.state.get(),
+            VpnState::Connected | VpnState::Reconnecting
+        )
+    }
+
+    /// Returns the security parameters of the current session, if it is still open.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        let quic = self.current_quic.lock().unwrap().clone()?;
+        if quic.close_reason().is_some() {
+            return None;
+        }
+        Some(session_info::describe(
+            &quic,
+            self.key_updates.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// Rotates the traffic keys of the current session.
+    pub fn request_key_update(&self) -> Result<(), VpnError> {
+        let quic = self
+            .current_quic
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|q| q.close_reason().is_none())
+            .ok_or_else(|| VpnError::InvalidConfig("No open session".into()))?;
+        quic.force_key_update();
+        let count = self.key_updates.fetch_add(1, Ordering::Relaxed) + 1;
+        self.diagnostics.record(
+            "session",
+            format!("Key update requested ({count} since handshake)"),
+        );
+        Ok(())
+    }
+
+    /// Stops the data plane without giving up the TUN fd and returns what another
+    /// client instance needs to resume with `attach()`, e.g. after reloading the library.
+    /// The edgetun session itself can't be carried over; `attach()` establishes a new
+    /// one with the same parameters. `on_stop` is called with code `Detached`.
+    pub fn detach(&self) -> Result<SessionHandover, VpnError> {
+        let mut guard = self.handover.lock().unwrap();
+        let handover = guard
+            .as_ref()
+            .ok_or_else(|| VpnError::InvalidConfig("No session to detach".into()))?;
+        if handover.tun_fd < 0 {
+            return Err(VpnError::InvalidConfig(
+                "Only sessions running on a TUN fd can be detached".into(),
+            ));
+        }
+        // The data plane closes its fd when it stops, so the handover gets a duplicate.
+        let tun_fd = tun::dup_fd(handover.tun_fd)
+            .map_err(|e| VpnError::InvalidConfig(format!("Failed to duplicate TUN fd: {e}")))?;
+        let mut handover = guard.take().unwrap();
+        handover.tun_fd = tun_fd;
+        handover.options = self.base_options.lock().unwrap().clone();
+
+        log::info!("Detaching session, handing over TUN fd {tun_fd}");
+        self.detached.store(true, Ordering::Relaxed);
+        self.stop();
+        Ok(handover)
+    }
+
+    /// Resumes a session handed over by `detach()`: performs a new handshake with the
+    /// same parameters and starts the data plane on the handed-over TUN fd. On failure
+    /// the fd stays with the caller, e.g. to retry.
+    pub fn attach(
+        &self,
+        handover: SessionHandover,
+        callback: Option<Box<dyn VpnCallback>>,
+    ) -> Result<VpnClientConfig, VpnError> {
+        if handover.tun_fd < 0 {
+            return Err(VpnError::InvalidConfig("Handover has no TUN fd".into()));
+        }
+        self.set_transport_options(handover.options);
+        let config = if self.auth.lock().unwrap().is_some() {
+            self.handshake_with_auth(handover.endhost_api, handover.edgetun_servers)?
+        } else {
+            self.handshake(
+                handover.snap_token,
+                handover.endhost_api,
+                handover.edgetun_servers,
+            )?
+        };
+        if config.assigned_addresses != handover.config.assigned_addresses {
+            log::warn!(
+                "Reattached session was assigned {:?}, the interface has {:?}",
+                config.assigned_addresses,
+                handover.config.assigned_addresses
+            );
+            self.diagnostics.record(
+                "session",
+                "Reattached session was assigned different addresses",
+            );
+        }
+        self.start(handover.tun_fd, callback)?;
+        Ok(config)
+    }
+
+    /// Sets the domains whose resolved addresses should be routed through the tunnel.
+    pub fn set_split_tunnel_domains(&self, domains: Vec<String>) {
+        self.domain_routes.set_domains(domains);
+    }
+
+    pub fn get_stats(&self) -> VpnStats {
+        let mut stats = self.stats.snapshot();
+        stats.power_behavior = match *self.power.lock().unwrap() {
+            Some(power) => power.behavior(),
+            None => "normal operation",
+        }
+        .into();
+        stats
+    }
+
+    pub fn set_transport_options(&self, options: TransportOptions) {
+        *self.base_options.lock().unwrap() = options;
+        self.publish_options();
+    }
+
+    pub fn transport_options(&self) -> TransportOptions {
+        self.base_options.lock().unwrap().clone()
+    }
+
+    /// Informs the client about the underlying network, so it can adapt keepalive
+    /// and stats intervals and the reconnect backoff to the link.
+    pub fn set_network_type(&self, network_type: NetworkType, metered: bool) {
+        let link = LinkInfo {
+            network_type,
+            metered,
+        };
+        let previous = self.link.lock().unwrap().replace(link);
+        if previous != Some(link) {
+            self.diagnostics.record(
+                "link",
+                format!("{previous:?} -> {network_type:?} (metered: {metered})"),
+            );
+            self.publish_options();
+        }
+    }
+
+    /// Informs the client about the device's power conditions, so it can save battery
+    /// by relaxing keepalive and stats intervals and deferring session rotation. The
+    /// chosen behavior is recorded in the diagnostics log and reported in the stats.
+    pub fn set_power_state(&self, screen_on: bool, battery_saver: bool, charging: bool) {
+        let power = PowerState {
+            screen_on,
+            battery_saver,
+            charging,
+        };
+        let previous = self.power.lock().unwrap().replace(power);
+        if previous != Some(power) {
+            self.diagnostics.record("power", power.behavior());
+            self.rotation_deferred.send_replace(power.defers_rotation());
+            self.publish_options();
+        }
+    }
+
+    /// Sets the file where state that should survive restarts is kept (server health,
+    /// stats history, ...). The app should pass a path in its private storage.
+    pub fn set_state_path(&self, path: String) {
+        let (store, recovery) = Store::open(path);
+        if let Some(recovery) = recovery {
+            self.diagnostics.record("state", recovery);
+        }
+        *self.store.lock().unwrap() = Some(Arc::new(store));
+    }
+
+    /// Returns the traffic totals of past sessions, oldest first. Empty without a state path.
+    pub fn stats_history(&self) -> Vec<SessionTotals> {
+        match &*self.store.lock().unwrap() {
+            Some(store) => store.stats_history(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns up to `max_lines` of the most recent log lines kept in memory.
+    pub fn get_recent_logs(&self, max_lines: u32) -> Vec<String> {
+        logging::recent(max_lines as usize)
+    }
+
+    /// Sets how many log lines are kept in memory (0 disables the in-memory log).
+    pub fn set_log_capacity(&self, lines: u32) {
+        logging::set_capacity(lines as usize);
+    }
+
+    /// Takes the oldest pending event, waiting up to `timeout_ms` for one. Returns `None`
+    /// on timeout. Events are queued whether or not a `VpnCallback` was given.
+    pub fn poll_event(&self, timeout_ms: u32) -> Option<VpnEvent> {
+        self.events
+            .poll(std::time::Duration::from_millis(timeout_ms.into()))
+    }
+
+    pub fn diagnostics(&self) -> Vec<DiagnosticEvent> {
+        self.diagnostics.events()
+    }
+
+    /// Recomputes the effective options and hands them to a running session.
+    fn publish_options(&self) {
+        let base = self.base_options.lock().unwrap().clone();
+        let effective = match *self.link.lock().unwrap() {
+            Some(link) => link.adjust(&base),
+            None => base,
+        };
+        let effective = match *self.power.lock().unwrap() {
+            Some(power) => power.adjust(&effective),
+            None => effective,
+        };
+        self.options.send_replace(effective);
+    }
+
+    /// Switches to a named traffic profile ("default", "gaming", "streaming", "bulk").
+    pub fn set_profile(&self, name: String) -> Result<(), VpnError> {
+        let profile = Profile::from_name(&name)
+            .ok_or_else(|| VpnError::InvalidConfig(format!("Unknown profile: {name}")))?;
+        log::info!("Switching to profile {profile:?}");
+        self.set_transport_options(profile.options());
+        Ok(())
+    }
+
+    /// Sets routes to add to or exclude from the advertised ones, applied by subsequent
+    /// handshakes and rotations.
+    pub fn set_route_overrides(&self, overrides: Vec<RouteOverride>) {
+        *self.route_overrides.lock().unwrap() = overrides;
+    }
+
+    /// Has the data plane check every `interval_ms` that the routes from the handshake are
+    /// still installed, reporting missing ones through `on_routing_conflict`. Takes
+    /// effect on the next `start()`; `None` disables the check.
+    pub fn set_route_verifier(&self, verifier: Option<Box<dyn RouteVerifier>>, interval_ms: u32) {
+        *self.route_verifier.lock().unwrap() =
+            verifier.map(|v| (Arc::from(v), Duration::from_millis(interval_ms.into())));
+    }
+
+    fn route_check(&self) -> Option<RouteCheck> {
+        let (verifier, interval) = self.route_verifier.lock().unwrap().clone()?;
+        let expected = self
+            .handover
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .config
+            .routes
+            .clone();
+        Some(RouteCheck {
+            verifier,
+            interval,
+            expected: Arc::new(Mutex::new(expected)),
+        })
+    }
+
+    /// Restricts uplink DNS (including DNS over TLS) to `resolvers`; queries to any other
+    /// server are dropped and reported through `on_dns_leak_blocked`. An empty list
+    /// disables enforcement.
+    pub fn set_dns_enforcement(&self, resolvers: Vec<String>) -> Result<(), VpnError> {
+        let resolvers = resolvers
+            .iter()
+            .map(|r| {
+                r.parse().map_err(|e| {
+                    VpnError::InvalidConfig(format!("Invalid resolver address {r:?}: {e}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.dns_guard.set_resolvers(resolvers);
+        Ok(())
+    }
+
+    /// Sets the DNS servers and search domains reported in `VpnClientConfig` for the
+    /// interface, as provisioned for the edgetun servers: their control channel only
+    /// conveys addresses and routes. Applies to subsequent handshakes and rotations.
+    pub fn set_tunnel_dns(
+        &self,
+        dns_servers: Vec<String>,
+        search_domains: Vec<String>,
+    ) -> Result<(), VpnError> {
+        let servers = dns_servers
+            .iter()
+            .map(|s| {
+                s.parse().map_err(|e| {
+                    VpnError::InvalidConfig(format!("Invalid DNS server address {s:?}: {e}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        *self.tunnel_dns.lock().unwrap() = TunnelDns {
+            servers,
+            search_domains,
+        };
+        Ok(())
+    }
+
+    pub fn dns_enforcement(&self) -> Vec<String> {
+        self.dns_guard
+            .resolvers()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    pub fn split_tunnel_domains(&self) -> Vec<String> {
+        self.domain_routes.domains()
+    }
+
+    /// Returns the host routes currently learned from DNS answers for the split tunnel domains.
+    pub fn split_tunnel_routes(&self) -> Vec<Route> {
+        self.domain_routes.routes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::AuthToken;
+
+    /// Blocks in `fetch_token` until released, then has no token.
+    struct StuckProvider(Mutex<mpsc::Receiver<()>>);
+
+    impl AuthProvider for StuckProvider {
+        fn fetch_token(&self, _force_refresh: bool) -> Option<AuthToken> {
+            let _ = self.0.lock().unwrap().recv();
+            None
+        }
+    }
+
+    fn client(handshake_timeout_ms: u32) -> (Arc<ToyVpnClient>, mpsc::Sender<()>) {
+        let client = Arc::new(ToyVpnClient::create().unwrap());
+        let (release, stuck) = mpsc::channel();
+        client.set_auth_provider(Some(Box::new(StuckProvider(Mutex::new(stuck)))));
+        client.set_transport_options(TransportOptions {
+            handshake_timeout_ms,
+            ..TransportOptions::default()
+        });
+        (client, release)
+    }
+
+    /// Never gets past the token, so it needs no servers.
+    fn handshake(client: &ToyVpnClient) -> Result<VpnClientConfig, VpnError> {
+        client.handshake_with_auth("https://snap.example".into(), Vec::new())
+    }
+
+    #[test]
+    fn tunnel_dns_servers_must_be_addresses() {
+        let client = ToyVpnClient::new();
+        let res = client.set_tunnel_dns(vec!["dns.example".into()], Vec::new());
+        assert!(matches!(res, Err(VpnError::InvalidConfig(_))));
+        let res = client.set_tunnel_dns(
+            vec!["10.8.0.1".into(), "fd00::1".into()],
+            vec!["corp.example".into()],
+        );
+        assert!(res.is_ok());
+        let dns = client.tunnel_dns.lock().unwrap();
+        assert_eq!(dns.servers.len(), 2);
+        assert_eq!(dns.search_domains, ["corp.example"]);
+    }
+
+    #[test]
+    fn token_fetch_counts_towards_the_timeout() {
+        let (client, _release) = client(50);
+        assert!(matches!(handshake(&client), Err(VpnError::Timeout(_))));
+        assert_eq!(client.state(), VpnState::Idle);
+    }
+
+    #[test]
+    fn token_fetch_can_be_cancelled() {
+        let (client, _release) = client(0);
+        let handshake = std::thread::spawn({
+            let client = client.clone();
+            move || handshake(&client)
+        });
+        while client.state() != VpnState::Handshaking {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        client.cancel_handshake();
+        assert!(matches!(
+            handshake.join().unwrap(),
+            Err(VpnError::Cancelled)
+        ));
+        assert_eq!(client.state(), VpnState::Idle);
+    }
+}
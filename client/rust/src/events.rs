@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Route, StopInfo, VpnCallback, VpnClientConfig, VpnEvent, VpnState};
+
+/// Number of events kept for the embedder; older ones are discarded.
+const MAX_EVENTS: usize = 1024;
+
+/// Queue of events for embedders that pull them with `poll_event()` instead of (or in
+/// addition to) implementing `VpnCallback`.
+///
+/// It is registered as a callback next to the embedder's, so it sees the same
+/// notifications; errors are pushed directly by the data plane.
+#[derive(Default)]
+pub struct EventQueue {
+    events: Mutex<VecDeque<VpnEvent>>,
+    ready: Condvar,
+}
+
+impl EventQueue {
+    pub fn push(&self, event: VpnEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+        self.ready.notify_one();
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        self.push(VpnEvent::Error {
+            message: message.into(),
+        });
+    }
+
+    /// Takes the oldest event, waiting up to `timeout` for one to arrive.
+    pub fn poll(&self, timeout: Duration) -> Option<VpnEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut events = self.events.lock().unwrap();
+        loop {
+            if let Some(event) = events.pop_front() {
+                return Some(event);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            events = self.ready.wait_timeout(events, remaining).unwrap().0;
+        }
+    }
+}
+
+impl VpnCallback for EventQueue {
+    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64) {
+        self.push(VpnEvent::StatsUpdate { tx_bytes, rx_bytes });
+    }
+
+    fn on_state_change(&self, state: VpnState) {
+        self.push(VpnEvent::StateChanged { state });
+    }
+
+    fn on_stop(&self, info: StopInfo) {
+        self.push(VpnEvent::Stopped { info });
+    }
+
+    fn on_session_rotated(&self, config: VpnClientConfig) {
+        self.push(VpnEvent::RoutesChanged {
+            routes: config.routes,
+        });
+    }
+
+    fn on_mtu_changed(&self, mtu: u32) {
+        self.push(VpnEvent::MtuChanged { mtu });
+    }
+
+    fn on_dns_leak_blocked(&self, resolver: String) {
+        self.push(VpnEvent::DnsLeakBlocked { resolver });
+    }
+
+    fn on_routing_conflict(&self, missing_routes: Vec<Route>) {
+        self.push(VpnEvent::RoutingConflict { missing_routes });
+    }
+
+    fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>) {
+        self.push(VpnEvent::SplitTunnelRoutesChanged { routes });
+    }
+}
@@ -0,0 +1,190 @@
+//! Well-formed packets for tests, with correct lengths and checksums.
+
+use std::net::{IpAddr, SocketAddr};
+
+pub const SYN: u8 = 0x02;
+pub const ACK: u8 = 0x10;
+
+/// The TUN side of the test connections.
+pub fn client() -> SocketAddr {
+    "10.8.0.2:49152".parse().unwrap()
+}
+
+/// The remote side of the test connections.
+pub fn server() -> SocketAddr {
+    "192.0.2.1:443".parse().unwrap()
+}
+
+/// A TCP segment from `src` to `dst`.
+pub struct Tcp<'a> {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    pub flags: u8,
+    /// Options, padded to a multiple of four bytes.
+    pub options: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl Tcp<'_> {
+    pub fn build(&self) -> Vec<u8> {
+        let mut options = self.options.to_vec();
+        options.resize(options.len().next_multiple_of(4), 0);
+        let header_len = 20 + options.len();
+        let mut tcp = Vec::with_capacity(header_len + self.payload.len());
+        tcp.extend(self.src.port().to_be_bytes());
+        tcp.extend(self.dst.port().to_be_bytes());
+        tcp.extend(self.seq.to_be_bytes());
+        tcp.extend([0; 4]);
+        tcp.extend([(header_len / 4) as u8 * 16, self.flags]);
+        tcp.extend([0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend(options);
+        tcp.extend(self.payload);
+        ip(self.src.ip(), self.dst.ip(), libc::IPPROTO_TCP as u8, tcp)
+    }
+}
+
+/// A TCP SYN from `src` to `dst`, announcing `mss` if given.
+pub fn syn(src: SocketAddr, dst: SocketAddr, mss: Option<u16>) -> Vec<u8> {
+    handshake(src, dst, SYN, mss)
+}
+
+/// A TCP SYN-ACK from `src` to `dst`, announcing `mss` if given.
+pub fn syn_ack(src: SocketAddr, dst: SocketAddr, mss: Option<u16>) -> Vec<u8> {
+    handshake(src, dst, SYN | ACK, mss)
+}
+
+fn handshake(src: SocketAddr, dst: SocketAddr, flags: u8, mss: Option<u16>) -> Vec<u8> {
+    let options: Vec<u8> = mss
+        .map(|mss| [[2, 4], mss.to_be_bytes()].concat())
+        .unwrap_or_default();
+    Tcp {
+        src,
+        dst,
+        seq: 1,
+        flags,
+        options: &options,
+        payload: &[],
+    }
+    .build()
+}
+
+/// A UDP datagram from `src` to `dst`.
+pub fn udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut udp = Vec::with_capacity(8 + payload.len());
+    udp.extend(src.port().to_be_bytes());
+    udp.extend(dst.port().to_be_bytes());
+    udp.extend((8 + payload.len() as u16).to_be_bytes());
+    udp.extend([0, 0]);
+    udp.extend(payload);
+    ip(src.ip(), dst.ip(), libc::IPPROTO_UDP as u8, udp)
+}
+
+/// Wraps a transport segment in an IP header, filling in the checksums. Both
+/// addresses must be of the same version.
+fn ip(src: IpAddr, dst: IpAddr, proto: u8, mut l4: Vec<u8>) -> Vec<u8> {
+    let checksum_at = if proto == libc::IPPROTO_TCP as u8 {
+        16
+    } else {
+        6
+    };
+    let checksum = match !fold(pseudo_header_sum(src, dst, proto, l4.len()) + sum(&l4)) {
+        // In UDP, 0 means no checksum.
+        0 if proto == libc::IPPROTO_UDP as u8 => 0xffff,
+        checksum => checksum,
+    };
+    l4[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut packet = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0x45, 0];
+            header.extend((20 + l4.len() as u16).to_be_bytes());
+            header.extend([0, 0, 0x40, 0, 64, proto, 0, 0]);
+            header.extend(src.octets());
+            header.extend(dst.octets());
+            let checksum = !fold(sum(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend((l4.len() as u16).to_be_bytes());
+            header.extend([proto, 64]);
+            header.extend(src.octets());
+            header.extend(dst.octets());
+            header
+        }
+        _ => panic!("mixed IP versions: {src} -> {dst}"),
+    };
+    packet.extend(l4);
+    packet
+}
+
+/// Verifies a packet built here (or changed since): returns 0 if the IPv4 header
+/// checksum, where there is one, and the transport checksum are both correct.
+pub fn checksum_error(packet: &[u8]) -> u16 {
+    let (header_len, src, dst, proto) = match packet[0] >> 4 {
+        4 => {
+            let ip_error = !fold(sum(&packet[..20]));
+            if ip_error != 0 {
+                return ip_error;
+            }
+            let src: [u8; 4] = packet[12..16].try_into().unwrap();
+            let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+            (20, IpAddr::from(src), IpAddr::from(dst), packet[9])
+        }
+        6 => {
+            let src: [u8; 16] = packet[8..24].try_into().unwrap();
+            let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+            (40, IpAddr::from(src), IpAddr::from(dst), packet[6])
+        }
+        version => panic!("not an IP packet: version {version}"),
+    };
+    let l4 = &packet[header_len..];
+    !fold(pseudo_header_sum(src, dst, proto, l4.len()) + sum(l4))
+}
+
+fn pseudo_header_sum(src: IpAddr, dst: IpAddr, proto: u8, len: usize) -> u32 {
+    let addresses = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => [src.octets(), dst.octets()].concat(),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => [src.octets(), dst.octets()].concat(),
+        _ => panic!("mixed IP versions: {src} -> {dst}"),
+    };
+    sum(&addresses) + u32::from(proto) + len as u32
+}
+
+/// Sum of `data` as big-endian 16-bit words, an odd last byte padded with zero.
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Folds carries into the low 16 bits, as the one's complement sum does.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_packets_check_out() {
+        let v6 = |port| SocketAddr::new("2001:db8::1".parse().unwrap(), port);
+        for packet in [
+            syn(client(), server(), Some(1460)),
+            syn_ack(v6(443), v6(50000), None),
+            udp(client(), server(), b"odd"),
+            udp(v6(53), v6(50000), &[]),
+        ] {
+            assert_eq!(checksum_error(&packet), 0);
+        }
+        let mut packet = syn(client(), server(), Some(1460));
+        packet[22] ^= 1;
+        assert_ne!(checksum_error(&packet), 0);
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::alloc_audit;
+use crate::packet::{self, FlowKey};
+
+/// Slots for connection attempts awaiting their SYN-ACK. An attempt whose slot is taken
+/// by another recent one isn't sampled.
+const MAX_PENDING: usize = 1024;
+
+/// SYNs older than this are considered lost rather than answered late.
+const MAX_HANDSHAKE_RTT: Duration = Duration::from_secs(10);
+
+/// Number of most recent samples percentiles are computed over.
+const MAX_SAMPLES: usize = 1024;
+
+/// Number of destinations tracked individually.
+const MAX_DESTINATIONS: usize = 256;
+
+/// Slots looked at for a destination, starting at the one its hash picks.
+const DESTINATION_PROBES: usize = 8;
+
+/// Number of most recent samples kept per destination.
+const SAMPLES_PER_DESTINATION: usize = 16;
+
+/// A destination needs this many samples before it can be flagged.
+const MIN_DESTINATION_SAMPLES: usize = 4;
+
+/// A destination is anomalous if its median exceeds the overall median by this factor.
+const ANOMALY_FACTOR: u32 = 3;
+
+/// Passive per-destination latency estimates from TCP handshakes through the tunnel.
+///
+/// The time between a SYN on the uplink and the matching SYN-ACK on the downlink is
+/// the round trip through the tunnel to the destination and back, without any
+/// application processing in between.
+///
+/// Nothing here takes a lock or allocates on the data plane. SYNs are noted in a
+/// fixed table of atomics by the uplink task; samples are written by the downlink task
+/// alone, into a ring and into per-destination slots guarded by sequence counters, so
+/// [`summary`](Self::summary) only ever reads.
+pub struct LatencySampler {
+    /// Reference point for the millisecond timestamps below.
+    epoch: Instant,
+    hasher: RandomState,
+    /// SYNs sent, as `tag << 32 | sent_ms` in the slot picked by their flow's hash;
+    /// 0 if free.
+    pending: [AtomicU64; MAX_PENDING],
+    /// Most recent samples over all destinations, in ms; sample `n` is at
+    /// `n % MAX_SAMPLES`.
+    samples: [AtomicU32; MAX_SAMPLES],
+    /// Samples written so far.
+    written: AtomicU64,
+    /// `written` as of the last reset; earlier samples don't count.
+    reset_at: AtomicU64,
+    destinations: [Destination; MAX_DESTINATIONS],
+    /// Bumped by a reset; destination slots of earlier generations are free.
+    generation: AtomicU32,
+}
+
+/// A destination's recent samples, written by the downlink task only.
+#[derive(Default)]
+struct Destination {
+    /// Odd while the slot is being written.
+    seq: AtomicU32,
+    /// The sampler generation the slot was taken in; 0 if never used.
+    generation: AtomicU32,
+    ipv4: AtomicBool,
+    /// The address as IPv6, IPv4 ones mapped.
+    address: [AtomicU64; 2],
+    last_seen_ms: AtomicU32,
+    /// Samples written so far; sample `n` is at `n % SAMPLES_PER_DESTINATION`.
+    written: AtomicU32,
+    samples: [AtomicU32; SAMPLES_PER_DESTINATION],
+}
+
+/// A consistent copy of a [`Destination`].
+struct DestinationSnapshot {
+    generation: u32,
+    address: IpAddr,
+    samples: Vec<u32>,
+}
+
+/// Aggregated handshake latencies, in ms. Zero while there are no samples.
+#[derive(Debug, Default)]
+pub struct LatencySummary {
+    pub p50_ms: u32,
+    pub p90_ms: u32,
+    pub p99_ms: u32,
+    /// Destinations whose latency is far above the overall median.
+    pub anomalous: Vec<String>,
+}
+
+impl Default for LatencySampler {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            hasher: RandomState::new(),
+            pending: std::array::from_fn(|_| AtomicU64::new(0)),
+            samples: std::array::from_fn(|_| AtomicU32::new(0)),
+            written: AtomicU64::new(0),
+            reset_at: AtomicU64::new(0),
+            destinations: std::array::from_fn(|_| Destination::default()),
+            generation: AtomicU32::new(1),
+        }
+    }
+}
+
+impl LatencySampler {
+    /// Notes the time of connection attempts leaving through the tunnel.
+    pub fn inspect_uplink(&self, packet: &[u8]) {
+        if !packet::is_tcp_syn(packet) {
+            return;
+        }
+        let (slot, tag) = self.pending_slot(packet::flow_key(packet));
+        let now = self.now_ms();
+        let current = slot.load(Ordering::Relaxed);
+        let taken_by_other = current != 0
+            && (current >> 32) as u32 != tag
+            && now.wrapping_sub(current as u32) < MAX_HANDSHAKE_RTT.as_millis() as u32;
+        if !taken_by_other {
+            slot.store(u64::from(tag) << 32 | u64::from(now), Ordering::Relaxed);
+        }
+    }
+
+    /// Completes a sample when the answer to a noted connection attempt arrives.
+    pub fn inspect_downlink(&self, packet: &[u8]) {
+        if !packet::is_tcp_syn_ack(packet) {
+            return;
+        }
+        let key = packet::flow_key(packet);
+        // The uplink SYN had source and destination the other way round.
+        let syn_key = FlowKey {
+            src: key.dst,
+            dst: key.src,
+            src_port: key.dst_port,
+            dst_port: key.src_port,
+            ..key
+        };
+        let (slot, tag) = self.pending_slot(syn_key);
+        let pending = slot.load(Ordering::Relaxed);
+        if pending == 0 || (pending >> 32) as u32 != tag {
+            return;
+        }
+        if slot
+            .compare_exchange(pending, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let rtt_ms = self.now_ms().wrapping_sub(pending as u32);
+        if let Some(destination) = key
+            .src
+            .filter(|_| rtt_ms < MAX_HANDSHAKE_RTT.as_millis() as u32)
+        {
+            self.add(destination, rtt_ms);
+        }
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let written = self.written.load(Ordering::Acquire);
+        let from = self
+            .reset_at
+            .load(Ordering::Relaxed)
+            .max(written.saturating_sub(MAX_SAMPLES as u64));
+        let mut overall: Vec<u32> = (from..written)
+            .map(|n| self.samples[n as usize % MAX_SAMPLES].load(Ordering::Relaxed))
+            .collect();
+        if overall.is_empty() {
+            return LatencySummary::default();
+        }
+        overall.sort_unstable();
+        let median = percentile(&overall, 50);
+        let generation = self.generation.load(Ordering::Acquire);
+        let mut anomalous: Vec<String> = self
+            .destinations
+            .iter()
+            .map(Destination::snapshot)
+            .filter(|d| d.generation == generation)
+            .filter(|d| d.samples.len() >= MIN_DESTINATION_SAMPLES)
+            .filter_map(|mut d| {
+                d.samples.sort_unstable();
+                (percentile(&d.samples, 50) > median * ANOMALY_FACTOR)
+                    .then(|| d.address.to_string())
+            })
+            .collect();
+        anomalous.sort();
+        LatencySummary {
+            p50_ms: median,
+            p90_ms: percentile(&overall, 90),
+            p99_ms: percentile(&overall, 99),
+            anomalous,
+        }
+    }
+
+    pub fn reset(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.reset_at
+            .store(self.written.load(Ordering::Acquire), Ordering::Relaxed);
+        for slot in &self.pending {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Milliseconds since creation, plus one so a taken pending slot is never 0.
+    fn now_ms(&self) -> u32 {
+        (self.epoch.elapsed().as_millis() as u32).wrapping_add(1)
+    }
+
+    /// The pending slot for a SYN of `key`, and the tag telling its flow apart from
+    /// others mapping to the same slot.
+    fn pending_slot(&self, key: FlowKey) -> (&AtomicU64, u32) {
+        let hash = self.hasher.hash_one(key);
+        (
+            &self.pending[hash as usize % MAX_PENDING],
+            (hash >> 32) as u32,
+        )
+    }
+
+    /// Records a sample. Called by the downlink task only.
+    fn add(&self, destination: IpAddr, rtt_ms: u32) {
+        alloc_audit::exempt(|| log::debug!("TCP handshake to {destination} took {rtt_ms}ms"));
+        let n = self.written.load(Ordering::Relaxed);
+        self.samples[n as usize % MAX_SAMPLES].store(rtt_ms, Ordering::Relaxed);
+        self.written.store(n + 1, Ordering::Release);
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let address = u128::from(to_ipv6(destination));
+        let start = self.hasher.hash_one(destination) as usize;
+        // The destination's own slot, else a free one, else the one not heard from the
+        // longest. As the only writer, this task can read the slots without the
+        // sequence counters.
+        let last_seen = |d: &Destination| d.last_seen_ms.load(Ordering::Relaxed);
+        let (mut own, mut free, mut oldest) = (None, None, None::<&Destination>);
+        for i in 0..DESTINATION_PROBES {
+            let candidate = &self.destinations[(start + i) % MAX_DESTINATIONS];
+            if candidate.generation.load(Ordering::Relaxed) != generation {
+                free.get_or_insert(candidate);
+            } else if candidate.address() == address {
+                own = Some(candidate);
+                break;
+            } else if oldest.is_none_or(|o| last_seen(candidate) < last_seen(o)) {
+                oldest = Some(candidate);
+            }
+        }
+        let Some(slot) = own.or(free).or(oldest) else {
+            return;
+        };
+        let now = self.now_ms();
+        slot.write(|slot| {
+            if slot.generation.load(Ordering::Relaxed) != generation || slot.address() != address {
+                slot.generation.store(generation, Ordering::Relaxed);
+                slot.ipv4.store(destination.is_ipv4(), Ordering::Relaxed);
+                slot.address[0].store((address >> 64) as u64, Ordering::Relaxed);
+                slot.address[1].store(address as u64, Ordering::Relaxed);
+                slot.written.store(0, Ordering::Relaxed);
+            }
+            let n = slot.written.load(Ordering::Relaxed);
+            slot.samples[n as usize % SAMPLES_PER_DESTINATION].store(rtt_ms, Ordering::Relaxed);
+            slot.written.store(n + 1, Ordering::Relaxed);
+            slot.last_seen_ms.store(now, Ordering::Relaxed);
+        });
+    }
+}
+
+impl Destination {
+    fn address(&self) -> u128 {
+        u128::from(self.address[0].load(Ordering::Relaxed)) << 64
+            | u128::from(self.address[1].load(Ordering::Relaxed))
+    }
+
+    /// Updates the slot, marking it as being written for concurrent readers.
+    fn write(&self, update: impl FnOnce(&Self)) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        update(self);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Copies the slot, retrying while it is being written.
+    fn snapshot(&self) -> DestinationSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let address = Ipv6Addr::from(self.address());
+            let written = self.written.load(Ordering::Relaxed) as usize;
+            let snapshot = DestinationSnapshot {
+                generation: self.generation.load(Ordering::Relaxed),
+                address: match address.to_ipv4_mapped() {
+                    Some(v4) if self.ipv4.load(Ordering::Relaxed) => v4.into(),
+                    _ => address.into(),
+                },
+                samples: self.samples[..written.min(SAMPLES_PER_DESTINATION)]
+                    .iter()
+                    .map(|s| s.load(Ordering::Relaxed))
+                    .collect(),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return snapshot;
+            }
+        }
+    }
+}
+
+fn to_ipv6(address: IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// Nearest-rank percentile of non-empty, sorted `samples`.
+fn percentile(samples: &[u32], p: usize) -> u32 {
+    let rank = (samples.len() * p).div_ceil(100).max(1);
+    samples[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::fixtures;
+
+    /// Does a handshake from a new port of the client with `server` taking `rtt`.
+    async fn handshake(sampler: &LatencySampler, server: [u8; 4], port: u16, rtt: Duration) {
+        let client = SocketAddr::new(fixtures::client().ip(), port);
+        let server = SocketAddr::new(server.into(), 443);
+        sampler.inspect_uplink(&fixtures::syn(client, server, None));
+        tokio::time::advance(rtt).await;
+        sampler.inspect_downlink(&fixtures::syn_ack(server, client, None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_is_sampled() {
+        let sampler = LatencySampler::default();
+        handshake(&sampler, [192, 0, 2, 1], 40000, Duration::from_millis(30)).await;
+        let summary = sampler.summary();
+        assert_eq!((summary.p50_ms, summary.p99_ms), (30, 30));
+
+        // A repeated or unsolicited SYN-ACK adds nothing.
+        let client = SocketAddr::new(fixtures::client().ip(), 40000);
+        for server in [fixtures::server(), "192.0.2.9:443".parse().unwrap()] {
+            sampler.inspect_downlink(&fixtures::syn_ack(server, client, None));
+        }
+        assert_eq!(sampler.written.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_answer_is_not_sampled() {
+        let sampler = LatencySampler::default();
+        handshake(&sampler, [192, 0, 2, 1], 40000, MAX_HANDSHAKE_RTT).await;
+        assert_eq!(sampler.summary().p50_ms, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_destination_is_flagged() {
+        let sampler = LatencySampler::default();
+        let mut port = 40000;
+        for server in 1..=4 {
+            for _ in 0..4 {
+                port += 1;
+                handshake(
+                    &sampler,
+                    [192, 0, 2, server],
+                    port,
+                    Duration::from_millis(10),
+                )
+                .await;
+            }
+        }
+        for _ in 0..MIN_DESTINATION_SAMPLES {
+            port += 1;
+            handshake(
+                &sampler,
+                [198, 51, 100, 1],
+                port,
+                Duration::from_millis(100),
+            )
+            .await;
+        }
+        let summary = sampler.summary();
+        assert_eq!(summary.p50_ms, 10);
+        assert_eq!(summary.anomalous, vec!["198.51.100.1".to_string()]);
+
+        sampler.reset();
+        let summary = sampler.summary();
+        assert_eq!(summary.p50_ms, 0);
+        assert!(summary.anomalous.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_table_makes_room_for_new_destinations() {
+        let sampler = LatencySampler::default();
+        for i in 0..MAX_DESTINATIONS as u16 * 2 {
+            let server = [192, 0, (i >> 8) as u8 + 2, i as u8];
+            handshake(&sampler, server, 40000 + i, Duration::from_millis(10)).await;
+        }
+        for port in 0..MIN_DESTINATION_SAMPLES as u16 {
+            handshake(
+                &sampler,
+                [198, 51, 100, 1],
+                port,
+                Duration::from_millis(100),
+            )
+            .await;
+        }
+        assert_eq!(
+            sampler.summary().anomalous,
+            vec!["198.51.100.1".to_string()]
+        );
+    }
+}
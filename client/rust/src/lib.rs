@@ -1,231 +1,424 @@
-use std::sync::{Arc, Mutex};
-use tokio::runtime::Runtime;
-
-use anyhow::Context;
-use edge_token::dummy_edge_app_token;
-use edge_tun::client::{ClientBuilder, Control, Incoming, Outgoing};
-use edge_tun::PSEUDO_SECURE_SERVER_SECRET;
-use quinn::crypto::rustls::QuicClientConfig;
-use quinn::EndpointConfig;
-use rustls::ClientConfig;
-use scion_proto::address::SocketAddr as ScionSocketAddr;
-use scion_stack::scionstack::ScionStackBuilder;
-use std::str::FromStr;
-use std::time::Duration;
-use url::Url;
-
+//! Client library of the toy VPN: tunnels IP packets from a TUN device (or an
+//! embedder-provided packet flow) to an edgetun server over SCION.
+//!
+//! The engine is [`ToyVpnClient`]. Android uses it through the UniFFI bindings
+//! generated from `toyvpn.udl`, other native embedders through the C API in [`capi`],
+//! and Rust programs directly:
+//!
+//! ```no_run
+//! use toyvpn_client::{ToyVpnClient, VpnError};
+//!
+//! # fn run(tun_fd: i32) -> Result<(), VpnError> {
+//! let client = ToyVpnClient::new();
+//! let config = client.handshake(
+//!     "snap-token".into(),
+//!     "https://snap.example.com".into(),
+//!     vec!["1-ff00:0:110,[10.0.0.1]:4443".into()],
+//! )?;
+//! // Configure the TUN device from `config`, then:
+//! client.start(tun_fd, None)?;
+//! while let Some(event) = client.poll_event(60_000) {
+//!     println!("{event:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The types below are shared by all three; the bindings are thin wrappers around them.
+
+mod alloc_audit;
+mod auth;
+mod batching;
+mod callback;
+pub mod capi;
 mod client;
+mod close;
+mod connect;
+mod diagnostics;
+mod dns_guard;
+mod downlink_buffer;
+mod engine;
+mod events;
+#[cfg(test)]
+mod fixtures;
+mod latency;
+mod logging;
+mod mss;
+mod mtu;
+mod ndp;
+mod network;
+mod packet;
+mod persist;
+mod power;
+mod profile;
+mod rng;
+mod rotation;
+mod routes;
+mod session_info;
+mod split_dns;
+mod state;
+mod stats;
+mod tun;
+mod uplink;
+mod uplink_buffer;
+
+pub use engine::ToyVpnClient;
+pub(crate) use engine::ToyVpnClientConnection;
 
 // ----- User-defined types that must exist BEFORE include_scaffolding! -----
 
+/// A prefix to route through the tunnel.
+///
+/// Only destination and prefix length: edgetun's control data carries nothing but
+/// prefixes, and Android's `VpnService.Builder` couldn't install a gateway, metric or
+/// routing table anyway.
+#[derive(Debug, Clone)]
 pub struct Route {
     pub destination: String,
     pub prefix_length: i32,
 }
 
+/// Embedder adjustment of the advertised routes, applied during `handshake()`.
+#[derive(Debug, Clone)]
+pub struct RouteOverride {
+    pub destination: String,
+    pub prefix_length: i32,
+    /// Drop the matching advertised route instead of adding it.
+    pub exclude: bool,
+}
+
+#[derive(Debug, Clone)]
 pub struct VpnClientConfig {
+    /// The first assigned address, for embedders that only handle one.
     pub client_ip: String,
+    /// All assigned addresses, e.g. one IPv4 and one IPv6 address.
+    pub assigned_addresses: Vec<String>,
+    /// The prefix length of each of `assigned_addresses`, in the same order: that of
+    /// the narrowest advertised route containing the address, or 32/128 without one.
+    pub assigned_prefix_lengths: Vec<i32>,
     pub routes: Vec<Route>,
+    /// Resolvers to configure on the interface, as set with `set_tunnel_dns()`.
+    pub dns_servers: Vec<String>,
+    /// Search domains to configure on the interface, as set with `set_tunnel_dns()`.
+    pub search_domains: Vec<String>,
 }
 
-/// Callback interface for VPN events (defined by user, called from Kotlin)
-pub trait VpnCallback: Send + Sync {
-    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64);
-    fn on_stop(&self, reason: String);
+/// How the data plane reads packets from the TUN device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunReadStrategy {
+    /// Readiness-based reads on the Tokio reactor.
+    Epoll,
+    /// Blocking reads on a dedicated thread, for kernels where epoll on TUN fds performs poorly.
+    BlockingThread,
 }
 
-/// Error type for VPN operations
-#[derive(thiserror::Error, Debug)]
-pub enum VpnError {
-    #[error("Failed to start: {0}")]
-    StartFailed(String),
+/// Tunables for the tunnel and data plane.
+///
+/// QUIC parameters and buffer limits take effect on the next `handshake()`/`start()`,
+/// the stats cadence also applies to a running session.
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    pub uplink_buffer_per_flow_bytes: u32,
+    pub uplink_buffer_total_bytes: u32,
+    pub keepalive_interval_ms: u32,
+    pub stats_interval_ms: u32,
+    pub datagram_buffer_bytes: u32,
+    pub tun_read_strategy: TunReadStrategy,
+    /// Maximum delay for coalescing background uplink traffic; 0 disables batching.
+    pub uplink_batch_max_delay_ms: u32,
+    /// Age after which the session is proactively replaced by a new one; 0 disables rotation.
+    pub max_session_duration_ms: u64,
+    /// Downlink packets buffered while the TUN can't take them; bulk traffic is dropped
+    /// first when full.
+    pub downlink_buffer_bytes: u32,
+    /// Delay before the second attempt to replace a session that stopped working;
+    /// doubled for each further one.
+    pub reconnect_initial_backoff_ms: u32,
+    /// Upper bound for the delay between reconnect attempts.
+    pub reconnect_max_backoff_ms: u32,
+    /// Failed reconnect attempts after which the VPN stops; 0 retries forever.
+    pub reconnect_max_attempts: u32,
+    /// Time a handshake may take, from reaching the SNAP to the edgetun session being
+    /// set up, before it fails with `Timeout`; 0 waits forever.
+    pub handshake_timeout_ms: u32,
 }
 
-/// The main VPN client object
-pub struct ToyVpnClient {
-    stop_signal: Arc<tokio::sync::Notify>,
-    runtime: Runtime,
-    connection: Mutex<Option<ToyVpnClientConnection>>,
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            uplink_buffer_per_flow_bytes: 32 * 1024,
+            uplink_buffer_total_bytes: 512 * 1024,
+            // 5 secs == 1/6 default idle time
+            keepalive_interval_ms: 5_000,
+            stats_interval_ms: 1_000,
+            datagram_buffer_bytes: 1024 * 1024,
+            tun_read_strategy: TunReadStrategy::Epoll,
+            uplink_batch_max_delay_ms: 0,
+            max_session_duration_ms: 0,
+            downlink_buffer_bytes: 256 * 1024,
+            reconnect_initial_backoff_ms: 1_000,
+            reconnect_max_backoff_ms: 60_000,
+            reconnect_max_attempts: 0,
+            handshake_timeout_ms: 15_000,
+        }
+    }
 }
 
-pub struct ToyVpnClientConnection {
-    edge_read: Incoming,
-    edge_write: Outgoing,
-    ctrl: Control,
+/// Kind of the underlying network the tunnel runs over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkType {
+    Wifi,
+    Cellular,
+    Ethernet,
+    Other,
 }
 
-impl Default for ToyVpnClient {
-    fn default() -> Self {
-        Self::new()
-    }
+#[derive(Debug, Clone)]
+pub struct DiagnosticEvent {
+    pub unix_time_ms: u64,
+    pub category: String,
+    pub message: String,
 }
 
-impl ToyVpnClient {
-    pub fn new() -> Self {
-        android_logger::init_once(
-            android_logger::Config::default()
-                .with_max_level(log::LevelFilter::Debug)
-                .with_tag("ToyVpnRust"),
-        );
+#[derive(Debug, Clone)]
+pub struct VpnStats {
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    pub replayed_bytes: u64,
+    pub buffer_dropped_bytes: u64,
+    /// Send retries of uplink packets after transient failures.
+    pub tx_retried_packets: u64,
+    /// Uplink packets dropped because they could neither be sent nor buffered.
+    pub tx_dropped_packets: u64,
+    /// Uplink packets dropped because their source isn't one of the assigned addresses.
+    pub tx_invalid_source_packets: u64,
+    /// Uplink DNS packets dropped because they weren't addressed to an approved resolver.
+    pub tx_dns_blocked_packets: u64,
+    /// Downlink packets dropped because the TUN stalled and the downlink buffer was full.
+    pub rx_dropped_packets: u64,
+    /// Percentiles of TCP handshake (SYN to SYN-ACK) times through the tunnel, in ms;
+    /// 0 while no handshakes were seen.
+    pub tcp_handshake_p50_ms: u32,
+    pub tcp_handshake_p90_ms: u32,
+    pub tcp_handshake_p99_ms: u32,
+    /// Destinations whose handshake times are far above the overall median.
+    pub anomalous_latency_destinations: Vec<String>,
+    /// How the client adapts to the power state reported with `set_power_state()`,
+    /// e.g. whether session rotation is deferred.
+    pub power_behavior: String,
+}
 
-        Self {
-            stop_signal: Arc::new(tokio::sync::Notify::new()),
-            runtime: tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .expect("Failed to create Tokio runtime"),
-            connection: Mutex::new(None),
-        }
-    }
+/// Security parameters of the current session's QUIC connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// QUIC always uses TLS 1.3.
+    pub tls_version: String,
+    /// The TLS 1.3 cipher suites offered, in order of preference. The QUIC stack doesn't
+    /// report which one the server picked.
+    pub cipher_suites: Vec<String>,
+    /// The negotiated application protocol.
+    pub alpn: Option<String>,
+    /// The server name the certificate was verified against.
+    pub server_name: Option<String>,
+    /// SHA-256 fingerprint of the server's certificate, hex encoded.
+    pub certificate_sha256: Option<String>,
+    /// Key updates requested with `request_key_update()` since the last handshake.
+    pub key_updates_requested: u64,
+    pub rtt_ms: u32,
+}
 
-    pub fn handshake(
-        &self,
-        snap_token: String,
-        endhost_api: String,
-        edgetun_server: String,
-    ) -> Result<VpnClientConfig, VpnError> {
-        log::info!("Starting handshake");
-
-        let edgetun_server = ScionSocketAddr::from_str(&edgetun_server).unwrap();
-        let endhost_api = Url::from_str(&endhost_api).unwrap();
-
-        let (edge_read, edge_write, ctrl) = self
-            .runtime
-            .block_on(async {
-                let quic_conn = establish_quic_conn(endhost_api, snap_token, edgetun_server)
-                    .await
-                    .context("Failed to establish QUIC connection to snap")?;
-
-                let (edge_read, edge_write, ctrl) = ClientBuilder::default()
-                    .with_initial_mtu(1280)
-                    .with_initial_auth_token(dummy_edge_app_token())
-                    .connect(quic_conn)
-                    .await
-                    .expect("Failed to establish edgetun client connection");
-
-                log::info!("edgetun client connection established");
-                log::info!("Advertised routes: {:?}", ctrl.advertised_routes());
-
-                anyhow::Ok((edge_read, edge_write, ctrl))
-            })
-            .map_err(|e| VpnError::StartFailed(e.to_string()))?;
-
-        let ip = ctrl
-            .assigned_addresses().first()
-            .cloned()
-            .ok_or(VpnError::StartFailed(
-                "No assigned address from edgetun server".into(),
-            ))?;
-
-        let mut routes = Vec::new();
-
-        for route in ctrl.advertised_routes() {
-            routes.push(Route {
-                destination: route.network().to_string(),
-                prefix_length: route.prefix_len() as i32,
-            });
-        }
+/// Traffic totals of a finished session, as kept in the persisted stats history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTotals {
+    pub unix_time_ms: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
 
-        self.connection
-            .lock()
-            .unwrap()
-            .replace(ToyVpnClientConnection {
-                edge_read,
-                edge_write,
-                ctrl,
-            });
-
-        Ok(VpnClientConfig {
-            client_ip: ip.to_string(),
-            routes,
-        })
-    }
+/// What another client instance needs to resume a session, see `ToyVpnClient::detach`.
+#[derive(Debug, Clone)]
+pub struct SessionHandover {
+    /// The TUN fd, owned by the handover until it is passed to `attach()`.
+    pub tun_fd: i32,
+    pub snap_token: String,
+    pub endhost_api: String,
+    pub edgetun_servers: Vec<String>,
+    /// The configuration the TUN interface was set up with.
+    pub config: VpnClientConfig,
+    pub options: TransportOptions,
+}
 
-    pub fn start(&self, tun_fd: i32, callback: Box<dyn VpnCallback>) -> Result<(), VpnError> {
-        let stop_signal = self.stop_signal.clone();
-        let callback: Arc<dyn VpnCallback> = Arc::from(callback);
-
-        // Take the connection
-        let connection = self
-            .connection
-            .lock()
-            .unwrap()
-            .take()
-            .ok_or(VpnError::StartFailed(
-                "VPN connection not established. Call handshake() first.".into(),
-            ))?;
-
-        let rt = self.runtime.handle().clone();
-        std::thread::spawn(move || {
-            rt.block_on(async move {
-                log::info!("Rust VPN Thread started");
-                let res = client::run_vpn(tun_fd, connection, callback.clone(), stop_signal).await;
-                if let Err(e) = res {
-                    log::error!("VPN Loop Error: {e:?}");
-                    callback.on_stop(e.to_string());
-                } else {
-                    log::info!("VPN Loop finished cleanly");
-                    callback.on_stop("Stopped".to_string());
-                }
-            });
-        });
-
-        Ok(())
-    }
+/// Connection state of a `ToyVpnClient`, see `ToyVpnClient::state`. Changes are
+/// reported through `on_state_change` and [`VpnEvent::StateChanged`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VpnState {
+    /// No session yet, or the last handshake failed.
+    Idle,
+    /// `handshake()` is in progress, or the session it established awaits `start()`.
+    Handshaking,
+    /// The data plane is running.
+    Connected,
+    /// The session stopped working and a new one is being established.
+    Reconnecting,
+    /// `stop()` or `detach()` was called and the data plane is shutting down.
+    Stopping,
+    /// The data plane has stopped; `on_stop` tells why.
+    Stopped,
+}
 
-    pub fn stop(&self) {
-        log::info!("Stop signal received");
-        self.stop_signal.notify_one();
-    }
+/// Why the data plane stopped, see [`StopInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopCode {
+    /// `stop()` was called.
+    Stopped,
+    /// The TUN fd was taken away, e.g. because another VPN app took over.
+    Revoked,
+    /// `detach()` was called; the TUN fd lives on in the handover.
+    Detached,
+    /// The server ended the session.
+    ServerClosed,
+    /// The data plane failed.
+    Error,
+}
+
+/// What `on_stop` reports about the end of the data plane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopInfo {
+    pub code: StopCode,
+    /// Human-readable details for logs, e.g. the server's close reason. Not localized.
+    pub detail: String,
+    /// Whether a new handshake and `start()` may succeed without user intervention.
+    pub retriable: bool,
+    /// For `Error`, the error followed by its causes, outermost first.
+    pub error_chain: Vec<String>,
+}
+
+/// Events returned by `ToyVpnClient::poll_event`, mirroring the `VpnCallback` notifications.
+#[derive(Debug, Clone)]
+pub enum VpnEvent {
+    StatsUpdate { tx_bytes: u64, rx_bytes: u64 },
+    StateChanged { state: VpnState },
+    RoutesChanged { routes: Vec<Route> },
+    MtuChanged { mtu: u32 },
+    RoutingConflict { missing_routes: Vec<Route> },
+    SplitTunnelRoutesChanged { routes: Vec<Route> },
+    DnsLeakBlocked { resolver: String },
+    Error { message: String },
+    Stopped { info: StopInfo },
+}
+
+/// Callback interface for VPN events, implemented by the embedder (e.g. in Kotlin).
+pub trait VpnCallback: Send + Sync {
+    fn on_stats_update(&self, tx_bytes: u64, rx_bytes: u64);
+    /// The connection state changed. `Stopped` is reported right after `on_stop`.
+    fn on_state_change(&self, state: VpnState);
+    /// The data plane stopped; this is the last notification of a `start()`.
+    fn on_stop(&self, info: StopInfo);
+    /// The session was replaced, after `max_session_duration_ms` or because the old one
+    /// failed; `config` is the new session's configuration.
+    fn on_session_rotated(&self, config: VpnClientConfig);
+    /// The path to the server now carries packets of at most `mtu` bytes; larger
+    /// ones are dropped. New TCP connections are clamped to fit it; for other traffic,
+    /// the embedder may rebuild the interface with this MTU.
+    fn on_mtu_changed(&self, mtu: u32);
+    /// DNS to `resolver` was dropped because it isn't one of the approved resolvers.
+    /// Reported at most once a minute per resolver.
+    fn on_dns_leak_blocked(&self, resolver: String);
+    /// Routes the interface was set up with are no longer installed, e.g. because another
+    /// app took over the default route. The VPN should be rebuilt to restore them.
+    fn on_routing_conflict(&self, missing_routes: Vec<Route>);
+    /// The host routes learned for the split tunnel domains changed, because one was
+    /// learned or expired; `routes` is the complete current set. They only take effect
+    /// once the interface is rebuilt with them and handed over with `replace_tun()`.
+    fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>);
+}
+
+/// A SNAP token as obtained by an [`AuthProvider`].
+#[derive(Debug, Clone)]
+pub struct AuthToken {
+    pub token: String,
+    /// Expiry in milliseconds since the Unix epoch; 0 if unknown.
+    pub expires_at_unix_ms: u64,
+}
+
+/// Supplies SNAP tokens, e.g. from the app's OAuth flow, for `handshake_with_auth()`
+/// and for every later session the client establishes.
+pub trait AuthProvider: Send + Sync {
+    /// Returns a token, or `None` if the user couldn't be authenticated. With
+    /// `force_refresh`, the previous token has expired and must not be returned again.
+    /// May block, e.g. while the app refreshes the token.
+    fn fetch_token(&self, force_refresh: bool) -> Option<AuthToken>;
+}
+
+/// Hears of every connection state change, for `ToyVpnClient::set_state_listener`. Unlike
+/// the callback passed to `start()`, it can be set before `handshake()`.
+pub trait StateListener: Send + Sync {
+    fn on_state_change(&self, state: VpnState);
+}
+
+/// Reports the routes actually installed in the OS, for `ToyVpnClient::set_route_verifier`.
+pub trait RouteVerifier: Send + Sync {
+    /// Returns the routes currently pointing at the VPN interface.
+    fn installed_routes(&self) -> Vec<Route>;
+}
+
+/// Packet source/sink provided by the embedder instead of a TUN fd, with
+/// `NEPacketTunnelFlow` semantics.
+pub trait PacketFlow: Send + Sync {
+    /// Blocks until at least one packet is available. Should return an empty list
+    /// once the tunnel is being torn down.
+    fn read_packets(&self) -> Vec<Vec<u8>>;
+    /// Called in order from a dedicated thread, so it may block while the embedder
+    /// is busy; packets queue up (and are eventually dropped) meanwhile.
+    fn write_packets(&self, packets: Vec<Vec<u8>>);
+}
+
+/// Error type for VPN operations
+#[derive(thiserror::Error, Debug)]
+pub enum VpnError {
+    #[error("Failed to start: {0}")]
+    StartFailed(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Async runtime unavailable: {0}")]
+    RuntimeUnavailable(String),
+    /// No token could be obtained; unlike `StartFailed`, retrying won't help until the
+    /// user has authenticated again.
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+    /// An edgetun server address couldn't be parsed.
+    #[error("Invalid server address: {0}")]
+    InvalidServerAddress(String),
+    /// The SNAP's endhost API couldn't be reached, e.g. because the device is offline.
+    /// Worth retrying once connectivity changes.
+    #[error("Endhost API unreachable: {0}")]
+    EndhostApiUnreachable(String),
+    /// The TLS handshake with the server failed, e.g. because its certificate isn't
+    /// trusted. Retrying won't help.
+    #[error("TLS error: {0}")]
+    TlsError(String),
+    /// The server rejected the SNAP token. A new token is needed before retrying.
+    #[error("Authentication rejected by server: {0}")]
+    AuthRejected(String),
+    /// The server accepted the session but assigned no address to it.
+    #[error("No address assigned by server")]
+    NoAddressAssigned,
+    /// A data plane is running or still stopping, or another handshake is under way;
+    /// `stop_async()` or `cancel_handshake()` it first.
+    #[error("VPN is already running")]
+    AlreadyRunning,
+    /// The handshake took longer than `handshake_timeout_ms`.
+    #[error("Handshake timed out: {0}")]
+    Timeout(String),
+    /// The handshake was aborted by `cancel_handshake()`.
+    #[error("Handshake cancelled")]
+    Cancelled,
 }
 
-/// Establishes a QUIC connection to the edge app server via the given SNAP.
-async fn establish_quic_conn(
-    endhost_api_addr: url::Url,
-    auth_token: String,
-    server_addr: ScionSocketAddr,
-) -> anyhow::Result<quinn::Connection> {
-    let scion_stack = ScionStackBuilder::new(endhost_api_addr)
-        .with_auth_token(auth_token)
-        .build()
-        .await
-        .context("Failed to create SCION stack")?;
-
-    let (cert_der, _server_config) = scion_sdk_utils::test::generate_cert(
-        PSEUDO_SECURE_SERVER_SECRET,
-        vec!["localhost".into()],
-        vec![b"edgetun".to_vec()],
-    );
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(cert_der).unwrap();
-
-    let mut client_crypto = ClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    client_crypto.alpn_protocols = vec![b"edgetun".to_vec()];
-
-    let mut transport_config = quinn::TransportConfig::default();
-    // 5 secs == 1/6 default idle time
-    transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
-    let mut client_config =
-        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto).unwrap()));
-    client_config.transport_config(Arc::new(transport_config));
-    let mut endpoint = scion_stack
-        .quic_endpoint(None, EndpointConfig::default(), None, None)
-        .await
-        .unwrap();
-
-    endpoint.set_default_client_config(client_config);
-
-    log::info!("created quic endpoint, connecting to edge app server");
-
-    let conn = endpoint
-        .connect(server_addr, "localhost")
-        .context("Failed to initialize connection to edge app server")?
-        .await
-        .context("Failed to establish connection to edge app server")?;
-
-    Ok(conn)
+/// The TUN read strategy to use on an Android device with API level `api_level`
+/// (`Build.VERSION.SDK_INT`).
+pub fn recommended_tun_read_strategy(api_level: u32) -> TunReadStrategy {
+    tun::recommended_read_strategy(api_level)
 }
 
 // ----- Include UniFFI scaffolding AFTER defining the types -----
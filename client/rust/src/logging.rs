@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use android_logger::AndroidLogger;
+
+const DEFAULT_CAPACITY: usize = 5000;
+
+/// Forwards log records to logcat and keeps the most recent lines in memory,
+/// since logcat rotates too quickly to be useful for bug reports.
+struct RingLogger {
+    android: AndroidLogger,
+    ring: LineRing,
+}
+
+/// The most recent `capacity` lines; a capacity of 0 keeps none.
+struct LineRing {
+    capacity: AtomicUsize,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl LineRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: AtomicUsize::new(capacity),
+            lines: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Appends the line made by `line`, evicting the oldest ones if full. `line` isn't
+    /// called while capture is disabled.
+    fn push(&self, line: impl FnOnce() -> String) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let line = line();
+        let mut lines = self.lines.lock().unwrap();
+        while lines.len() >= capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn recent(&self, max_lines: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(max_lines))
+            .cloned()
+            .collect()
+    }
+
+    fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut lines = self.lines.lock().unwrap();
+        while lines.len() > capacity {
+            lines.pop_front();
+        }
+    }
+}
+
+static LOGGER: OnceLock<RingLogger> = OnceLock::new();
+
+pub fn init() {
+    let mut created = false;
+    let logger = LOGGER.get_or_init(|| {
+        created = true;
+        RingLogger {
+            android: AndroidLogger::new(
+                android_logger::Config::default()
+                    .with_max_level(log::LevelFilter::Debug)
+                    .with_tag("ToyVpnRust"),
+            ),
+            ring: LineRing::new(DEFAULT_CAPACITY),
+        }
+    });
+    if created && log::set_logger(logger).is_ok() {
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+}
+
+/// Returns up to `max_lines` of the most recent log lines, oldest first.
+pub fn recent(max_lines: usize) -> Vec<String> {
+    LOGGER
+        .get()
+        .map(|logger| logger.ring.recent(max_lines))
+        .unwrap_or_default()
+}
+
+/// Sets the number of lines kept in memory.
+pub fn set_capacity(capacity: usize) {
+    if let Some(logger) = LOGGER.get() {
+        logger.ring.set_capacity(capacity);
+    }
+}
+
+impl log::Log for RingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.android.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.android.log(record);
+
+        self.ring.push(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!(
+                "{}.{:03} {} {}: {}",
+                now.as_secs(),
+                now.subsec_millis(),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        });
+    }
+
+    fn flush(&self) {
+        self.android.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(capacity: usize, lines: std::ops::Range<u32>) -> LineRing {
+        let ring = LineRing::new(capacity);
+        for n in lines {
+            ring.push(|| n.to_string());
+        }
+        ring
+    }
+
+    #[test]
+    fn evicts_the_oldest_lines() {
+        let ring = ring(3, 0..5);
+        assert_eq!(ring.recent(10), ["2", "3", "4"]);
+    }
+
+    #[test]
+    fn recent_returns_the_newest_lines_oldest_first() {
+        let ring = ring(10, 0..5);
+        assert_eq!(ring.recent(2), ["3", "4"]);
+        assert_eq!(ring.recent(0), Vec::<String>::new());
+    }
+
+    #[test]
+    fn shrinking_drops_the_oldest_lines() {
+        let ring = ring(10, 0..5);
+        ring.set_capacity(2);
+        assert_eq!(ring.recent(10), ["3", "4"]);
+        ring.push(|| "5".into());
+        assert_eq!(ring.recent(10), ["4", "5"]);
+    }
+
+    #[test]
+    fn capacity_zero_disables_capture() {
+        let ring = ring(10, 0..3);
+        ring.set_capacity(0);
+        assert!(ring.recent(10).is_empty());
+        ring.push(|| panic!("formatted while disabled"));
+        assert!(ring.recent(10).is_empty());
+    }
+}
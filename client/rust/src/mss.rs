@@ -0,0 +1,339 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::alloc_audit;
+use crate::diagnostics::Diagnostics;
+use crate::packet::{self, FlowKey};
+
+/// Packets up to this size fit through any IPv6 path and practically any IPv4 one.
+const SAFE_PACKET_SIZE: usize = 1280;
+
+/// IP and TCP headers without options, per IP version; a segment's MSS is the packet
+/// size less these.
+const TCP_IPV4_HEADERS: u32 = 40;
+const TCP_IPV6_HEADERS: u32 = 60;
+
+/// Retransmissions of the same large segment after which its destination is flagged.
+const BLACKHOLE_RETRANSMITS: u32 = 3;
+
+/// Flows not sending a large segment for this long are forgotten.
+const FLOW_IDLE: Duration = Duration::from_secs(60);
+
+/// Large-segment flows tracked; beyond this, new ones aren't watched.
+const MAX_FLOWS: usize = 1024;
+
+/// How long a destination stays clamped; paths change, so it is given another chance.
+const CLAMP_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Destinations clamped at the same time.
+const MAX_CLAMPED: usize = 256;
+
+/// MSS clamping to what the tunnel and the paths behind it carry.
+///
+/// The TUN interface keeps the MTU it was set up with, while the tunnel's path MTU
+/// (see `mtu::monitor`) may be lower. SYNs and SYN-ACKs therefore get their MSS lowered
+/// to fit the path MTU, so TCP never sends segments the tunnel would drop. Other
+/// protocols are left to the MTU fallback.
+///
+/// Somewhere behind the tunnel, a path may also drop packets that are too large without
+/// sending the ICMP error path MTU discovery relies on. TCP then retransmits its large
+/// segments without ever getting them through. When the uplink's [`UplinkMss`] sees
+/// that, new connections to that destination are clamped further, so both sides only
+/// send segments that fit any path. Other destinations keep the full MSS.
+///
+/// Shared by both directions; its lock is only taken for connection attempts while
+/// destinations are clamped, and when flagging one.
+pub struct MssClamp {
+    /// Clamped destinations and when they were flagged.
+    clamped: Mutex<HashMap<IpAddr, Instant>>,
+    /// Number of entries in `clamped`, to skip the lock while there are none.
+    clamped_count: AtomicUsize,
+    /// The tunnel's current path MTU; 0 until it is known.
+    path_mtu: AtomicU32,
+    diagnostics: Arc<Diagnostics>,
+}
+
+/// The uplink's side of [`MssClamp`]: clamps connection attempts and watches large
+/// segments for blackholing. Owned by the uplink task, so following flows needs no
+/// lock.
+pub struct UplinkMss {
+    clamp: Arc<MssClamp>,
+    /// The last large segment sent per flow.
+    flows: HashMap<FlowKey, Segment>,
+}
+
+struct Segment {
+    seq: u32,
+    retransmits: u32,
+    sent: Instant,
+}
+
+impl MssClamp {
+    pub fn new(diagnostics: Arc<Diagnostics>) -> Self {
+        Self {
+            clamped: Mutex::default(),
+            clamped_count: AtomicUsize::new(0),
+            path_mtu: AtomicU32::new(0),
+            diagnostics,
+        }
+    }
+
+    /// Sets the path MTU that new connections are clamped to.
+    pub fn set_path_mtu(&self, mtu: u32) {
+        self.path_mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Clamps accepted connections like outgoing ones, so we don't send large segments
+    /// either.
+    pub fn inspect_downlink(&self, packet: Bytes) -> Bytes {
+        if !packet::is_tcp_syn_ack(&packet) {
+            return packet;
+        }
+        self.clamp(packet, |(src, _)| src)
+    }
+
+    /// Lowers the MSS of `packet` to fit the path MTU, or any path if the remote end
+    /// picked by `remote` is flagged.
+    fn clamp(&self, packet: Bytes, remote: impl Fn((IpAddr, IpAddr)) -> IpAddr) -> Bytes {
+        let Some(addresses) = packet::addresses(&packet) else {
+            return packet;
+        };
+        let remote = remote(addresses);
+        let Some(current) = packet::tcp_mss(&packet) else {
+            return packet;
+        };
+        let headers = if remote.is_ipv4() {
+            TCP_IPV4_HEADERS
+        } else {
+            TCP_IPV6_HEADERS
+        };
+        let mut max_packet = match self.path_mtu.load(Ordering::Relaxed) {
+            0 => u32::MAX,
+            mtu => mtu,
+        };
+        if self.is_clamped(remote) {
+            max_packet = max_packet.min(SAFE_PACKET_SIZE as u32);
+        }
+        let mss = max_packet.saturating_sub(headers).min(u16::MAX.into()) as u16;
+        if current <= mss {
+            return packet;
+        }
+        // Once per clamped connection, so copying is fine.
+        alloc_audit::exempt(|| {
+            let mut clamped = packet.to_vec();
+            if !packet::clamp_tcp_mss(&mut clamped, mss) {
+                return packet;
+            }
+            log::debug!("Clamped MSS to {mss} for connection with {remote}");
+            Bytes::from(clamped)
+        })
+    }
+
+    fn is_clamped(&self, destination: IpAddr) -> bool {
+        if self.clamped_count.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let mut clamped = self.clamped.lock().unwrap();
+        let Some(since) = clamped.get(&destination) else {
+            return false;
+        };
+        if since.elapsed() < CLAMP_DURATION {
+            return true;
+        }
+        clamped.remove(&destination);
+        self.clamped_count.store(clamped.len(), Ordering::Relaxed);
+        drop(clamped);
+        self.diagnostics
+            .record("mss", format!("No longer clamping MSS for {destination}"));
+        false
+    }
+
+    /// Flags `destination`. Returns false if it already was.
+    fn clamp_destination(&self, destination: IpAddr, now: Instant) -> bool {
+        let mut clamped = self.clamped.lock().unwrap();
+        if clamped.contains_key(&destination) {
+            return false;
+        }
+        if clamped.len() >= MAX_CLAMPED {
+            // Make room by giving the longest-clamped destination another chance.
+            if let Some(oldest) = clamped
+                .iter()
+                .min_by_key(|(_, since)| **since)
+                .map(|(ip, _)| *ip)
+            {
+                clamped.remove(&oldest);
+            }
+        }
+        clamped.insert(destination, now);
+        self.clamped_count.store(clamped.len(), Ordering::Relaxed);
+        true
+    }
+}
+
+impl UplinkMss {
+    pub fn new(clamp: Arc<MssClamp>) -> Self {
+        Self {
+            clamp,
+            // Sized up front, so tracking a flow never allocates on the data plane.
+            flows: HashMap::with_capacity(MAX_FLOWS),
+        }
+    }
+
+    /// Watches uplink TCP for blackholed segments and clamps connection attempts to
+    /// the path MTU, and further for flagged destinations.
+    pub fn inspect(&mut self, packet: Bytes) -> Bytes {
+        if packet::is_tcp_syn(&packet) {
+            return self.clamp.clamp(packet, |(_, dst)| dst);
+        }
+        if packet.len() <= SAFE_PACKET_SIZE {
+            return packet;
+        }
+        let Some((seq, len)) = packet::tcp_segment(&packet).filter(|(_, len)| *len > 0) else {
+            return packet;
+        };
+        let key = packet::flow_key(&packet);
+        let Some(dst) = key.dst else {
+            return packet;
+        };
+
+        let now = Instant::now();
+        if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS {
+            self.flows
+                .retain(|_, s| now.duration_since(s.sent) < FLOW_IDLE);
+            if self.flows.len() >= MAX_FLOWS {
+                return packet;
+            }
+        }
+        let segment = match self.flows.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // The first time we see this segment; it hasn't been retransmitted.
+                entry.insert(Segment {
+                    seq,
+                    retransmits: 0,
+                    sent: now,
+                });
+                return packet;
+            }
+        };
+        if segment.seq != seq {
+            // Progress: a new segment, so the previous one got through.
+            *segment = Segment {
+                seq,
+                retransmits: 0,
+                sent: now,
+            };
+            return packet;
+        }
+        segment.sent = now;
+        segment.retransmits += 1;
+        if segment.retransmits >= BLACKHOLE_RETRANSMITS {
+            self.flows.remove(&key);
+            if self.clamp.clamp_destination(dst, now) {
+                self.clamp.diagnostics.record(
+                    "mss",
+                    format!(
+                        "{len} byte segments to {dst} are retransmitted without progress, \
+                         clamping MSS of new connections to it"
+                    ),
+                );
+            }
+        }
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn syn(mss: u16) -> Bytes {
+        Bytes::from(fixtures::syn(
+            fixtures::client(),
+            fixtures::server(),
+            Some(mss),
+        ))
+    }
+
+    fn uplink() -> UplinkMss {
+        UplinkMss::new(Arc::new(MssClamp::new(Arc::new(Diagnostics::default()))))
+    }
+
+    /// A full-size segment starting at `seq` from the client to the server.
+    fn segment(seq: u32) -> Bytes {
+        Bytes::from(
+            fixtures::Tcp {
+                src: fixtures::client(),
+                dst: fixtures::server(),
+                seq,
+                flags: fixtures::ACK,
+                options: &[],
+                payload: &[0; 1400],
+            }
+            .build(),
+        )
+    }
+
+    #[test]
+    fn syn_is_kept_without_path_mtu() {
+        let packet = syn(1460);
+        assert_eq!(uplink().inspect(packet.clone()), packet);
+    }
+
+    #[test]
+    fn syn_is_clamped_to_path_mtu() {
+        let mut mss = uplink();
+        mss.clamp.set_path_mtu(1400);
+        let packet = mss.inspect(syn(1460));
+        assert_eq!(packet::tcp_mss(&packet), Some(1360));
+        assert_eq!(fixtures::checksum_error(&packet), 0);
+    }
+
+    #[test]
+    fn smaller_mss_is_kept() {
+        let mut mss = uplink();
+        mss.clamp.set_path_mtu(1400);
+        let packet = syn(1200);
+        assert_eq!(mss.inspect(packet.clone()), packet);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blackholed_destination_is_clamped() {
+        let mut mss = uplink();
+        // The original and one retransmission short of the threshold, then progress.
+        for _ in 0..BLACKHOLE_RETRANSMITS {
+            mss.inspect(segment(1));
+        }
+        mss.inspect(segment(1401));
+        assert_eq!(packet::tcp_mss(&mss.inspect(syn(1460))), Some(1460));
+
+        for _ in 0..=BLACKHOLE_RETRANSMITS {
+            mss.inspect(segment(2801));
+        }
+        let clamped = mss.inspect(syn(1460));
+        assert_eq!(
+            packet::tcp_mss(&clamped),
+            Some(SAFE_PACKET_SIZE as u16 - 40)
+        );
+        assert_eq!(fixtures::checksum_error(&clamped), 0);
+        // Connections from the destination are clamped as well.
+        let syn_ack = fixtures::syn_ack(fixtures::server(), fixtures::client(), Some(1460));
+        let clamped = mss.clamp.inspect_downlink(Bytes::from(syn_ack));
+        assert_eq!(
+            packet::tcp_mss(&clamped),
+            Some(SAFE_PACKET_SIZE as u16 - 40)
+        );
+
+        tokio::time::advance(CLAMP_DURATION).await;
+        assert_eq!(packet::tcp_mss(&mss.inspect(syn(1460))), Some(1460));
+        assert_eq!(mss.clamp.clamped_count.load(Ordering::Relaxed), 0);
+    }
+}
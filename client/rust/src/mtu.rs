@@ -0,0 +1,239 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::mss::MssClamp;
+use crate::VpnCallback;
+
+/// How often the path MTU of the current session is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Initial headroom for edgetun's per-packet framing inside a QUIC datagram. edgetun
+/// doesn't specify its framing overhead, so this is only an estimate, which
+/// [`MtuFallback`] raises when packets that should have fit are rejected as too large.
+const INITIAL_TUNNEL_OVERHEAD: usize = 8;
+/// Upper bound for the calibrated overhead, so rejections that are really due to the
+/// path shrinking can't lower the MTU without limit; the fallback covers those.
+const MAX_TUNNEL_OVERHEAD: usize = 64;
+
+/// TUN MTUs stepped down through on send failures; the last is IPv6's minimum.
+const FALLBACK_PLATEAUS: [u32; 4] = [1420, 1400, 1350, 1280];
+/// Sends failing as too large within [`FAILURE_WINDOW`] before the MTU is stepped down.
+const FAILURES_TO_STEP_DOWN: u32 = 3;
+const FAILURE_WINDOW: Duration = Duration::from_secs(10);
+/// How long a lowered MTU is kept before the full path MTU is tried again.
+const FALLBACK_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Whether a failed send was rejected because the packet is too large for the path.
+pub fn is_too_large(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<quinn::SendDatagramError>(),
+            Some(quinn::SendDatagramError::TooLarge)
+        ) || cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.raw_os_error() == Some(libc::EMSGSIZE))
+    })
+}
+
+/// An MTU lowered below the path MTU QUIC reports, because sends keep failing as too
+/// large, e.g. right after a path change before QUIC has noticed.
+///
+/// Repeated failures step the MTU down [`FALLBACK_PLATEAUS`], never below 1280. After
+/// [`FALLBACK_DURATION`] the fallback is lifted, which probes the full path MTU again;
+/// if that still fails, it is stepped down anew.
+///
+/// A packet rejected although it was within the maximum datagram size less the assumed
+/// tunnel overhead shows that edgetun's framing takes more room than that; the overhead
+/// is raised accordingly.
+pub struct MtuFallback {
+    inner: Mutex<FallbackState>,
+    /// Notified whenever the fallback MTU is lowered or the overhead raised.
+    changed: Notify,
+    /// The maximum datagram size last seen by `monitor`; 0 until then.
+    max_datagram: AtomicUsize,
+    overhead: AtomicUsize,
+}
+
+impl Default for MtuFallback {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::default(),
+            changed: Notify::new(),
+            max_datagram: AtomicUsize::new(0),
+            overhead: AtomicUsize::new(INITIAL_TUNNEL_OVERHEAD),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FallbackState {
+    mtu: Option<u32>,
+    since: Option<Instant>,
+    failures: u32,
+    window_start: Option<Instant>,
+}
+
+impl MtuFallback {
+    /// Notes that a packet of `len` bytes was too large to send.
+    pub fn too_large(&self, len: usize) {
+        self.calibrate_overhead(len);
+        let now = Instant::now();
+        let mut state = self.inner.lock().unwrap();
+        if state
+            .window_start
+            .is_none_or(|start| now.duration_since(start) > FAILURE_WINDOW)
+        {
+            state.window_start = Some(now);
+            state.failures = 0;
+        }
+        state.failures += 1;
+        if state.failures < FAILURES_TO_STEP_DOWN {
+            return;
+        }
+        state.failures = 0;
+        state.window_start = None;
+
+        let current = state.mtu.unwrap_or(u32::MAX).min(len as u32);
+        let Some(&mtu) = FALLBACK_PLATEAUS
+            .iter()
+            .find(|&&p| p < current)
+            .or(FALLBACK_PLATEAUS.last())
+        else {
+            return;
+        };
+        if state.mtu == Some(mtu) {
+            return;
+        }
+        log::warn!("Sends of {len} byte packets keep failing, falling back to MTU {mtu}");
+        state.mtu = Some(mtu);
+        state.since = Some(now);
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    /// Raises the tunnel overhead if a packet of `len` bytes should have fit.
+    fn calibrate_overhead(&self, len: usize) {
+        let max_datagram = self.max_datagram.load(Ordering::Relaxed);
+        let overhead = self.overhead.load(Ordering::Relaxed);
+        if max_datagram == 0 || len + overhead > max_datagram {
+            return;
+        }
+        let needed = (max_datagram - len + 1).min(MAX_TUNNEL_OVERHEAD);
+        if needed > overhead {
+            log::warn!(
+                "{len} byte packet too large for {max_datagram} byte datagrams, assuming \
+                 {needed} bytes of tunnel overhead"
+            );
+            self.overhead.store(needed, Ordering::Relaxed);
+            self.changed.notify_one();
+        }
+    }
+
+    /// Notes the current maximum datagram size and returns the TUN MTU that fits it.
+    fn mtu_for(&self, max_datagram: usize) -> u32 {
+        self.max_datagram.store(max_datagram, Ordering::Relaxed);
+        max_datagram.saturating_sub(self.overhead.load(Ordering::Relaxed)) as u32
+    }
+
+    /// The fallback MTU, if one is in effect.
+    fn mtu(&self) -> Option<u32> {
+        let mut state = self.inner.lock().unwrap();
+        if state
+            .since
+            .is_some_and(|since| since.elapsed() >= FALLBACK_DURATION)
+        {
+            log::info!("Lifting MTU fallback, probing the full path MTU again");
+            state.mtu = None;
+            state.since = None;
+        }
+        state.mtu
+    }
+}
+
+/// Reports the TUN MTU the current session's path supports whenever it changes, and
+/// has TCP connections clamped to it through `mss`, as the interface keeps its MTU.
+///
+/// The path MTU itself is discovered by quinn's PLPMTUD (RFC 8899), enabled in
+/// `connect`: it probes with padded packets of increasing size and falls back to the
+/// base MTU when probes or full-sized packets go missing (e.g. after a path switch),
+/// which is reflected in the maximum datagram size. While `fallback` is in effect, the
+/// lower of the two is reported.
+pub async fn monitor(
+    mut session: watch::Receiver<quinn::Connection>,
+    fallback: Arc<MtuFallback>,
+    mss: Arc<MssClamp>,
+    callback: Arc<dyn VpnCallback>,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut reported = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = fallback.changed.notified() => {}
+            res = session.changed() => {
+                if res.is_err() {
+                    return;
+                }
+            }
+        }
+        let Some(max_datagram) = session.borrow().max_datagram_size() else {
+            continue;
+        };
+        let mut mtu = fallback.mtu_for(max_datagram);
+        if let Some(fallback) = fallback.mtu() {
+            mtu = mtu.min(fallback);
+        }
+        if reported != Some(mtu) {
+            log::info!("Path MTU changed: TUN MTU is now {mtu}");
+            reported = Some(mtu);
+            mss.set_path_mtu(mtu);
+            callback.on_mtu_changed(mtu);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overhead_is_raised_when_a_fitting_packet_is_rejected() {
+        let fallback = MtuFallback::default();
+        assert_eq!(fallback.mtu_for(1200), 1192);
+        fallback.too_large(1150);
+        assert_eq!(fallback.mtu_for(1200), 1149);
+    }
+
+    #[test]
+    fn overhead_is_bounded() {
+        let fallback = MtuFallback::default();
+        fallback.mtu_for(1200);
+        fallback.too_large(1000);
+        assert_eq!(fallback.mtu_for(1200), 1200 - MAX_TUNNEL_OVERHEAD as u32);
+    }
+
+    #[test]
+    fn overhead_is_kept_for_packets_beyond_the_datagram_size() {
+        let fallback = MtuFallback::default();
+        fallback.mtu_for(1200);
+        fallback.too_large(1195);
+        assert_eq!(fallback.mtu_for(1200), 1192);
+    }
+
+    #[test]
+    fn repeated_failures_step_down() {
+        let fallback = MtuFallback::default();
+        for _ in 0..FAILURES_TO_STEP_DOWN {
+            fallback.too_large(1500);
+        }
+        assert_eq!(fallback.mtu(), Some(1420));
+        for _ in 0..FAILURES_TO_STEP_DOWN {
+            fallback.too_large(1420);
+        }
+        assert_eq!(fallback.mtu(), Some(1400));
+    }
+}
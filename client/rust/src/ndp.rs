@@ -0,0 +1,237 @@
+//! Local answers to IPv6 neighbor discovery on the TUN.
+//!
+//! With IPv6 assigned, the local stack may solicit routers and resolve neighbors on the
+//! TUN. The server never answers these, so they are handled here: router solicitations
+//! get an advertisement that advertises nothing (router lifetime 0), which stops the
+//! retries without touching the routes set up with the interface, and neighbor
+//! solicitations for anything but our own addresses are answered on behalf of the
+//! tunnel. Duplicate address detection goes unanswered, so it succeeds. No neighbor
+//! discovery message is sent into the tunnel.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::alloc_audit;
+
+const ICMPV6: u8 = 58;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const REDIRECT: u8 = 137;
+
+/// Neighbor discovery messages are only valid with this hop limit (RFC 4861).
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// The link-local address our router advertisements come from.
+const ROUTER_ADDRESS: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+/// Solicited and Override flags of a neighbor advertisement.
+const NA_SOLICITED_OVERRIDE: u8 = 0x60;
+
+/// What to do with an uplink packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Not neighbor discovery; send it through the tunnel.
+    Forward,
+    /// Neighbor discovery that needs no answer.
+    Drop,
+    /// Neighbor discovery answered by writing this packet back to the TUN.
+    Reply(Vec<u8>),
+}
+
+/// Decides what to do with `packet`, given the addresses assigned to this client.
+pub fn handle(packet: &[u8], own: &[IpAddr]) -> Action {
+    if packet.len() < 44 || packet[0] >> 4 != 6 || packet[6] != ICMPV6 {
+        return Action::Forward;
+    }
+    let icmp = &packet[40..];
+    if !(ROUTER_SOLICITATION..=REDIRECT).contains(&icmp[0]) {
+        return Action::Forward;
+    }
+    let src = ipv6(&packet[8..24]);
+    if packet[7] != NDP_HOP_LIMIT {
+        return Action::Drop;
+    }
+
+    match icmp[0] {
+        ROUTER_SOLICITATION => {
+            let dst = if src.is_unspecified() {
+                Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1)
+            } else {
+                src
+            };
+            // Hop limit, flags, router lifetime, reachable time and retransmit timer all
+            // zero: "no opinion", and not a default router.
+            alloc_audit::exempt(|| {
+                let mut ra = vec![0; 16];
+                ra[0] = ROUTER_ADVERTISEMENT;
+                log::debug!("Answering router solicitation from {src}");
+                Action::Reply(reply(ROUTER_ADDRESS, dst, ra))
+            })
+        }
+        NEIGHBOR_SOLICITATION if icmp.len() >= 24 => {
+            let target = ipv6(&icmp[8..24]);
+            if src.is_unspecified() || own.contains(&IpAddr::V6(target)) {
+                // Duplicate address detection, or resolving ourselves.
+                return Action::Drop;
+            }
+            alloc_audit::exempt(|| {
+                let mut na = vec![0; 24];
+                na[0] = NEIGHBOR_ADVERTISEMENT;
+                na[4] = NA_SOLICITED_OVERRIDE;
+                na[8..24].copy_from_slice(&target.octets());
+                log::debug!("Answering neighbor solicitation for {target} from {src}");
+                Action::Reply(reply(target, src, na))
+            })
+        }
+        _ => Action::Drop,
+    }
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let octets: [u8; 16] = bytes.try_into().unwrap();
+    octets.into()
+}
+
+/// Wraps an ICMPv6 message into an IPv6 packet, filling in the checksum.
+fn reply(src: Ipv6Addr, dst: Ipv6Addr, mut icmp: Vec<u8>) -> Vec<u8> {
+    let checksum = icmpv6_checksum(src, dst, &icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut packet = Vec::with_capacity(40 + icmp.len());
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[ICMPV6, NDP_HOP_LIMIT]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(&icmp);
+    packet
+}
+
+fn icmpv6_checksum(src: Ipv6Addr, dst: Ipv6Addr, icmp: &[u8]) -> u16 {
+    let pseudo_header = [
+        &src.octets()[..],
+        &dst.octets()[..],
+        &(icmp.len() as u32).to_be_bytes()[..],
+        &[0, 0, 0, ICMPV6][..],
+    ];
+    let mut sum: u32 = 0;
+    for chunk in pseudo_header.into_iter().chain([icmp]) {
+        for word in chunk.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    const NEIGHBOR: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+    fn own() -> [IpAddr; 1] {
+        [IpAddr::V6(HOST)]
+    }
+
+    fn router_solicitation(src: Ipv6Addr) -> Vec<u8> {
+        let mut rs = vec![0; 8];
+        rs[0] = ROUTER_SOLICITATION;
+        reply(src, Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2), rs)
+    }
+
+    fn neighbor_solicitation(src: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+        let mut ns = vec![0; 24];
+        ns[0] = NEIGHBOR_SOLICITATION;
+        ns[8..24].copy_from_slice(&target.octets());
+        reply(src, target, ns)
+    }
+
+    fn checksum_ok(packet: &[u8]) -> bool {
+        icmpv6_checksum(ipv6(&packet[8..24]), ipv6(&packet[24..40]), &packet[40..]) == 0
+    }
+
+    #[test]
+    fn answers_router_solicitation_with_zero_lifetime() {
+        let Action::Reply(ra) = handle(&router_solicitation(Ipv6Addr::UNSPECIFIED), &own()) else {
+            panic!("no reply");
+        };
+        assert_eq!(ipv6(&ra[8..24]), ROUTER_ADDRESS);
+        assert_eq!(ipv6(&ra[24..40]), ALL_NODES);
+        assert_eq!(ra[7], NDP_HOP_LIMIT);
+        assert_eq!(ra[40], ROUTER_ADVERTISEMENT);
+        // Router lifetime.
+        assert_eq!(ra[46..48], [0, 0]);
+        assert!(checksum_ok(&ra));
+
+        // A solicitation from an address is answered to that address.
+        let Action::Reply(ra) = handle(&router_solicitation(HOST), &own()) else {
+            panic!("no reply");
+        };
+        assert_eq!(ipv6(&ra[24..40]), HOST);
+    }
+
+    #[test]
+    fn answers_neighbor_solicitation_for_others() {
+        let Action::Reply(na) = handle(&neighbor_solicitation(HOST, NEIGHBOR), &own()) else {
+            panic!("no reply");
+        };
+        assert_eq!(ipv6(&na[8..24]), NEIGHBOR);
+        assert_eq!(ipv6(&na[24..40]), HOST);
+        assert_eq!(na[40], NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(na[44], NA_SOLICITED_OVERRIDE);
+        assert_eq!(ipv6(&na[48..64]), NEIGHBOR);
+        assert!(checksum_ok(&na));
+    }
+
+    #[test]
+    fn ignores_solicitations_for_own_addresses() {
+        assert_eq!(
+            handle(&neighbor_solicitation(NEIGHBOR, HOST), &own()),
+            Action::Drop
+        );
+        // Duplicate address detection.
+        assert_eq!(
+            handle(
+                &neighbor_solicitation(Ipv6Addr::UNSPECIFIED, NEIGHBOR),
+                &own()
+            ),
+            Action::Drop
+        );
+    }
+
+    #[test]
+    fn drops_ndp_with_wrong_hop_limit() {
+        let mut rs = router_solicitation(HOST);
+        rs[7] = 64;
+        assert_eq!(handle(&rs, &own()), Action::Drop);
+        let mut ns = neighbor_solicitation(HOST, NEIGHBOR);
+        ns[7] = 1;
+        assert_eq!(handle(&ns, &own()), Action::Drop);
+    }
+
+    #[test]
+    fn forwards_other_traffic() {
+        // An echo request.
+        let mut ping = vec![0; 8];
+        ping[0] = 128;
+        assert_eq!(
+            handle(&reply(HOST, NEIGHBOR, ping), &own()),
+            Action::Forward
+        );
+        let udp = crate::fixtures::udp(crate::fixtures::client(), crate::fixtures::server(), b"");
+        assert_eq!(handle(&udp, &own()), Action::Forward);
+    }
+
+    #[test]
+    fn checksum_matches_known_value() {
+        let mut ra = vec![0; 16];
+        ra[0] = ROUTER_ADVERTISEMENT;
+        assert_eq!(icmpv6_checksum(ROUTER_ADDRESS, ALL_NODES, &ra), 0x7c2f);
+    }
+}
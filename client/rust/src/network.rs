@@ -0,0 +1,88 @@
+use crate::{NetworkType, TransportOptions};
+
+/// The underlying network as reported by the embedder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub network_type: NetworkType,
+    pub metered: bool,
+}
+
+impl LinkInfo {
+    /// Derives the effective options for this link from the configured ones.
+    ///
+    /// On cellular every wakeup of the radio is expensive, so keepalives and stats
+    /// are spaced out, and so are reconnect attempts, which on a flaky cell mostly fail
+    /// anyway. On metered links the stats cadence is relaxed further, and failed
+    /// reconnects back off longer since every attempt costs the user data.
+    pub fn adjust(&self, options: &TransportOptions) -> TransportOptions {
+        let mut options = options.clone();
+        if self.network_type == NetworkType::Cellular {
+            options.keepalive_interval_ms = options.keepalive_interval_ms.max(15_000);
+            options.stats_interval_ms = options.stats_interval_ms.max(2_000);
+            options.reconnect_initial_backoff_ms = options.reconnect_initial_backoff_ms.max(2_000);
+            options.reconnect_max_backoff_ms = options.reconnect_max_backoff_ms.max(120_000);
+        }
+        if self.metered {
+            options.stats_interval_ms = options.stats_interval_ms.max(5_000);
+            options.reconnect_initial_backoff_ms = options.reconnect_initial_backoff_ms.max(5_000);
+            options.reconnect_max_backoff_ms = options.reconnect_max_backoff_ms.max(300_000);
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adjust(network_type: NetworkType, metered: bool) -> TransportOptions {
+        LinkInfo {
+            network_type,
+            metered,
+        }
+        .adjust(&TransportOptions::default())
+    }
+
+    #[test]
+    fn unmetered_wifi_keeps_options() {
+        let options = adjust(NetworkType::Wifi, false);
+        let default = TransportOptions::default();
+        assert_eq!(options.keepalive_interval_ms, default.keepalive_interval_ms);
+        assert_eq!(
+            options.reconnect_initial_backoff_ms,
+            default.reconnect_initial_backoff_ms
+        );
+        assert_eq!(
+            options.reconnect_max_backoff_ms,
+            default.reconnect_max_backoff_ms
+        );
+    }
+
+    #[test]
+    fn cellular_and_metered_back_off_longer() {
+        let default = TransportOptions::default();
+        let cellular = adjust(NetworkType::Cellular, false);
+        let metered = adjust(NetworkType::Cellular, true);
+        assert!(cellular.reconnect_initial_backoff_ms > default.reconnect_initial_backoff_ms);
+        assert!(cellular.reconnect_max_backoff_ms > default.reconnect_max_backoff_ms);
+        assert!(metered.reconnect_initial_backoff_ms > cellular.reconnect_initial_backoff_ms);
+        assert!(metered.reconnect_max_backoff_ms > cellular.reconnect_max_backoff_ms);
+        assert!(metered.keepalive_interval_ms >= 15_000);
+    }
+
+    #[test]
+    fn never_shortens_configured_backoff() {
+        let options = TransportOptions {
+            reconnect_initial_backoff_ms: 30_000,
+            reconnect_max_backoff_ms: 600_000,
+            ..TransportOptions::default()
+        };
+        let link = LinkInfo {
+            network_type: NetworkType::Cellular,
+            metered: true,
+        };
+        let adjusted = link.adjust(&options);
+        assert_eq!(adjusted.reconnect_initial_backoff_ms, 30_000);
+        assert_eq!(adjusted.reconnect_max_backoff_ms, 600_000);
+    }
+}
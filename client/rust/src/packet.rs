@@ -0,0 +1,248 @@
+//! Minimal, allocation-free inspection of IP packets seen on the TUN.
+
+/// Returns the UDP payload of an IPv4/IPv6 packet if its source port is `port`.
+pub fn udp_payload_from_port(packet: &[u8], port: u16) -> Option<&[u8]> {
+    let (proto, l4) = transport(packet)?;
+    if proto != libc::IPPROTO_UDP as u8 || l4.len() < 8 {
+        return None;
+    }
+    if u16::from_be_bytes([l4[0], l4[1]]) != port {
+        return None;
+    }
+    Some(&l4[8..])
+}
+
+/// Returns the transport protocol number and the transport header + payload.
+///
+/// IPv6 extension headers are not traversed.
+pub fn transport(packet: &[u8]) -> Option<(u8, &[u8])> {
+    transport_offset(packet).map(|(proto, offset)| (proto, &packet[offset..]))
+}
+
+/// Returns the transport protocol number and where the transport header starts.
+fn transport_offset(packet: &[u8]) -> Option<(u8, usize)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ihl = usize::from(packet[0] & 0x0f) * 4;
+            if ihl < 20 || packet.len() < ihl {
+                return None;
+            }
+            Some((packet[9], ihl))
+        }
+        6 => {
+            if packet.len() < 40 {
+                return None;
+            }
+            Some((packet[6], 40))
+        }
+        _ => None,
+    }
+}
+
+/// Identifies a transport flow by its 5-tuple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FlowKey {
+    pub proto: u8,
+    pub src: Option<std::net::IpAddr>,
+    pub dst: Option<std::net::IpAddr>,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+/// Extracts the flow key of a packet. Unparsable packets map to the default key.
+pub fn flow_key(packet: &[u8]) -> FlowKey {
+    let Some((proto, l4)) = transport(packet) else {
+        return FlowKey::default();
+    };
+    let (src, dst) = addresses(packet).unzip();
+    let (src_port, dst_port) = match proto {
+        p if (p == libc::IPPROTO_TCP as u8 || p == libc::IPPROTO_UDP as u8) && l4.len() >= 4 => (
+            u16::from_be_bytes([l4[0], l4[1]]),
+            u16::from_be_bytes([l4[2], l4[3]]),
+        ),
+        _ => (0, 0),
+    };
+    FlowKey {
+        proto,
+        src,
+        dst,
+        src_port,
+        dst_port,
+    }
+}
+
+/// Returns the source and destination address of an IPv4/IPv6 packet.
+pub fn addresses(packet: &[u8]) -> Option<(std::net::IpAddr, std::net::IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some((src.into(), dst.into()))
+        }
+        6 if packet.len() >= 40 => {
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some((src.into(), dst.into()))
+        }
+        _ => None,
+    }
+}
+
+/// Returns true for a TCP segment with SYN set and ACK unset, i.e. a connection attempt.
+pub fn is_tcp_syn(packet: &[u8]) -> bool {
+    tcp_flags(packet).is_some_and(|flags| flags & 0x12 == 0x02)
+}
+
+/// Returns true for a TCP segment with both SYN and ACK set, i.e. an accepted connection.
+pub fn is_tcp_syn_ack(packet: &[u8]) -> bool {
+    tcp_flags(packet).is_some_and(|flags| flags & 0x12 == 0x12)
+}
+
+fn tcp_flags(packet: &[u8]) -> Option<u8> {
+    match transport(packet) {
+        Some((proto, l4)) if proto == libc::IPPROTO_TCP as u8 && l4.len() >= 14 => Some(l4[13]),
+        _ => None,
+    }
+}
+
+/// Returns the sequence number and payload length of a TCP segment.
+pub fn tcp_segment(packet: &[u8]) -> Option<(u32, usize)> {
+    let (proto, l4) = transport(packet)?;
+    if proto != libc::IPPROTO_TCP as u8 || l4.len() < 20 {
+        return None;
+    }
+    let header_len = usize::from(l4[12] >> 4) * 4;
+    let seq = u32::from_be_bytes([l4[4], l4[5], l4[6], l4[7]]);
+    Some((seq, l4.len().checked_sub(header_len)?))
+}
+
+/// The MSS option of a TCP SYN or SYN-ACK, if it has one.
+pub fn tcp_mss(packet: &[u8]) -> Option<u16> {
+    let i = mss_option(packet)?;
+    Some(u16::from_be_bytes([packet[i], packet[i + 1]]))
+}
+
+/// Lowers the MSS option of a TCP SYN or SYN-ACK to at most `mss`, updating the
+/// checksum. Returns whether the packet was changed.
+pub fn clamp_tcp_mss(packet: &mut [u8], mss: u16) -> bool {
+    let Some(i) = mss_option(packet) else {
+        return false;
+    };
+    let old = u16::from_be_bytes([packet[i], packet[i + 1]]);
+    if old <= mss {
+        return false;
+    }
+    packet[i..i + 2].copy_from_slice(&mss.to_be_bytes());
+    let Some((_, offset)) = transport_offset(packet) else {
+        return false;
+    };
+    let l4 = &mut packet[offset..];
+    let checksum = u16::from_be_bytes([l4[16], l4[17]]);
+    l4[16..18].copy_from_slice(&update_checksum(checksum, old, mss).to_be_bytes());
+    true
+}
+
+/// Position of the MSS value within a TCP SYN or SYN-ACK, if it has the option.
+fn mss_option(packet: &[u8]) -> Option<usize> {
+    let (proto, offset) = transport_offset(packet)?;
+    let l4 = &packet[offset..];
+    if proto != libc::IPPROTO_TCP as u8 || l4.len() < 20 || l4[13] & 0x02 == 0 {
+        return None;
+    }
+    let header_len = (usize::from(l4[12] >> 4) * 4).min(l4.len());
+    let mut i = 20;
+    while i < header_len {
+        match l4[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = usize::from(*l4.get(i + 1).unwrap_or(&0));
+                if len < 2 || i + len > header_len {
+                    break;
+                }
+                // The incremental checksum update in `clamp_tcp_mss` needs the value
+                // 16-bit aligned, which it is in practice.
+                if kind == 2 && len == 4 && i % 2 == 0 {
+                    return Some(offset + i + 2);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+/// Incremental Internet checksum update for one changed 16-bit word (RFC 1624).
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::fixtures;
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::new("2001:db8::1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn clamps_mss_and_fixes_checksum() {
+        for mut packet in [
+            fixtures::syn(fixtures::client(), fixtures::server(), Some(1460)),
+            fixtures::syn_ack(v6(443), v6(50000), Some(1440)),
+        ] {
+            assert!(clamp_tcp_mss(&mut packet, 1200));
+            assert_eq!(tcp_mss(&packet), Some(1200));
+            assert_eq!(fixtures::checksum_error(&packet), 0);
+        }
+    }
+
+    #[test]
+    fn checksum_update_handles_carries() {
+        // Values whose sums wrap around in one's complement arithmetic.
+        for (from, to) in [(0xffff, 0), (0xffff, 1), (0x8000, 0x7fff), (0xfffe, 0x0100)] {
+            let mut packet = fixtures::syn(fixtures::client(), fixtures::server(), Some(from));
+            assert!(clamp_tcp_mss(&mut packet, to));
+            assert_eq!(tcp_mss(&packet), Some(to));
+            assert_eq!(fixtures::checksum_error(&packet), 0, "{from} -> {to}");
+        }
+    }
+
+    #[test]
+    fn finds_mss_after_other_options() {
+        // NOP, NOP, SACK permitted, then MSS.
+        let options = [1, 1, 4, 2, 2, 4, 0x05, 0xb4];
+        let mut packet = fixtures::Tcp {
+            src: fixtures::client(),
+            dst: fixtures::server(),
+            seq: 1,
+            flags: fixtures::SYN,
+            options: &options,
+            payload: &[],
+        }
+        .build();
+        assert_eq!(tcp_mss(&packet), Some(1460));
+        assert!(clamp_tcp_mss(&mut packet, 1360));
+        assert_eq!(tcp_mss(&packet), Some(1360));
+        assert_eq!(fixtures::checksum_error(&packet), 0);
+    }
+
+    #[test]
+    fn leaves_other_packets_alone() {
+        let mut smaller = fixtures::syn(fixtures::client(), fixtures::server(), Some(1200));
+        let mut without = fixtures::syn(fixtures::client(), fixtures::server(), None);
+        let mut udp = fixtures::udp(fixtures::client(), fixtures::server(), &[2, 4, 5, 0xb4]);
+        for packet in [&mut smaller, &mut without, &mut udp] {
+            let before = packet.clone();
+            assert!(!clamp_tcp_mss(packet, 1300));
+            assert_eq!(*packet, before);
+        }
+        assert_eq!(tcp_mss(&without), None);
+    }
+}
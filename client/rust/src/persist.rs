@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::SessionTotals;
+
+/// First line of the file; bumped when the format changes incompatibly.
+const HEADER: &str = "toyvpn-state 1";
+
+/// Health scores of the edgetun servers handshakes were attempted with.
+pub const SERVER_HEALTH: &str = "server_health";
+/// Traffic totals of past sessions, see [`SessionTotals`].
+pub const STATS_HISTORY: &str = "stats_history";
+
+/// Number of past sessions kept in [`STATS_HISTORY`].
+const MAX_STATS_HISTORY: usize = 32;
+
+/// Number of servers kept in [`SERVER_HEALTH`]; the least healthy are dropped first.
+const MAX_SERVER_HEALTH: usize = 32;
+
+/// Health score of a server without a record, in percent.
+pub const DEFAULT_SERVER_HEALTH: u32 = 50;
+
+/// A small key-value store persisted to a file supplied by the app, for state that
+/// should survive restarts.
+///
+/// The file is a header line, one `key=value` line per entry (with `\`, `\n` and `=`
+/// escaped) and a trailing checksum line. Every update rewrites the file via a
+/// temporary file and keeps the previous version as a backup; a missing or corrupt
+/// file is recovered from the backup, or else the store starts out empty.
+///
+/// Updates take effect in memory right away and are written by a background thread,
+/// so callers on the async runtime never wait for the file system. Dropping the store
+/// waits for the last update to be written.
+///
+/// Only server health and the stats history are kept. TLS session tickets (which would
+/// need a persistent rustls `ClientSessionStore`) and SCION path preferences (which the
+/// SCION stack keeps to itself) are not persisted, so every start does a full
+/// handshake over freshly discovered paths.
+pub struct Store {
+    entries: Mutex<BTreeMap<String, String>>,
+    /// Snapshots for the writer thread; `None` once dropped.
+    writes: Option<mpsc::Sender<BTreeMap<String, String>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Store {
+    /// Opens the store at `path`. Also returns a description of any recovery that
+    /// was necessary, for the diagnostics log.
+    pub fn open(path: impl Into<PathBuf>) -> (Self, Option<String>) {
+        let path = path.into();
+        let (entries, recovery) = match load(&path) {
+            Ok(entries) => (entries, None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match load(&backup_path(&path)) {
+                Ok(entries) => (entries, Some("Restored state from backup".to_string())),
+                Err(_) => (BTreeMap::new(), None),
+            },
+            Err(e) => match load(&backup_path(&path)) {
+                Ok(entries) => (
+                    entries,
+                    Some(format!("State unreadable ({e}), restored from backup")),
+                ),
+                Err(_) => (
+                    BTreeMap::new(),
+                    Some(format!("State unreadable ({e}), starting empty")),
+                ),
+            },
+        };
+        if let Some(recovery) = &recovery {
+            log::warn!("{}: {recovery}", path.display());
+        }
+        let (writes, pending) = mpsc::channel();
+        let writer = std::thread::spawn(move || write_loop(&path, pending));
+        let store = Self {
+            entries: Mutex::new(entries),
+            writes: Some(writes),
+            writer: Some(writer),
+        };
+        (store, recovery)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Sets `key` and has the store written back in the background.
+    pub fn set(&self, key: &str, value: impl Into<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.to_string(), value.into());
+        if let Some(writes) = &self.writes {
+            // Sent under the lock, so snapshots arrive in the order they were taken.
+            let _ = writes.send(entries.clone());
+        }
+    }
+
+    /// Appends a session's traffic totals to the stats history, dropping the oldest.
+    pub fn record_session(&self, totals: SessionTotals) {
+        let mut history = self.stats_history();
+        history.push(totals);
+        let skip = history.len().saturating_sub(MAX_STATS_HISTORY);
+        let value = history[skip..]
+            .iter()
+            .map(encode_totals)
+            .collect::<Vec<_>>()
+            .join(",");
+        self.set(STATS_HISTORY, value)
+    }
+
+    /// Returns the recorded session totals, oldest first.
+    pub fn stats_history(&self) -> Vec<SessionTotals> {
+        self.get(STATS_HISTORY)
+            .map(|v| v.split(',').filter_map(decode_totals).collect())
+            .unwrap_or_default()
+    }
+
+    /// Updates a server's health score with the outcome of a connection attempt.
+    ///
+    /// The score is a moving average of recent outcomes in percent, so a server that
+    /// fails now and then stays ahead of one that failed the last few times in a row.
+    pub fn record_handshake(&self, server: &str, ok: bool) {
+        let mut health = self.server_health();
+        let score = health.get(server).copied().unwrap_or(DEFAULT_SERVER_HEALTH);
+        let outcome = if ok { 100 } else { 0 };
+        health.insert(server.to_string(), (score * 3 + outcome) / 4);
+
+        let mut health: Vec<_> = health.into_iter().collect();
+        health.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        health.truncate(MAX_SERVER_HEALTH);
+        let value = health
+            .iter()
+            .map(|(server, score)| format!("{score} {server}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.set(SERVER_HEALTH, value)
+    }
+
+    /// Returns the health score of every server with a record, in percent.
+    pub fn server_health(&self) -> HashMap<String, u32> {
+        // Server addresses may contain commas, so entries go on separate lines.
+        self.get(SERVER_HEALTH)
+            .map(|v| {
+                v.lines()
+                    .filter_map(|line| {
+                        let (score, server) = line.split_once(' ')?;
+                        Some((server.to_string(), score.parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        drop(self.writes.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes the snapshots sent by a [`Store`] to `path` until it is dropped. Snapshots
+/// that queued up while writing are skipped in favor of the latest.
+fn write_loop(path: &Path, pending: mpsc::Receiver<BTreeMap<String, String>>) {
+    while let Ok(mut entries) = pending.recv() {
+        while let Ok(newer) = pending.try_recv() {
+            entries = newer;
+        }
+        if let Err(e) = save(path, &entries) {
+            log::warn!("Failed to write {}: {e}", path.display());
+        }
+    }
+}
+
+fn save(path: &Path, entries: &BTreeMap<String, String>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encode(entries))?;
+    // Keep the previous version around in case the new one gets damaged.
+    if path.exists() {
+        fs::rename(path, backup_path(path))?;
+    }
+    fs::rename(&tmp, path)
+}
+
+fn encode_totals(totals: &SessionTotals) -> String {
+    format!(
+        "{}:{}:{}",
+        totals.unix_time_ms, totals.tx_bytes, totals.rx_bytes
+    )
+}
+
+fn decode_totals(s: &str) -> Option<SessionTotals> {
+    let mut parts = s.split(':').map(|p| p.parse().ok());
+    let totals = SessionTotals {
+        unix_time_ms: parts.next()??,
+        tx_bytes: parts.next()??,
+        rx_bytes: parts.next()??,
+    };
+    parts.next().is_none().then_some(totals)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("bak")
+}
+
+fn load(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+    decode(&content).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt file"))
+}
+
+fn encode(entries: &BTreeMap<String, String>) -> String {
+    let mut body = format!("{HEADER}\n");
+    for (key, value) in entries {
+        body.push_str(&format!("{}={}\n", escape(key), escape(value)));
+    }
+    let checksum = fnv1a(body.as_bytes());
+    body.push_str(&format!("checksum {checksum:016x}\n"));
+    body
+}
+
+fn decode(content: &str) -> Option<BTreeMap<String, String>> {
+    let body_len = content.trim_end_matches('\n').rfind('\n')? + 1;
+    let (body, trailer) = content.split_at(body_len);
+    let checksum = u64::from_str_radix(trailer.trim().strip_prefix("checksum ")?, 16).ok()?;
+    if checksum != fnv1a(body.as_bytes()) {
+        return None;
+    }
+
+    let mut lines = body.lines();
+    if lines.next()? != HEADER {
+        return None;
+    }
+    lines
+        .map(|line| {
+            // `=` is always escaped, so the first one separates key and value.
+            let (key, value) = line.split_once('=')?;
+            Some((unescape(key)?, unescape(value)?))
+        })
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '=' => out.push_str("\\e"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => out.push('\\'),
+            'n' => out.push('\n'),
+            'e' => out.push('='),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// 64-bit FNV-1a, enough to detect truncated or garbled files.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A path in a fresh directory of its own, removed again when the test passes.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "toyvpn-persist-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir.join("state"))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    fn entries(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let entries = entries(&[("a", "1"), ("key=with\\odd\nchars", "value=\n\\")]);
+        assert_eq!(decode(&encode(&entries)), Some(entries));
+        assert_eq!(decode(&encode(&BTreeMap::new())), Some(BTreeMap::new()));
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        let s = "a=b\\c\nd";
+        assert_eq!(escape(s), "a\\eb\\\\c\\nd");
+        assert_eq!(unescape(&escape(s)).as_deref(), Some(s));
+        assert_eq!(unescape("dangling\\"), None);
+        assert_eq!(unescape("unknown\\x"), None);
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let encoded = encode(&entries(&[("server_health", "100 1-ff00:0:110,[::1]:443")]));
+        assert!(decode(&encoded.replace("100", "101")).is_none());
+        assert!(decode(&encoded[..encoded.len() - 5]).is_none());
+        assert!(decode(&encoded.replace(HEADER, "toyvpn-state 2")).is_none());
+        assert!(decode("").is_none());
+    }
+
+    #[test]
+    fn updates_survive_reopening() {
+        let path = TempPath::new();
+        let (store, recovery) = Store::open(&path.0);
+        assert_eq!(recovery, None);
+        store.record_handshake("server-a", true);
+        store.record_handshake("server-b", false);
+        store.set("other", "value=1");
+        drop(store);
+
+        let (store, recovery) = Store::open(&path.0);
+        assert_eq!(recovery, None);
+        assert_eq!(store.get("other").as_deref(), Some("value=1"));
+        let health = store.server_health();
+        assert_eq!(health["server-a"], 62);
+        assert_eq!(health["server-b"], 37);
+    }
+
+    #[test]
+    fn corrupt_file_is_recovered_from_backup() {
+        let path = TempPath::new();
+        let (store, _) = Store::open(&path.0);
+        store.set("key", "old");
+        drop(store);
+        let (store, _) = Store::open(&path.0);
+        store.set("key", "new");
+        drop(store);
+
+        fs::write(&path.0, "garbage").unwrap();
+        let (store, recovery) = Store::open(&path.0);
+        assert!(recovery.unwrap().contains("restored from backup"));
+        assert_eq!(store.get("key").as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn unrecoverable_file_starts_empty() {
+        let path = TempPath::new();
+        fs::write(&path.0, "garbage").unwrap();
+        let (store, recovery) = Store::open(&path.0);
+        assert!(recovery.unwrap().contains("starting empty"));
+        assert_eq!(store.get("key"), None);
+    }
+
+    #[test]
+    fn stats_history_keeps_the_latest_sessions() {
+        let path = TempPath::new();
+        let (store, _) = Store::open(&path.0);
+        for i in 0..MAX_STATS_HISTORY as u64 + 3 {
+            store.record_session(SessionTotals {
+                unix_time_ms: i,
+                tx_bytes: i * 10,
+                rx_bytes: i * 20,
+            });
+        }
+        let history = store.stats_history();
+        assert_eq!(history.len(), MAX_STATS_HISTORY);
+        assert_eq!(history[0].unix_time_ms, 3);
+        assert_eq!(
+            history.last().unwrap().rx_bytes,
+            (MAX_STATS_HISTORY as u64 + 2) * 20
+        );
+    }
+}
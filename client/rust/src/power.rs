@@ -0,0 +1,100 @@
+use crate::TransportOptions;
+
+/// The device's power conditions as reported by the embedder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerState {
+    pub screen_on: bool,
+    pub battery_saver: bool,
+    pub charging: bool,
+}
+
+impl PowerState {
+    /// Battery saver with the screen off and no charger: the device is trying hard to sleep.
+    fn deep_saver(&self) -> bool {
+        self.battery_saver && !self.screen_on && !self.charging
+    }
+
+    /// Whether scheduled session rotation waits until the device wakes up. Reconnects
+    /// because the uplink is failing still happen right away.
+    pub fn defers_rotation(&self) -> bool {
+        self.deep_saver()
+    }
+
+    /// Derives the effective options for this power state from the given ones.
+    ///
+    /// While charging nothing is changed. With the screen off nobody looks at the stats,
+    /// and in battery saver keepalives are spaced out too. Deferring session rotation in
+    /// deep battery saver is up to [`Self::defers_rotation`], as the session's maximum
+    /// duration stays the same.
+    pub fn adjust(&self, options: &TransportOptions) -> TransportOptions {
+        let mut options = options.clone();
+        if self.charging {
+            return options;
+        }
+        if !self.screen_on {
+            options.stats_interval_ms = options.stats_interval_ms.max(10_000);
+        }
+        if self.battery_saver {
+            // Stays below the QUIC idle timeout, so the session survives.
+            options.keepalive_interval_ms = options.keepalive_interval_ms.max(25_000);
+            options.stats_interval_ms = options.stats_interval_ms.max(30_000);
+        }
+        options
+    }
+
+    /// Describes what is adjusted in this state, for the diagnostics log and stats.
+    pub fn behavior(&self) -> &'static str {
+        if self.charging {
+            "charging: normal operation"
+        } else if self.deep_saver() {
+            "deep battery saver: relaxed keepalives and stats, session rotation deferred"
+        } else if self.battery_saver {
+            "battery saver: relaxed keepalives and stats"
+        } else if !self.screen_on {
+            "screen off: relaxed stats"
+        } else {
+            "normal operation"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEEP_SAVER: PowerState = PowerState {
+        screen_on: false,
+        battery_saver: true,
+        charging: false,
+    };
+
+    fn options() -> TransportOptions {
+        TransportOptions {
+            max_session_duration_ms: 3_600_000,
+            ..TransportOptions::default()
+        }
+    }
+
+    #[test]
+    fn deep_saver_defers_rotation_without_disabling_it() {
+        let adjusted = DEEP_SAVER.adjust(&options());
+        assert!(DEEP_SAVER.defers_rotation());
+        assert_eq!(adjusted.max_session_duration_ms, 3_600_000);
+        assert!(adjusted.keepalive_interval_ms >= 25_000);
+    }
+
+    #[test]
+    fn charging_changes_nothing() {
+        let charging = PowerState {
+            charging: true,
+            ..DEEP_SAVER
+        };
+        let adjusted = charging.adjust(&options());
+        assert!(!charging.defers_rotation());
+        assert_eq!(
+            adjusted.keepalive_interval_ms,
+            options().keepalive_interval_ms
+        );
+        assert_eq!(adjusted.stats_interval_ms, options().stats_interval_ms);
+    }
+}
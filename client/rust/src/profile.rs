@@ -0,0 +1,89 @@
+use crate::TransportOptions;
+
+/// Named bundles of transport tunables, so users can pick a trade-off between
+/// latency and throughput without knowing the individual knobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Default,
+    /// Short queues, frequent keepalives and no uplink batching for interactive,
+    /// latency-sensitive traffic.
+    Gaming,
+    /// Moderate queues, relaxed stats cadence, background uplink traffic batched briefly.
+    Streaming,
+    /// Deep queues and infrequent wakeups for maximum throughput; background uplink
+    /// traffic is batched the longest.
+    Bulk,
+}
+
+impl Profile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "gaming" => Some(Self::Gaming),
+            "streaming" => Some(Self::Streaming),
+            "bulk" => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+
+    pub fn options(self) -> TransportOptions {
+        let default = TransportOptions::default();
+        match self {
+            Self::Default => default,
+            Self::Gaming => TransportOptions {
+                uplink_buffer_per_flow_bytes: 8 * 1024,
+                uplink_buffer_total_bytes: 64 * 1024,
+                keepalive_interval_ms: 2_000,
+                datagram_buffer_bytes: 64 * 1024,
+                // Stale game state is worthless; better drop it than deliver it late.
+                downlink_buffer_bytes: 64 * 1024,
+                uplink_batch_max_delay_ms: 0,
+                ..default
+            },
+            Self::Streaming => TransportOptions {
+                uplink_buffer_per_flow_bytes: 64 * 1024,
+                uplink_buffer_total_bytes: 1024 * 1024,
+                stats_interval_ms: 2_000,
+                datagram_buffer_bytes: 1024 * 1024,
+                downlink_buffer_bytes: 1024 * 1024,
+                // A media player's sparse uplink (acks, telemetry) tolerates a short delay.
+                uplink_batch_max_delay_ms: 20,
+                ..default
+            },
+            Self::Bulk => TransportOptions {
+                uplink_buffer_per_flow_bytes: 128 * 1024,
+                uplink_buffer_total_bytes: 2 * 1024 * 1024,
+                keepalive_interval_ms: 10_000,
+                stats_interval_ms: 5_000,
+                datagram_buffer_bytes: 4 * 1024 * 1024,
+                downlink_buffer_bytes: 2 * 1024 * 1024,
+                uplink_batch_max_delay_ms: 50,
+                ..default
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_leniently() {
+        assert_eq!(Profile::from_name(" Gaming "), Some(Profile::Gaming));
+        assert_eq!(Profile::from_name("BULK"), Some(Profile::Bulk));
+        assert_eq!(Profile::from_name("turbo"), None);
+    }
+
+    #[test]
+    fn batching_grows_with_latency_tolerance() {
+        let delay = |p: Profile| p.options().uplink_batch_max_delay_ms;
+        assert_eq!(delay(Profile::Gaming), 0);
+        assert!(delay(Profile::Streaming) > 0);
+        assert!(delay(Profile::Bulk) > delay(Profile::Streaming));
+        assert_eq!(
+            delay(Profile::Default),
+            TransportOptions::default().uplink_batch_max_delay_ms
+        );
+    }
+}
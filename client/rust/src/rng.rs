@@ -0,0 +1,62 @@
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Source of randomness for timing decisions such as backoff jitter, replaceable by a
+/// seeded one in tests.
+pub trait Rng: Send + Sync {
+    fn next_u64(&self) -> u64;
+
+    /// A value in `0..=max`, close enough to uniform for jitter.
+    fn up_to(&self, max: u64) -> u64 {
+        match max.checked_add(1) {
+            Some(bound) => self.next_u64() % bound,
+            None => self.next_u64(),
+        }
+    }
+}
+
+/// The operating system's generator.
+pub struct SystemRng(SystemRandom);
+
+impl Default for SystemRng {
+    fn default() -> Self {
+        Self(SystemRandom::new())
+    }
+}
+
+impl Rng for SystemRng {
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0; 8];
+        // Only fails if the OS has no randomness to give; no jitter beats failing then.
+        if self.0.fill(&mut bytes).is_err() {
+            return 0;
+        }
+        u64::from_ne_bytes(bytes)
+    }
+}
+
+/// A deterministic xorshift64* generator, for tests.
+#[cfg(test)]
+pub struct SeededRng(AtomicU64);
+
+#[cfg(test)]
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0.
+        Self(AtomicU64::new(seed.max(1)))
+    }
+}
+
+#[cfg(test)]
+impl Rng for SeededRng {
+    fn next_u64(&self) -> u64 {
+        let mut x = self.0.load(Ordering::Relaxed);
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0.store(x, Ordering::Relaxed);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
@@ -0,0 +1,581 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use edge_tun::client::{Control, Incoming, Outgoing};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::Instant;
+
+use crate::client;
+use crate::connect::{self, SessionParams, TunnelDns};
+use crate::diagnostics::Diagnostics;
+use crate::events::EventQueue;
+use crate::rng::Rng;
+use crate::state::RunState;
+use crate::{
+    Route, RouteOverride, ToyVpnClientConnection, TransportOptions, VpnCallback, VpnError, VpnState,
+};
+
+/// Delay before retrying a failed rotation of a working session, which is kept meanwhile.
+const ROTATION_RETRY: Duration = Duration::from_secs(30);
+
+/// Hands the halves of a rotated session to the data plane tasks.
+pub struct Rotation {
+    pub uplink: mpsc::Sender<(Outgoing, Vec<IpAddr>)>,
+    /// The current session's QUIC connection, for path MTU monitoring.
+    pub quic: watch::Sender<quinn::Connection>,
+    /// The current session's QUIC connection, for `ToyVpnClient::connection_info`.
+    pub current_quic: Arc<Mutex<Option<quinn::Connection>>>,
+    /// The new session's downlink, with its QUIC connection to tell why it closes.
+    pub downlink: mpsc::Sender<(Incoming, quinn::Connection)>,
+    pub callback: Arc<dyn VpnCallback>,
+    pub diagnostics: Arc<Diagnostics>,
+    pub route_overrides: Arc<Mutex<Vec<RouteOverride>>>,
+    /// For the rotated session's DNS servers.
+    pub tunnel_dns: Arc<Mutex<TunnelDns>>,
+    pub events: Arc<EventQueue>,
+    pub state: RunState,
+    pub options: watch::Receiver<TransportOptions>,
+    /// Notified when the current session looks dead and should be replaced right away.
+    pub reconnect: Arc<Notify>,
+    /// Notified when the data plane stops.
+    pub stop: Arc<Notify>,
+    /// Set while scheduled rotation is deferred to save battery.
+    pub rotation_deferred: watch::Receiver<bool>,
+    /// The routes the route check expects, if it runs; see `routes::RouteCheck`.
+    pub expected_routes: Option<Arc<Mutex<Vec<Route>>>>,
+    /// For reconnect backoff jitter.
+    pub rng: Arc<dyn Rng>,
+    /// Notified by `cancel_handshake()`, which aborts the handshake for a new session.
+    pub handshake_cancel: Arc<Notify>,
+}
+
+/// Replaces the session every `max_session_duration_ms`, or right away when the data
+/// plane requests a reconnect, make-before-break: the new session is fully established
+/// before the data plane switches over to it, and only then is the old one closed.
+/// Each new session gets a token from `params.auth`, refreshed if the last one expires.
+/// A new session assigned other addresses than the TUN interface has can't be switched
+/// to; the data plane then stops with a retriable error.
+/// Owns the current session's control handle; returns once the data plane has gone away,
+/// or with an error when reconnecting was given up on.
+pub async fn rotate_sessions(
+    mut ctrl: Control,
+    params: SessionParams,
+    mut rotation: Rotation,
+) -> anyhow::Result<()> {
+    let mut session_start = Instant::now();
+    loop {
+        let reason = rotation_due(
+            session_start,
+            &mut rotation.options,
+            &mut rotation.rotation_deferred,
+            &rotation.reconnect,
+        )
+        .await;
+
+        log::info!("Replacing session: {reason}");
+        let cancelled = rotation.handshake_cancel.notified();
+        let replacement = async {
+            match reason {
+                Trigger::Expired => {
+                    let options = rotation.options.borrow().clone();
+                    let connection = params.connect(&options, &rotation.diagnostics);
+                    connect::with_timeout(&options, connection).await
+                }
+                Trigger::UplinkFailing => {
+                    rotation.state.set(VpnState::Reconnecting);
+                    reconnect(&params, &rotation).await
+                }
+            }
+        };
+        let res = tokio::select! {
+            res = replacement => res,
+            _ = cancelled => {
+                log::info!("Handshake cancelled");
+                Err(VpnError::Cancelled.into())
+            }
+        };
+        let ToyVpnClientConnection {
+            edge_read,
+            edge_write,
+            ctrl: new_ctrl,
+            quic,
+            ..
+        } = match res {
+            Ok(connection) => connection,
+            Err(e) if reason == Trigger::UplinkFailing => {
+                rotation
+                    .diagnostics
+                    .record("session", format!("Giving up reconnecting: {e:#}"));
+                return Err(e);
+            }
+            Err(e) => {
+                log::warn!("Replacing session failed, keeping current one: {e:#}");
+                rotation
+                    .diagnostics
+                    .record("session", format!("Replacing session failed: {e:#}"));
+                rotation
+                    .events
+                    .error(format!("Replacing session failed: {e:#}"));
+                if !wait_to_retry(&rotation.reconnect, &rotation.stop).await {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        // The TUN interface keeps the addresses it was set up with, so the new session
+        // is only usable with the same ones.
+        let sources = new_ctrl.assigned_addresses();
+        let current = ctrl.assigned_addresses();
+        if !same_addresses(&sources, &current) {
+            let e = anyhow!(
+                "Replacement session was assigned {sources:?} instead of {current:?}; the \
+                 VPN interface must be rebuilt with a new handshake"
+            );
+            rotation.diagnostics.record("session", e.to_string());
+            return Err(e);
+        }
+        let config = connect::client_config(
+            &new_ctrl,
+            &rotation.route_overrides.lock().unwrap(),
+            &rotation.tunnel_dns.lock().unwrap(),
+        );
+
+        if rotation.uplink.send((edge_write, sources)).await.is_err()
+            || rotation
+                .downlink
+                .send((edge_read, quic.clone()))
+                .await
+                .is_err()
+        {
+            return Ok(());
+        }
+        let age = session_start.elapsed();
+        // The data plane has switched over, so the old session can go.
+        drop(std::mem::replace(&mut ctrl, new_ctrl));
+        *rotation.current_quic.lock().unwrap() = Some(quic.clone());
+        rotation.quic.send_replace(quic);
+        session_start = Instant::now();
+        rotation.diagnostics.record(
+            "session",
+            format!("Replaced session after {}s: {reason}", age.as_secs()),
+        );
+        if reason == Trigger::UplinkFailing {
+            rotation.state.set(VpnState::Connected);
+        }
+
+        match config {
+            Ok(config) => {
+                if let Some(expected) = &rotation.expected_routes {
+                    *expected.lock().unwrap() = config.routes.clone();
+                }
+                rotation.callback.on_session_rotated(config)
+            }
+            Err(e) => log::warn!("Rotated session has no usable config: {e:#}"),
+        }
+    }
+}
+
+/// Establishes a session to replace one that stopped working, retrying with exponential
+/// backoff as configured in the transport options. Errors that trying again won't fix,
+/// e.g. a rejected token, end it right away.
+async fn reconnect(
+    params: &SessionParams,
+    rotation: &Rotation,
+) -> anyhow::Result<ToyVpnClientConnection> {
+    with_retries(
+        &rotation.options,
+        rotation.rng.as_ref(),
+        |options| async move {
+            connect::with_timeout(&options, params.connect(&options, &rotation.diagnostics)).await
+        },
+        |attempt, backoff, e| {
+            log::warn!("Reconnect attempt {attempt} failed, retrying in {backoff:?}: {e:#}");
+            rotation.diagnostics.record(
+                "session",
+                format!("Reconnect attempt {attempt} failed: {e:#}"),
+            );
+            rotation
+                .events
+                .error(format!("Reconnect attempt {attempt} failed: {e:#}"));
+        },
+    )
+    .await
+}
+
+/// Runs `attempt` with the current options until it succeeds, up to
+/// `reconnect_max_attempts` times, sleeping [`reconnect_backoff`] in between. Each
+/// failure that is retried is passed to `failed` with its attempt number and backoff.
+async fn with_retries<T, F>(
+    options: &watch::Receiver<TransportOptions>,
+    rng: &dyn Rng,
+    mut attempt: impl FnMut(TransportOptions) -> F,
+    mut failed: impl FnMut(u32, Duration, &anyhow::Error),
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let mut n = 1;
+    loop {
+        let options = options.borrow().clone();
+        let e = match attempt(options.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if !client::is_retriable(&e) {
+            return Err(e);
+        }
+        if options.reconnect_max_attempts != 0 && n >= options.reconnect_max_attempts {
+            return Err(e.context(format!("Reconnecting failed {n} times")));
+        }
+        let backoff = reconnect_backoff(&options, n, rng);
+        failed(n, backoff, &e);
+        tokio::time::sleep(backoff).await;
+        n += 1;
+    }
+}
+
+/// Whether `a` and `b` hold the same addresses, in whatever order.
+fn same_addresses(a: &[IpAddr], b: &[IpAddr]) -> bool {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+/// The delay after the `attempt`th failed reconnect attempt: exponential from the
+/// initial backoff up to the maximum, of which a random part of up to half is taken
+/// off, so clients that lost their sessions together don't all come back at once.
+fn reconnect_backoff(options: &TransportOptions, attempt: u32, rng: &dyn Rng) -> Duration {
+    let initial = u64::from(options.reconnect_initial_backoff_ms.max(1));
+    let max = u64::from(options.reconnect_max_backoff_ms).max(initial);
+    let ms = initial.saturating_mul(1 << (attempt - 1).min(32)).min(max);
+    Duration::from_millis(ms - rng.up_to(ms / 2))
+}
+
+/// Waits out the delay before retrying a failed rotation of a working session, unless
+/// that session fails meanwhile: then the reconnect request is left for `rotation_due`
+/// to act on right away. Returns false if the data plane stops.
+async fn wait_to_retry(reconnect: &Notify, stop: &Notify) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(ROTATION_RETRY) => true,
+        _ = reconnect.notified() => {
+            reconnect.notify_one();
+            true
+        }
+        _ = stop.notified() => false,
+    }
+}
+
+/// Why a session is replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    Expired,
+    UplinkFailing,
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Expired => write!(f, "maximum duration reached"),
+            Self::UplinkFailing => write!(f, "uplink failing"),
+        }
+    }
+}
+
+/// Waits until the session started at `session_start` is due for replacement, following
+/// changes of the configured maximum duration. A session expiring while rotation is
+/// `deferred` is replaced as soon as that ends. Returns the reason.
+async fn rotation_due(
+    session_start: Instant,
+    options: &mut watch::Receiver<TransportOptions>,
+    deferred: &mut watch::Receiver<bool>,
+    reconnect: &Notify,
+) -> Trigger {
+    loop {
+        let max_ms = options.borrow_and_update().max_session_duration_ms;
+        let due = (max_ms > 0).then(|| session_start + Duration::from_millis(max_ms));
+        tokio::select! {
+            biased;
+            _ = reconnect.notified() => return Trigger::UplinkFailing,
+            _ = tokio::time::sleep_until(due.unwrap_or(session_start)), if due.is_some() => {
+                break;
+            }
+            Ok(()) = options.changed() => {}
+        }
+    }
+    if *deferred.borrow_and_update() {
+        log::info!("Session rotation due, deferred to save battery");
+        tokio::select! {
+            _ = reconnect.notified() => return Trigger::UplinkFailing,
+            _ = deferred.wait_for(|deferred| !deferred) => {}
+        }
+    }
+    Trigger::Expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SeededRng;
+
+    const MAX_SESSION: Duration = Duration::from_secs(60);
+
+    fn options() -> watch::Sender<TransportOptions> {
+        watch::channel(TransportOptions {
+            max_session_duration_ms: MAX_SESSION.as_millis() as u64,
+            ..TransportOptions::default()
+        })
+        .0
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deferred_rotation_happens_once_deferral_ends() {
+        let options = options();
+        let deferred = watch::channel(true).0;
+        let reconnect = Arc::new(Notify::new());
+        let start = Instant::now();
+        let due = tokio::spawn({
+            let mut options = options.subscribe();
+            let mut deferred = deferred.subscribe();
+            let reconnect = reconnect.clone();
+            async move { rotation_due(start, &mut options, &mut deferred, &reconnect).await }
+        });
+
+        tokio::time::sleep(MAX_SESSION * 2).await;
+        assert!(!due.is_finished());
+
+        deferred.send_replace(false);
+        assert_eq!(due.await.unwrap(), Trigger::Expired);
+        assert_eq!(start.elapsed(), MAX_SESSION * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_is_not_deferred() {
+        let options = options();
+        let deferred = watch::channel(true).0;
+        let reconnect = Notify::new();
+        reconnect.notify_one();
+        let trigger = rotation_due(
+            Instant::now(),
+            &mut options.subscribe(),
+            &mut deferred.subscribe(),
+            &reconnect,
+        )
+        .await;
+        assert_eq!(trigger, Trigger::UplinkFailing);
+    }
+
+    #[test]
+    fn backoff_stays_within_bounds() {
+        let variants = [
+            TransportOptions::default(),
+            TransportOptions {
+                reconnect_initial_backoff_ms: 0,
+                reconnect_max_backoff_ms: 0,
+                ..TransportOptions::default()
+            },
+            TransportOptions {
+                reconnect_initial_backoff_ms: 5_000,
+                reconnect_max_backoff_ms: 1_000,
+                ..TransportOptions::default()
+            },
+            TransportOptions {
+                reconnect_initial_backoff_ms: u32::MAX,
+                reconnect_max_backoff_ms: u32::MAX,
+                ..TransportOptions::default()
+            },
+        ];
+        for options in variants {
+            let initial = u64::from(options.reconnect_initial_backoff_ms.max(1));
+            let max = u64::from(options.reconnect_max_backoff_ms).max(initial);
+            for seed in 0..100 {
+                let rng = SeededRng::new(seed);
+                for attempt in 1..=64 {
+                    let full = initial.saturating_mul(1 << (attempt - 1).min(32)).min(max);
+                    let ms = reconnect_backoff(&options, attempt, &rng).as_millis() as u64;
+                    assert!(
+                        (full - full / 2..=full).contains(&ms),
+                        "attempt {attempt}: {ms}ms outside {}..={full}",
+                        full - full / 2
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let rng = SeededRng::new(1);
+        let options = TransportOptions::default();
+        let delays: std::collections::HashSet<_> = (0..10)
+            .map(|_| reconnect_backoff(&options, 3, &rng))
+            .collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_on_the_backoff_schedule() {
+        let options = watch::channel(TransportOptions {
+            reconnect_initial_backoff_ms: 1_000,
+            reconnect_max_backoff_ms: 4_000,
+            reconnect_max_attempts: 5,
+            ..TransportOptions::default()
+        })
+        .0;
+        let mut attempts = Vec::new();
+        let mut reported = Vec::new();
+        let res: anyhow::Result<()> = with_retries(
+            &options.subscribe(),
+            &SeededRng::new(7),
+            |_| {
+                attempts.push(Instant::now());
+                async { Err(anyhow!("unreachable")) }
+            },
+            |n, backoff, _| reported.push((n, backoff)),
+        )
+        .await;
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .contains("Reconnecting failed 5 times"));
+
+        // The same seed gives the same jitter.
+        let rng = SeededRng::new(7);
+        let expected: Vec<_> = (1..5)
+            .map(|n| (n, reconnect_backoff(&options.borrow(), n, &rng)))
+            .collect();
+        assert_eq!(reported, expected);
+        let gaps: Vec<_> = attempts.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(gaps, expected.iter().map(|(_, b)| *b).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_success() {
+        let options = watch::channel(TransportOptions::default()).0;
+        let mut failures = 2;
+        let res = with_retries(
+            &options.subscribe(),
+            &SeededRng::new(1),
+            |_| {
+                let res = if failures > 0 {
+                    failures -= 1;
+                    Err(anyhow!("unreachable"))
+                } else {
+                    Ok("connected")
+                };
+                async move { res }
+            },
+            |_, _, _| {},
+        )
+        .await;
+        assert_eq!(res.unwrap(), "connected");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_errors_retrying_wont_fix() {
+        let options = watch::channel(TransportOptions::default()).0;
+        let start = Instant::now();
+        let mut attempts = 0;
+        let res: anyhow::Result<()> = with_retries(
+            &options.subscribe(),
+            &SeededRng::new(1),
+            |_| {
+                attempts += 1;
+                async { Err(VpnError::AuthRejected("revoked".into()).into()) }
+            },
+            |_, _, _| {},
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_expires_after_max_duration() {
+        let options = options();
+        let deferred = watch::channel(false).0;
+        let start = Instant::now();
+        let trigger = rotation_due(
+            start,
+            &mut options.subscribe(),
+            &mut deferred.subscribe(),
+            &Notify::new(),
+        )
+        .await;
+        assert_eq!(trigger, Trigger::Expired);
+        assert_eq!(start.elapsed(), MAX_SESSION);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiry_follows_changed_duration() {
+        let options = options();
+        let deferred = watch::channel(false).0;
+        let reconnect = Notify::new();
+        let mut receiver = options.subscribe();
+        let mut deferring = deferred.subscribe();
+        let start = Instant::now();
+        let due = rotation_due(start, &mut receiver, &mut deferring, &reconnect);
+        let extend = async {
+            tokio::time::sleep(MAX_SESSION / 2).await;
+            options.send_modify(|o| o.max_session_duration_ms *= 2);
+        };
+        let (trigger, ()) = tokio::join!(due, extend);
+        assert_eq!(trigger, Trigger::Expired);
+        assert_eq!(start.elapsed(), MAX_SESSION * 2);
+
+        // Without a maximum, sessions don't expire.
+        options.send_modify(|o| o.max_session_duration_ms = 0);
+        let never = rotation_due(Instant::now(), &mut receiver, &mut deferring, &reconnect);
+        assert!(tokio::time::timeout(MAX_SESSION * 10, never).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_rotation_is_retried_after_a_delay() {
+        let start = Instant::now();
+        assert!(wait_to_retry(&Notify::new(), &Notify::new()).await);
+        assert_eq!(start.elapsed(), ROTATION_RETRY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_session_is_replaced_without_waiting_to_retry() {
+        let options = options();
+        let deferred = watch::channel(false).0;
+        let (reconnect, stop) = (Notify::new(), Notify::new());
+        // The session expired and replacing it failed.
+        let start = Instant::now() - MAX_SESSION;
+        let failing = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            reconnect.notify_one();
+        };
+        let (retry, ()) = tokio::join!(wait_to_retry(&reconnect, &stop), failing);
+        assert!(retry);
+        assert_eq!(start.elapsed(), MAX_SESSION + Duration::from_secs(1));
+
+        let trigger = rotation_due(
+            start,
+            &mut options.subscribe(),
+            &mut deferred.subscribe(),
+            &reconnect,
+        )
+        .await;
+        assert_eq!(trigger, Trigger::UplinkFailing);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_ends_the_wait_to_retry() {
+        let (reconnect, stop) = (Notify::new(), Notify::new());
+        let start = Instant::now();
+        let stopping = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            stop.notify_one();
+        };
+        let (retry, ()) = tokio::join!(wait_to_retry(&reconnect, &stop), stopping);
+        assert!(!retry);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
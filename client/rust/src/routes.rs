@@ -0,0 +1,286 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+
+use crate::{Route, RouteOverride, RouteVerifier, VpnCallback};
+
+/// Lower bound for the route check interval, as each check calls into the embedder.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Applies embedder-provided overrides to the advertised routes.
+///
+/// An override matches a route with the same destination and prefix length. With
+/// `exclude` set, the matching route is removed; otherwise the route is added unless
+/// it is already there.
+pub fn apply_overrides(mut routes: Vec<Route>, overrides: &[RouteOverride]) -> Vec<Route> {
+    for o in overrides {
+        let pos = routes
+            .iter()
+            .position(|r| r.destination == o.destination && r.prefix_length == o.prefix_length);
+        match (pos, o.exclude) {
+            (Some(i), true) => {
+                routes.remove(i);
+            }
+            (None, false) => routes.push(Route {
+                destination: o.destination.clone(),
+                prefix_length: o.prefix_length,
+            }),
+            (Some(_), false) | (None, true) => {}
+        }
+    }
+    routes
+}
+
+/// The prefix length to configure `address` with on the interface: that of the
+/// narrowest advertised route containing it, or a host prefix if there is none. The
+/// default route says nothing about the client's subnet and is not considered.
+pub fn assigned_prefix_length(address: IpAddr, advertised: &[(IpAddr, u8)]) -> i32 {
+    let host = if address.is_ipv4() { 32 } else { 128 };
+    advertised
+        .iter()
+        .filter(|(network, len)| *len > 0 && prefix_contains(*network, *len, address))
+        .map(|(_, len)| i32::from(*len))
+        .max()
+        .unwrap_or(host)
+}
+
+/// Whether `address` is within `network`/`prefix_len`.
+fn prefix_contains(network: IpAddr, prefix_len: u8, address: IpAddr) -> bool {
+    let (network, address, bits) = match (network, address) {
+        (IpAddr::V4(n), IpAddr::V4(a)) => (u32::from(n).into(), u32::from(a).into(), 32),
+        (IpAddr::V6(n), IpAddr::V6(a)) => (u128::from(n), u128::from(a), 128),
+        _ => return false,
+    };
+    let prefix_len = u32::from(prefix_len).min(bits);
+    let mask = match u128::MAX.checked_shl(bits - prefix_len) {
+        Some(mask) if prefix_len > 0 => mask,
+        _ => 0,
+    };
+    (network ^ address) & mask == 0
+}
+
+/// Periodic comparison of the routes handed to the OS with the installed ones.
+pub struct RouteCheck {
+    pub verifier: Arc<dyn RouteVerifier>,
+    pub interval: Duration,
+    /// The current session's routes: those the TUN interface was set up with, then
+    /// those of each rotated session, which the app installs on `on_session_rotated`.
+    pub expected: Arc<Mutex<Vec<Route>>>,
+}
+
+/// Asks the embedder for the installed routes every `check.interval` and reports
+/// expected routes that went missing through `on_routing_conflict`, once per change.
+pub async fn verify_installed(check: RouteCheck, callback: Arc<dyn VpnCallback>) {
+    let mut interval = tokio::time::interval(check.interval.max(MIN_CHECK_INTERVAL));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reported = Vec::new();
+    loop {
+        interval.tick().await;
+        let verifier = check.verifier.clone();
+        let installed = match tokio::task::spawn_blocking(move || verifier.installed_routes()).await
+        {
+            Ok(installed) => installed,
+            Err(e) => {
+                log::warn!("Route verifier failed, no longer checking routes: {e}");
+                return;
+            }
+        };
+        let installed: Vec<_> = installed.iter().filter_map(route_key).collect();
+        let missing: Vec<Route> = check
+            .expected
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| route_key(r).is_some_and(|k| !installed.contains(&k)))
+            .cloned()
+            .collect();
+
+        let keys: Vec<_> = missing.iter().filter_map(route_key).collect();
+        if keys == reported {
+            continue;
+        }
+        reported = keys;
+        if missing.is_empty() {
+            log::info!("All routes are installed again");
+            continue;
+        }
+        log::warn!("Routes no longer installed: {missing:?}");
+        callback.on_routing_conflict(missing);
+    }
+}
+
+/// Destination and prefix length, parsed so that different notations of a prefix match.
+fn route_key(route: &Route) -> Option<(IpAddr, i32)> {
+    let destination = route.destination.parse().ok()?;
+    Some((destination, route.prefix_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StopInfo, VpnClientConfig, VpnState};
+
+    fn route(destination: &str, prefix_length: i32) -> Route {
+        Route {
+            destination: destination.into(),
+            prefix_length,
+        }
+    }
+
+    fn route_override(destination: &str, prefix_length: i32, exclude: bool) -> RouteOverride {
+        RouteOverride {
+            destination: destination.into(),
+            prefix_length,
+            exclude,
+        }
+    }
+
+    fn keys(routes: &[Route]) -> Vec<(String, i32)> {
+        routes
+            .iter()
+            .map(|r| (r.destination.clone(), r.prefix_length))
+            .collect()
+    }
+
+    #[test]
+    fn excludes_matching_route_only() {
+        let routes = vec![route("10.0.0.0", 8), route("10.0.0.0", 16)];
+        let routes = apply_overrides(routes, &[route_override("10.0.0.0", 16, true)]);
+        assert_eq!(keys(&routes), [("10.0.0.0".to_string(), 8)]);
+    }
+
+    #[test]
+    fn excluding_unknown_route_is_a_no_op() {
+        let routes = apply_overrides(
+            vec![route("0.0.0.0", 0)],
+            &[route_override("192.168.0.0", 16, true)],
+        );
+        assert_eq!(keys(&routes), [("0.0.0.0".to_string(), 0)]);
+    }
+
+    #[test]
+    fn adds_unmatched_route() {
+        let routes = apply_overrides(
+            vec![route("0.0.0.0", 0)],
+            &[route_override("fd00::", 8, false)],
+        );
+        assert_eq!(
+            keys(&routes),
+            [("0.0.0.0".to_string(), 0), ("fd00::".to_string(), 8)]
+        );
+    }
+
+    #[test]
+    fn does_not_duplicate_advertised_route() {
+        let routes = apply_overrides(
+            vec![route("10.0.0.0", 8)],
+            &[route_override("10.0.0.0", 8, false)],
+        );
+        assert_eq!(keys(&routes), [("10.0.0.0".to_string(), 8)]);
+    }
+
+    #[test]
+    fn later_overrides_see_earlier_ones() {
+        let routes = apply_overrides(
+            Vec::new(),
+            &[
+                route_override("10.1.0.0", 16, false),
+                route_override("10.1.0.0", 16, true),
+            ],
+        );
+        assert!(routes.is_empty());
+    }
+
+    fn advertised(routes: &[(&str, u8)]) -> Vec<(IpAddr, u8)> {
+        routes
+            .iter()
+            .map(|(n, len)| (n.parse().unwrap(), *len))
+            .collect()
+    }
+
+    #[test]
+    fn prefix_length_of_narrowest_containing_route() {
+        let routes = advertised(&[
+            ("0.0.0.0", 0),
+            ("10.0.0.0", 8),
+            ("10.8.0.0", 24),
+            ("10.9.0.0", 24),
+            ("fd00::", 8),
+            ("fd00:1::", 64),
+        ]);
+        let len = |a: &str| assigned_prefix_length(a.parse().unwrap(), &routes);
+        assert_eq!(len("10.8.0.2"), 24);
+        assert_eq!(len("10.7.0.2"), 8);
+        assert_eq!(len("fd00:1::2"), 64);
+        assert_eq!(len("fd00:2::2"), 8);
+    }
+
+    #[test]
+    fn host_prefix_length_without_containing_route() {
+        let routes = advertised(&[("0.0.0.0", 0), ("::", 0), ("192.168.0.0", 16)]);
+        let len = |a: &str| assigned_prefix_length(a.parse().unwrap(), &routes);
+        assert_eq!(len("10.8.0.2"), 32);
+        assert_eq!(len("fd00::2"), 128);
+    }
+
+    #[test]
+    fn prefix_containment() {
+        let contains =
+            |n: &str, len, a: &str| prefix_contains(n.parse().unwrap(), len, a.parse().unwrap());
+        assert!(contains("10.8.0.0", 24, "10.8.0.255"));
+        assert!(!contains("10.8.0.0", 24, "10.8.1.0"));
+        assert!(contains("10.8.0.1", 32, "10.8.0.1"));
+        assert!(contains("fd00::", 127, "fd00::1"));
+        assert!(!contains("fd00::", 128, "fd00::1"));
+        assert!(!contains("::", 96, "10.8.0.1"));
+    }
+
+    struct Installed(Vec<Route>);
+
+    impl RouteVerifier for Installed {
+        fn installed_routes(&self) -> Vec<Route> {
+            self.0.clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct Conflicts(Mutex<Vec<Vec<(String, i32)>>>);
+
+    impl VpnCallback for Conflicts {
+        fn on_stats_update(&self, _: u64, _: u64) {}
+        fn on_state_change(&self, _: VpnState) {}
+        fn on_stop(&self, _: StopInfo) {}
+        fn on_session_rotated(&self, _: VpnClientConfig) {}
+        fn on_mtu_changed(&self, _: u32) {}
+        fn on_dns_leak_blocked(&self, _: String) {}
+        fn on_routing_conflict(&self, missing_routes: Vec<Route>) {
+            self.0.lock().unwrap().push(keys(&missing_routes));
+        }
+        fn on_split_tunnel_routes_changed(&self, _: Vec<Route>) {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn checks_against_the_current_routes() {
+        let expected = Arc::new(Mutex::new(vec![route("10.0.0.0", 8)]));
+        let check = RouteCheck {
+            verifier: Arc::new(Installed(vec![route("10.0.0.0", 8), route("fd00::", 8)])),
+            interval: MIN_CHECK_INTERVAL,
+            expected: expected.clone(),
+        };
+        let conflicts = Arc::new(Conflicts::default());
+        let task = tokio::spawn(verify_installed(check, conflicts.clone()));
+        tokio::time::sleep(MIN_CHECK_INTERVAL * 2).await;
+        assert!(conflicts.0.lock().unwrap().is_empty());
+
+        // A rotated session brings other routes.
+        *expected.lock().unwrap() = vec![route("fd00::", 8), route("192.168.0.0", 16)];
+        tokio::time::sleep(MIN_CHECK_INTERVAL * 2).await;
+        assert_eq!(
+            *conflicts.0.lock().unwrap(),
+            vec![vec![("192.168.0.0".to_string(), 16)]]
+        );
+        task.abort();
+    }
+}
@@ -0,0 +1,36 @@
+use crate::ConnectionInfo;
+
+/// Describes the security parameters of the QUIC connection `quic`.
+pub fn describe(quic: &quinn::Connection, key_updates_requested: u64) -> ConnectionInfo {
+    let handshake = quic
+        .handshake_data()
+        .and_then(|d| d.downcast::<quinn::crypto::rustls::HandshakeData>().ok());
+    let certificate_sha256 = quic
+        .peer_identity()
+        .and_then(|id| id.downcast::<Vec<rustls::pki_types::CertificateDer>>().ok())
+        .and_then(|chain| {
+            let cert = chain.first()?;
+            let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+            Some(digest.as_ref().iter().map(|b| format!("{b:02x}")).collect())
+        });
+    // quinn doesn't report which suite the server picked, only what we offer is known.
+    let cipher_suites = rustls::crypto::ring::default_provider()
+        .cipher_suites
+        .iter()
+        .filter(|s| s.tls13().is_some())
+        .map(|s| format!("{:?}", s.suite()))
+        .collect();
+
+    ConnectionInfo {
+        tls_version: "TLSv1.3".into(),
+        cipher_suites,
+        alpn: handshake
+            .as_ref()
+            .and_then(|h| h.protocol.as_deref())
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        server_name: handshake.and_then(|h| h.server_name),
+        certificate_sha256,
+        key_updates_requested,
+        rtt_ms: quic.rtt().as_millis() as u32,
+    }
+}
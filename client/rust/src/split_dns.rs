@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::alloc_audit;
+use crate::packet::udp_payload_from_port;
+use crate::{Route, VpnCallback};
+
+/// Lower bound for learned route lifetimes, so very short DNS TTLs don't make routes flap.
+const MIN_ROUTE_TTL: Duration = Duration::from_secs(60);
+
+const DNS_PORT: u16 = 53;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+
+/// Host routes learned from DNS answers for a configured set of domains.
+///
+/// The downlink path feeds every packet through [`DomainRoutes::inspect_downlink`];
+/// answers to queries for a configured domain (or any subdomain of it) produce
+/// /32 resp. /128 routes that expire with the record TTL.
+#[derive(Default)]
+pub struct DomainRoutes {
+    inner: Mutex<Inner>,
+    /// Notified when a route is learned or the domain list changes.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct Inner {
+    domains: Vec<String>,
+    routes: HashMap<IpAddr, Instant>,
+}
+
+impl DomainRoutes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the configured domain list. Routes learned for the previous list are dropped.
+    pub fn set_domains(&self, domains: Vec<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.domains = domains.iter().map(|d| normalize(d)).collect();
+        inner.routes.clear();
+        log::info!("Split tunnel domains: {:?}", inner.domains);
+        self.changed.notify_one();
+    }
+
+    pub fn domains(&self) -> Vec<String> {
+        self.inner.lock().unwrap().domains.clone()
+    }
+
+    /// Returns the currently valid host routes, ordered by address, pruning expired ones.
+    pub fn routes(&self) -> Vec<Route> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.routes.retain(|_, expiry| *expiry > now);
+        let mut addresses: Vec<_> = inner.routes.keys().collect();
+        addresses.sort();
+        addresses
+            .into_iter()
+            .map(|ip| Route {
+                destination: ip.to_string(),
+                prefix_length: if ip.is_ipv4() { 32 } else { 128 },
+            })
+            .collect()
+    }
+
+    /// Calls `on_split_tunnel_routes_changed` whenever a route is learned or expires,
+    /// starting with the routes already known. Runs until aborted.
+    pub async fn report_changes(self: Arc<Self>, callback: Arc<dyn VpnCallback>) {
+        let mut reported = Vec::new();
+        loop {
+            let changed = self.changed.notified();
+            let routes = self.routes();
+            let keys: Vec<_> = routes
+                .iter()
+                .map(|r| (r.destination.clone(), r.prefix_length))
+                .collect();
+            if keys != reported {
+                log::info!("Split tunnel routes changed: {} routes", routes.len());
+                reported = keys;
+                callback.on_split_tunnel_routes_changed(routes);
+            }
+            let next_expiry = self.inner.lock().unwrap().routes.values().min().copied();
+            tokio::select! {
+                _ = changed => {}
+                _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(Instant::now)),
+                    if next_expiry.is_some() => {}
+            }
+        }
+    }
+
+    /// Inspects a packet written towards the TUN and learns routes from DNS answers.
+    pub fn inspect_downlink(&self, packet: &[u8]) {
+        let Some(dns) = udp_payload_from_port(packet, DNS_PORT) else {
+            return;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.domains.is_empty() {
+            return;
+        }
+
+        // Only DNS answers while split tunneling by domain; learning a route allocates.
+        alloc_audit::exempt(|| {
+            let Some(answer) = parse_answer(dns) else {
+                return;
+            };
+            if !inner
+                .domains
+                .iter()
+                .any(|d| domain_matches(&answer.qname, d))
+            {
+                return;
+            }
+
+            let now = Instant::now();
+            for (ip, ttl) in answer.addresses {
+                let expiry = now + Duration::from_secs(ttl.into()).max(MIN_ROUTE_TTL);
+                if inner.routes.insert(ip, expiry).is_none() {
+                    log::debug!("Learned split tunnel route {ip} for {}", answer.qname);
+                    self.changed.notify_one();
+                }
+            }
+        })
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn domain_matches(name: &str, domain: &str) -> bool {
+    name == domain
+        || (name.len() > domain.len()
+            && name.ends_with(domain)
+            && name.as_bytes()[name.len() - domain.len() - 1] == b'.')
+}
+
+struct DnsAnswer {
+    qname: String,
+    addresses: Vec<(IpAddr, u32)>,
+}
+
+fn parse_answer(msg: &[u8]) -> Option<DnsAnswer> {
+    if msg.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    // Only successful responses (QR set, RCODE 0).
+    if flags & 0x8000 == 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]);
+    if qdcount != 1 {
+        return None;
+    }
+
+    let (qname, mut pos) = read_name(msg, 12)?;
+    pos += 4; // QTYPE + QCLASS
+
+    let mut addresses = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(msg, pos)?;
+        let rr = msg.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([rr[0], rr[1]]);
+        let class = u16::from_be_bytes([rr[2], rr[3]]);
+        let ttl = u32::from_be_bytes([rr[4], rr[5], rr[6], rr[7]]);
+        let rdlen = usize::from(u16::from_be_bytes([rr[8], rr[9]]));
+        pos += 10;
+        let rdata = msg.get(pos..pos + rdlen)?;
+        pos += rdlen;
+
+        if class != DNS_CLASS_IN {
+            continue;
+        }
+        match (rtype, rdlen) {
+            (DNS_TYPE_A, 4) => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                addresses.push((Ipv4Addr::from(octets).into(), ttl));
+            }
+            (DNS_TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                addresses.push((Ipv6Addr::from(octets).into(), ttl));
+            }
+            _ => {}
+        }
+    }
+
+    Some(DnsAnswer { qname, addresses })
+}
+
+/// Reads an uncompressed name, as found in the question section.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    loop {
+        let len = usize::from(*msg.get(pos)?);
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = msg.get(pos..pos + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    Some((name, pos))
+}
+
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1;
+        if len == 0 {
+            return Some(pos);
+        }
+        pos += usize::from(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::fixtures;
+    use crate::{StopInfo, VpnClientConfig, VpnState};
+
+    /// Builds a DNS response for `qname` with one answer record per `(type, rdata, ttl)`.
+    fn response(flags: u16, qname: &str, answers: &[(u16, &[u8], u32)]) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34];
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        for label in qname.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+        msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        for (rtype, rdata, ttl) in answers {
+            // Compressed name pointing at the question.
+            msg.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&rtype.to_be_bytes());
+            msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&ttl.to_be_bytes());
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(rdata);
+        }
+        msg
+    }
+
+    /// Wraps a DNS message in an IPv4/UDP packet from port 53.
+    fn from_resolver(dns: &[u8]) -> Vec<u8> {
+        let resolver = SocketAddr::new([10, 0, 0, 53].into(), DNS_PORT);
+        fixtures::udp(resolver, "10.0.0.2:50000".parse().unwrap(), dns)
+    }
+
+    #[test]
+    fn parses_a_and_aaaa_answers() {
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let msg = response(
+            0x8180,
+            "WWW.Example.com",
+            &[
+                (5, b"\x03foo\x00", 300),
+                (DNS_TYPE_A, &[192, 0, 2, 1], 30),
+                (DNS_TYPE_AAAA, &v6.octets(), 600),
+            ],
+        );
+        let answer = parse_answer(&msg).unwrap();
+        assert_eq!(answer.qname, "www.example.com");
+        assert_eq!(
+            answer.addresses,
+            vec![(IpAddr::from([192, 0, 2, 1]), 30), (IpAddr::from(v6), 600)]
+        );
+    }
+
+    #[test]
+    fn ignores_queries_errors_and_truncated_messages() {
+        let answers: &[(u16, &[u8], u32)] = &[(DNS_TYPE_A, &[192, 0, 2, 1], 30)];
+        // A query, not a response.
+        assert!(parse_answer(&response(0x0100, "example.com", answers)).is_none());
+        // NXDOMAIN.
+        assert!(parse_answer(&response(0x8183, "example.com", answers)).is_none());
+        let msg = response(0x8180, "example.com", answers);
+        assert!(parse_answer(&msg[..msg.len() - 1]).is_none());
+        assert!(parse_answer(&msg[..11]).is_none());
+    }
+
+    #[test]
+    fn matches_domain_and_subdomains_only() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(domain_matches("a.b.example.com", "example.com"));
+        assert!(!domain_matches("badexample.com", "example.com"));
+        assert!(!domain_matches("com", "example.com"));
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Vec<Route>>>);
+
+    impl VpnCallback for Recorder {
+        fn on_stats_update(&self, _: u64, _: u64) {}
+        fn on_state_change(&self, _: VpnState) {}
+        fn on_stop(&self, _: StopInfo) {}
+        fn on_session_rotated(&self, _: VpnClientConfig) {}
+        fn on_mtu_changed(&self, _: u32) {}
+        fn on_dns_leak_blocked(&self, _: String) {}
+        fn on_routing_conflict(&self, _: Vec<Route>) {}
+        fn on_split_tunnel_routes_changed(&self, routes: Vec<Route>) {
+            self.0.lock().unwrap().push(routes);
+        }
+    }
+
+    impl Recorder {
+        fn destinations(&self) -> Vec<Vec<String>> {
+            let reports = self.0.lock().unwrap();
+            reports
+                .iter()
+                .map(|routes| routes.iter().map(|r| r.destination.clone()).collect())
+                .collect()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_learned_and_expired_routes() {
+        let routes = Arc::new(DomainRoutes::new());
+        routes.set_domains(vec!["Example.com.".into()]);
+        let recorder = Arc::new(Recorder::default());
+        let task = tokio::spawn(routes.clone().report_changes(recorder.clone()));
+
+        let unrelated = response(0x8180, "example.org", &[(DNS_TYPE_A, &[192, 0, 2, 9], 1)]);
+        routes.inspect_downlink(&from_resolver(&unrelated));
+        let short = response(0x8180, "example.com", &[(DNS_TYPE_A, &[192, 0, 2, 1], 1)]);
+        routes.inspect_downlink(&from_resolver(&short));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let long = response(
+            0x8180,
+            "cdn.example.com",
+            &[(DNS_TYPE_A, &[192, 0, 2, 2], 600)],
+        );
+        routes.inspect_downlink(&from_resolver(&long));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            recorder.destinations(),
+            vec![vec!["192.0.2.1"], vec!["192.0.2.1", "192.0.2.2"]]
+        );
+
+        // Short TTLs are raised to the minimum lifetime.
+        tokio::time::sleep(MIN_ROUTE_TTL).await;
+        assert_eq!(recorder.destinations().last().unwrap(), &["192.0.2.2"]);
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(recorder.destinations().last().unwrap(), &[] as &[&str]);
+        assert_eq!(recorder.destinations().len(), 4);
+        task.abort();
+    }
+}
@@ -0,0 +1,383 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::{watch, Notify};
+
+use crate::VpnState::{self, *};
+use crate::{StateListener, VpnCallback};
+
+/// The client's connection state, see [`VpnState`].
+///
+/// Changes are reported through `on_state_change` of the current observer: the event
+/// queue until `start()` is called, from then on all of that run's callbacks. They also
+/// go to the listener, if one is set, which unlike a run's callback already hears of
+/// `Handshaking`. A transition that isn't valid from the current state is ignored, and
+/// so is anything a data plane reports after a newer one was started, so a winding-down
+/// run can't move the client backwards. A new handshake has to wait until the previous
+/// run has `Stopped`.
+pub struct ConnectionState {
+    inner: Mutex<Inner>,
+    /// The state again, for waiting on changes.
+    changes: watch::Sender<VpnState>,
+}
+
+struct Inner {
+    state: VpnState,
+    observer: Arc<dyn VpnCallback>,
+    /// See `ToyVpnClient::set_state_listener`.
+    listener: Option<Arc<dyn StateListener>>,
+    /// Whether a handshake is under way, as opposed to one having succeeded and
+    /// waiting for `start()`; both are `Handshaking`.
+    handshake_in_progress: bool,
+    /// Increased by every `start()`, see [`RunState`].
+    run: u64,
+    /// The current run's stop signal. Each run gets its own, so a stop meant for one
+    /// run can't end the next.
+    stop_signal: Arc<Notify>,
+}
+
+/// The state as seen by one data plane run.
+#[derive(Clone)]
+pub struct RunState {
+    state: Arc<ConnectionState>,
+    run: u64,
+    stop_signal: Arc<Notify>,
+}
+
+impl ConnectionState {
+    pub fn new(observer: Arc<dyn VpnCallback>) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                state: Idle,
+                observer,
+                listener: None,
+                handshake_in_progress: false,
+                run: 0,
+                stop_signal: Arc::new(Notify::new()),
+            }),
+            changes: watch::channel(Idle).0,
+        })
+    }
+
+    pub fn get(&self) -> VpnState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Waits until the client is no longer `Stopping`.
+    pub async fn stopped(&self) {
+        let mut changes = self.changes.subscribe();
+        // Can't fail, the sender is `self`.
+        let _ = changes.wait_for(|state| *state != Stopping).await;
+    }
+
+    /// Moves to `state` if that is a valid transition.
+    pub fn set(&self, state: VpnState) {
+        self.transition(None, state);
+    }
+
+    /// Moves to `Handshaking` unless a data plane is running or another handshake is
+    /// under way, checking and changing the state in one step so that only one of two
+    /// concurrent handshakes goes ahead. Fails with the current state otherwise.
+    pub fn try_begin_handshake(&self) -> Result<(), VpnState> {
+        let mut inner = self.inner.lock().unwrap();
+        let from = inner.state;
+        let idle_handshake = from == Handshaking && !inner.handshake_in_progress;
+        if !idle_handshake && !is_valid(from, Handshaking) {
+            return Err(from);
+        }
+        inner.handshake_in_progress = true;
+        if from == Handshaking {
+            return Ok(());
+        }
+        log::info!("State: {from:?} -> Handshaking");
+        inner.state = Handshaking;
+        self.changes.send_replace(Handshaking);
+        let observer = inner.observer.clone();
+        let listener = inner.listener.clone();
+        drop(inner);
+        notify(&observer, listener.as_deref(), Handshaking);
+        Ok(())
+    }
+
+    /// Ends the handshake begun by `try_begin_handshake()`. An `established` session
+    /// stays `Handshaking` until `start()`; otherwise the client goes back to `Idle`.
+    pub fn end_handshake(&self, established: bool) {
+        self.inner.lock().unwrap().handshake_in_progress = false;
+        if !established {
+            self.set(Idle);
+        }
+    }
+
+    /// Reports changes to `listener` from now on, in addition to the observer.
+    pub fn set_listener(&self, listener: Option<Arc<dyn StateListener>>) {
+        self.inner.lock().unwrap().listener = listener;
+    }
+
+    /// Starts a data plane run reporting to `observer`, moving from a finished handshake
+    /// to `Connected`; the previous run, if any, no longer reports. Fails with the current
+    /// state if there is no handshake to start from.
+    pub fn start_run(
+        self: &Arc<Self>,
+        observer: Arc<dyn VpnCallback>,
+    ) -> Result<RunState, VpnState> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != Handshaking || inner.handshake_in_progress {
+            return Err(inner.state);
+        }
+        inner.observer = observer.clone();
+        inner.run += 1;
+        let run = inner.run;
+        let stop_signal = Arc::new(Notify::new());
+        inner.stop_signal = stop_signal.clone();
+        inner.state = Connected;
+        self.changes.send_replace(Connected);
+        let listener = inner.listener.clone();
+        drop(inner);
+        log::info!("State: Handshaking -> Connected");
+        notify(&observer, listener.as_deref(), Connected);
+        Ok(RunState {
+            state: self.clone(),
+            run,
+            stop_signal,
+        })
+    }
+
+    /// Moves to `Stopping` and tells the current run's data plane to stop.
+    pub fn stop(&self) {
+        let inner = self.inner.lock().unwrap();
+        inner.stop_signal.notify_one();
+        self.apply(inner, Stopping);
+    }
+
+    fn transition(&self, run: Option<u64>, state: VpnState) {
+        let inner = self.inner.lock().unwrap();
+        if run.is_some_and(|run| run != inner.run) {
+            return;
+        }
+        self.apply(inner, state);
+    }
+
+    fn apply(&self, mut inner: MutexGuard<'_, Inner>, state: VpnState) {
+        let from = inner.state;
+        if from == state {
+            return;
+        }
+        if !is_valid(from, state) {
+            log::debug!("Ignoring state change {from:?} -> {state:?}");
+            return;
+        }
+        log::info!("State: {from:?} -> {state:?}");
+        inner.state = state;
+        self.changes.send_replace(state);
+        let observer = inner.observer.clone();
+        let listener = inner.listener.clone();
+        // Notify outside the lock, so the callbacks may query the state.
+        drop(inner);
+        notify(&observer, listener.as_deref(), state);
+    }
+}
+
+fn notify(observer: &Arc<dyn VpnCallback>, listener: Option<&dyn StateListener>, state: VpnState) {
+    observer.on_state_change(state);
+    let Some(listener) = listener else {
+        return;
+    };
+    // Like `GuardedCallback`, keep an exception thrown by the embedder out of the caller.
+    if catch_unwind(AssertUnwindSafe(|| listener.on_state_change(state))).is_err() {
+        log::error!("State listener panicked on {state:?}");
+    }
+}
+
+impl RunState {
+    /// Moves to `state`, unless a newer run has been started since.
+    pub fn set(&self, state: VpnState) {
+        self.state.transition(Some(self.run), state);
+    }
+
+    /// Notified by `ConnectionState::stop()` while this is the current run.
+    pub fn stop_signal(&self) -> &Arc<Notify> {
+        &self.stop_signal
+    }
+}
+
+fn is_valid(from: VpnState, to: VpnState) -> bool {
+    matches!(
+        (from, to),
+        (Idle | Stopped, Handshaking)
+            | (Handshaking, Idle)
+            | (Connected, Reconnecting)
+            | (Reconnecting, Connected)
+            | (Connected | Reconnecting, Stopping)
+            | (Connected | Reconnecting | Stopping, Stopped)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::events::EventQueue;
+    use crate::VpnEvent;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<VpnState>>);
+
+    impl StateListener for Recorder {
+        fn on_state_change(&self, state: VpnState) {
+            self.0.lock().unwrap().push(state);
+        }
+    }
+
+    fn states(events: &EventQueue) -> Vec<VpnState> {
+        std::iter::from_fn(|| events.poll(Duration::ZERO))
+            .filter_map(|event| match event {
+                VpnEvent::StateChanged { state } => Some(state),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_transitions() {
+        let all = [
+            Idle,
+            Handshaking,
+            Connected,
+            Reconnecting,
+            Stopping,
+            Stopped,
+        ];
+        let valid = [
+            (Idle, Handshaking),
+            (Stopped, Handshaking),
+            (Handshaking, Idle),
+            (Connected, Reconnecting),
+            (Reconnecting, Connected),
+            (Connected, Stopping),
+            (Reconnecting, Stopping),
+            (Connected, Stopped),
+            (Reconnecting, Stopped),
+            (Stopping, Stopped),
+        ];
+        for from in all {
+            for to in all {
+                assert_eq!(
+                    is_valid(from, to),
+                    valid.contains(&(from, to)),
+                    "{from:?} -> {to:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn runs_start_only_from_a_handshake() {
+        let events = Arc::new(EventQueue::default());
+        let state = ConnectionState::new(events.clone());
+        assert_eq!(state.start_run(events.clone()).err(), Some(Idle));
+
+        state.try_begin_handshake().unwrap();
+        state.end_handshake(true);
+        let run = state.start_run(events.clone()).ok().unwrap();
+        assert_eq!(state.get(), Connected);
+        assert_eq!(state.start_run(events.clone()).err(), Some(Connected));
+
+        run.set(Stopped);
+        assert_eq!(state.start_run(events.clone()).err(), Some(Stopped));
+        assert_eq!(states(&events), [Handshaking, Connected, Stopped]);
+    }
+
+    #[test]
+    fn one_handshake_at_a_time() {
+        let events = Arc::new(EventQueue::default());
+        let state = ConnectionState::new(events.clone());
+        state.try_begin_handshake().unwrap();
+        assert_eq!(state.try_begin_handshake(), Err(Handshaking));
+        // Not before the handshake is done.
+        assert_eq!(state.start_run(events.clone()).err(), Some(Handshaking));
+
+        // A finished handshake may be replaced by another one, or started.
+        state.end_handshake(true);
+        state.try_begin_handshake().unwrap();
+        state.end_handshake(true);
+        let run = state.start_run(events.clone()).ok().unwrap();
+        assert_eq!(state.try_begin_handshake(), Err(Connected));
+
+        run.set(Stopped);
+        state.try_begin_handshake().unwrap();
+        state.end_handshake(false);
+        assert_eq!(state.get(), Idle);
+        assert_eq!(
+            states(&events),
+            [Handshaking, Connected, Stopped, Handshaking, Idle]
+        );
+    }
+
+    #[test]
+    fn old_runs_no_longer_report() {
+        let events = Arc::new(EventQueue::default());
+        let state = ConnectionState::new(events.clone());
+        state.set(Handshaking);
+        let old = state.start_run(events.clone()).ok().unwrap();
+        old.set(Stopped);
+        state.set(Handshaking);
+        let _new = state.start_run(events.clone()).ok().unwrap();
+        old.set(Reconnecting);
+        old.set(Stopped);
+        assert_eq!(state.get(), Connected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_reach_only_their_run() {
+        let events = Arc::new(EventQueue::default());
+        let state = ConnectionState::new(events.clone());
+        state.set(Handshaking);
+        let old = state.start_run(events.clone()).ok().unwrap();
+        state.stop();
+        // Stopping twice leaves a second wakeup behind.
+        state.stop();
+        old.stop_signal().notified().await;
+
+        // No new handshake before the old run has stopped.
+        assert_eq!(state.try_begin_handshake(), Err(Stopping));
+        assert_eq!(state.start_run(events.clone()).err(), Some(Stopping));
+        old.set(Stopped);
+
+        state.try_begin_handshake().unwrap();
+        state.end_handshake(true);
+        let new = state.start_run(events.clone()).ok().unwrap();
+        let stray = tokio::time::timeout(Duration::from_secs(1), new.stop_signal().notified());
+        assert!(stray.await.is_err());
+        assert_eq!(state.get(), Connected);
+
+        state.stop();
+        new.stop_signal().notified().await;
+        assert_eq!(
+            states(&events),
+            [
+                Handshaking,
+                Connected,
+                Stopping,
+                Stopped,
+                Handshaking,
+                Connected,
+                Stopping
+            ]
+        );
+    }
+
+    #[test]
+    fn listener_hears_the_handshake() {
+        let events = Arc::new(EventQueue::default());
+        let state = ConnectionState::new(events.clone());
+        let listener = Arc::new(Recorder::default());
+        state.set_listener(Some(listener.clone()));
+        state.set(Handshaking);
+        state.start_run(events).ok().unwrap().set(Stopping);
+        assert_eq!(
+            *listener.0.lock().unwrap(),
+            [Handshaking, Connected, Stopping]
+        );
+    }
+}
@@ -0,0 +1,659 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+
+use crate::alloc_audit;
+use crate::{PacketFlow, TunReadStrategy};
+
+const BUFFER_SIZE: usize = 4096;
+
+/// Size of the storage packets are read into; see [`PacketSlab`].
+const SLAB_SIZE: usize = 64 * 1024;
+
+/// How long the blocking reader waits for the fd before re-checking for shutdown.
+const POLL_TIMEOUT_MS: i32 = 250;
+
+/// Depth of the channel between a reader thread and the runtime.
+const READER_QUEUE_DEPTH: usize = 256;
+
+/// Depth of the channel from the runtime to a packet flow's writer thread; beyond it,
+/// writes report `WouldBlock` like a full TUN fd.
+const WRITER_QUEUE_DEPTH: usize = 256;
+
+/// Most packets handed to `PacketFlow::write_packets` in one call.
+const MAX_WRITE_BATCH: usize = 64;
+
+/// Where the data plane gets its packets from.
+pub enum TunBackend {
+    /// A TUN file descriptor, as handed out by Android's `VpnService`. Ownership is taken.
+    Fd(RawFd),
+    /// Embedder-provided packet callbacks, e.g. wrapping iOS' `NEPacketTunnelFlow`.
+    Flow(Arc<dyn PacketFlow>),
+}
+
+impl std::fmt::Display for TunBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fd(fd) => write!(f, "tun_fd={fd}"),
+            Self::Flow(_) => write!(f, "packet flow"),
+        }
+    }
+}
+
+/// Whether `err` from a TUN read or write means the fd was taken away from us, e.g.
+/// because Android revoked the VPN in favour of another app.
+pub fn is_revoked(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EBADF) | Some(libc::EIO) | Some(libc::ENODEV)
+    )
+}
+
+/// A TUN file descriptor owned by the data plane.
+///
+/// Once an operation failed with `EBADF`, the fd is no longer ours: by the time it is
+/// dropped, the number may have been reused for an unrelated file, so it is not closed.
+pub struct TunFile {
+    file: ManuallyDrop<File>,
+    invalid: AtomicBool,
+}
+
+impl TunFile {
+    fn new(file: File) -> Self {
+        Self {
+            file: ManuallyDrop::new(file),
+            invalid: AtomicBool::new(false),
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check((&*self.file).read(buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.check((&*self.file).write(buf))
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        self.file.try_clone().map(Self::new)
+    }
+
+    fn check<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &res {
+            if e.raw_os_error() == Some(libc::EBADF) {
+                self.invalid.store(true, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+impl AsRawFd for TunFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for TunFile {
+    fn drop(&mut self) {
+        if self.invalid.load(Ordering::Relaxed) {
+            log::warn!("Not closing invalidated TUN fd {}", self.file.as_raw_fd());
+        } else {
+            // SAFETY: not used after this.
+            unsafe { ManuallyDrop::drop(&mut self.file) };
+        }
+    }
+}
+
+/// The read strategy to use on an Android device with the given API level.
+///
+/// Not backed by device measurements yet (the `read_strategy_throughput` test is the
+/// harness for them); until then, the split follows where the epoll path costs more.
+/// Epoll needs an `epoll_wait` wakeup, the reads and a final `EAGAIN` read per burst,
+/// while the blocking thread needs one `poll` and the read per packet plus a channel
+/// hop. Older kernels, up to Android 9 (API 28), are the ones reported to deliver TUN
+/// readiness poorly, so they get the blocking thread; newer ones keep the reactor.
+pub fn recommended_read_strategy(api_level: u32) -> TunReadStrategy {
+    if api_level < 29 {
+        TunReadStrategy::BlockingThread
+    } else {
+        TunReadStrategy::Epoll
+    }
+}
+
+/// Threads started for TUN backends, so the data plane can wait for them to exit
+/// instead of leaving them behind.
+#[derive(Clone, Default)]
+pub struct TunThreads(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl TunThreads {
+    fn spawn(&self, name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+        let handle = std::thread::Builder::new().name(name.into()).spawn(f)?;
+        self.0.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for all threads to exit. The readers and writers must have
+    /// been dropped; a thread blocked in an embedder callback is left behind.
+    pub async fn join(&self, timeout: Duration) {
+        let handles = std::mem::take(&mut *self.0.lock().unwrap());
+        if handles.is_empty() {
+            return;
+        }
+        let join = tokio::task::spawn_blocking(move || {
+            for handle in handles {
+                let name = handle.thread().name().unwrap_or_default().to_owned();
+                if handle.join().is_err() {
+                    log::error!("TUN thread {name} panicked");
+                }
+            }
+        });
+        if tokio::time::timeout(timeout, join).await.is_err() {
+            log::warn!("TUN threads still running after {timeout:?}, leaving them behind");
+        }
+    }
+}
+
+/// Opens the backend, returning its read and write halves. Threads it needs are
+/// registered with `threads`.
+pub fn open(
+    backend: TunBackend,
+    strategy: TunReadStrategy,
+    threads: &TunThreads,
+) -> io::Result<(TunReader, TunWriter)> {
+    match backend {
+        TunBackend::Fd(fd) => {
+            // Set to non-blocking mode for AsyncFd, unless a dedicated thread does blocking reads
+            if strategy == TunReadStrategy::Epoll {
+                set_nonblocking(fd)?;
+            }
+
+            // Create File from raw fd. unsafe because we assume ownership of fd.
+            // We wrap it in AsyncFd to use with tokio
+            let tun_file = TunFile::new(unsafe { File::from_raw_fd(fd) });
+            let tun = Arc::new(AsyncFd::new(tun_file)?);
+
+            let reader = match strategy {
+                TunReadStrategy::Epoll => TunReader::Epoll {
+                    tun: tun.clone(),
+                    slab: PacketSlab::new(),
+                },
+                TunReadStrategy::BlockingThread => {
+                    let file = tun.get_ref().try_clone()?;
+                    TunReader::spawn_thread(threads, "tun-reader", move |tx, stop| {
+                        blocking_read_loop(file, tx, stop)
+                    })?
+                }
+            };
+            Ok((reader, TunWriter::Fd(tun)))
+        }
+        TunBackend::Flow(flow) => {
+            let read_flow = flow.clone();
+            let reader = TunReader::spawn_thread(threads, "tun-flow-reader", move |tx, stop| {
+                flow_read_loop(read_flow.as_ref(), tx, stop)
+            })?;
+            let (tx, packets) = mpsc::channel(WRITER_QUEUE_DEPTH);
+            threads.spawn("tun-flow-writer", move || {
+                flow_write_loop(flow.as_ref(), packets)
+            })?;
+            Ok((reader, TunWriter::Flow(tx)))
+        }
+    }
+}
+
+/// Reads packets from the TUN backend.
+pub enum TunReader {
+    /// Readiness-based reads on the runtime's reactor (requires a non-blocking fd).
+    Epoll {
+        tun: Arc<AsyncFd<TunFile>>,
+        slab: PacketSlab,
+    },
+    /// A dedicated thread doing blocking reads, feeding packets through a channel.
+    Thread {
+        packets: mpsc::Receiver<io::Result<Bytes>>,
+        stop: Arc<AtomicBool>,
+    },
+}
+
+impl TunReader {
+    fn spawn_thread(
+        threads: &TunThreads,
+        name: &str,
+        read_loop: impl FnOnce(mpsc::Sender<io::Result<Bytes>>, Arc<AtomicBool>) + Send + 'static,
+    ) -> io::Result<Self> {
+        let (tx, packets) = mpsc::channel(READER_QUEUE_DEPTH);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        threads.spawn(name, move || read_loop(tx, thread_stop))?;
+        Ok(Self::Thread { packets, stop })
+    }
+
+    /// Reads the next packet. An empty packet signals EOF. Cancel safe.
+    pub async fn read(&mut self) -> io::Result<Bytes> {
+        match self {
+            Self::Epoll { tun, slab } => loop {
+                let mut guard = tun.readable().await?;
+                match guard.try_io(|inner| slab.read(|buf| inner.get_ref().read(buf))) {
+                    Ok(res) => return res,
+                    Err(_would_block) => continue,
+                }
+            },
+            Self::Thread { packets, .. } => match packets.recv().await {
+                Some(res) => res,
+                None => Ok(Bytes::new()),
+            },
+        }
+    }
+}
+
+impl Drop for TunReader {
+    fn drop(&mut self) {
+        if let Self::Thread { stop, .. } = self {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes packets to the TUN backend.
+#[derive(Clone)]
+pub enum TunWriter {
+    Fd(Arc<AsyncFd<TunFile>>),
+    /// Feeds the packet flow's writer thread, so a slow embedder never blocks the runtime.
+    Flow(mpsc::Sender<Bytes>),
+}
+
+impl TunWriter {
+    /// Writes `packet` if the TUN can take it right away, failing with `WouldBlock`
+    /// otherwise, in which case the caller still has the packet to retry later. On a
+    /// blocking fd (see `TunReadStrategy::BlockingThread`) it may block.
+    pub fn try_write(&self, packet: &Bytes) -> io::Result<()> {
+        match self {
+            Self::Fd(tun) => tun.get_ref().write(packet).map(|_| ()),
+            Self::Flow(tx) => tx.try_send(packet.clone()).map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => io::ErrorKind::WouldBlock.into(),
+                mpsc::error::TrySendError::Closed(_) => flow_closed(),
+            }),
+        }
+    }
+
+    pub async fn write(&self, packet: Bytes) -> io::Result<()> {
+        match self {
+            // We loop until we can write or error
+            Self::Fd(tun) => loop {
+                let mut guard = tun.writable().await?;
+                match guard.try_io(|inner| inner.get_ref().write(&packet)) {
+                    Ok(res) => return res.map(|_| ()),
+                    Err(_would_block) => continue,
+                }
+            },
+            Self::Flow(tx) => tx.send(packet).await.map_err(|_| flow_closed()),
+        }
+    }
+}
+
+fn flow_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "packet flow writer exited")
+}
+
+/// Storage that packets are read into and then split off from without copying.
+///
+/// Once all packets split off a slab have been dropped, its storage is reclaimed, so
+/// in the steady state reading allocates nothing. A new slab is only allocated while
+/// packets are still held elsewhere (e.g. in the uplink backlog).
+pub struct PacketSlab(BytesMut);
+
+impl PacketSlab {
+    fn new() -> Self {
+        let mut slab = Self(BytesMut::new());
+        slab.refill();
+        slab
+    }
+
+    /// Makes room for the next packet, reclaiming or (rarely) allocating storage.
+    fn refill(&mut self) {
+        if self.0.capacity() >= BUFFER_SIZE {
+            return;
+        }
+        if self.0.try_reclaim(SLAB_SIZE) {
+            return;
+        }
+        self.0 = BytesMut::with_capacity(SLAB_SIZE);
+        // Splitting promotes the storage to shared, which allocates; do it up front
+        // rather than for the first packet.
+        drop(self.0.split_to(0));
+    }
+
+    /// Reads one packet using `read` and returns it, sharing the slab's storage.
+    fn read(&mut self, read: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<Bytes> {
+        self.refill();
+        let _scope = alloc_audit::Scope::enter("tun read");
+        self.0.resize(BUFFER_SIZE, 0);
+        let res = read(&mut self.0[..]);
+        self.0.truncate(*res.as_ref().unwrap_or(&0));
+        let packet = self.0.split().freeze();
+        res.map(|_| packet)
+    }
+}
+
+fn blocking_read_loop(file: TunFile, tx: mpsc::Sender<io::Result<Bytes>>, stop: Arc<AtomicBool>) {
+    log::info!("Blocking TUN reader started");
+    let mut slab = PacketSlab::new();
+    let mut pfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !stop.load(Ordering::Relaxed) {
+        // Poll with a timeout so the thread notices shutdown even on an idle TUN.
+        let ready = unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            let _ = tx.blocking_send(Err(err));
+            break;
+        }
+        if ready == 0 {
+            continue;
+        }
+        let res = slab.read(|buf| file.read(buf));
+        let done = !matches!(&res, Ok(p) if !p.is_empty());
+        if tx.blocking_send(res).is_err() || done {
+            break;
+        }
+    }
+    log::info!("Blocking TUN reader exiting");
+}
+
+fn flow_read_loop(
+    flow: &dyn PacketFlow,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    stop: Arc<AtomicBool>,
+) {
+    log::info!("Packet flow reader started");
+    while !stop.load(Ordering::Relaxed) {
+        // Blocks in the embedder until packets are available; none at all means EOF.
+        let packets = flow.read_packets();
+        if packets.is_empty() {
+            log::info!("Packet flow EOF");
+            let _ = tx.blocking_send(Ok(Bytes::new()));
+            break;
+        }
+        for packet in packets {
+            if packet.is_empty() {
+                continue;
+            }
+            if tx.blocking_send(Ok(Bytes::from(packet))).is_err() {
+                log::info!("Packet flow reader exiting");
+                return;
+            }
+        }
+    }
+    log::info!("Packet flow reader exiting");
+}
+
+/// Hands packets to the embedder in batches until all writers are gone.
+fn flow_write_loop(flow: &dyn PacketFlow, mut packets: mpsc::Receiver<Bytes>) {
+    log::info!("Packet flow writer started");
+    let mut batch = Vec::with_capacity(MAX_WRITE_BATCH);
+    while let Some(packet) = packets.blocking_recv() {
+        // The copies are inherent to the FFI boundary, which lowers into fresh buffers
+        // anyway; they happen here rather than on the runtime.
+        batch.push(packet.to_vec());
+        while batch.len() < MAX_WRITE_BATCH {
+            match packets.try_recv() {
+                Ok(packet) => batch.push(packet.to_vec()),
+                Err(_) => break,
+            }
+        }
+        flow.write_packets(std::mem::take(&mut batch));
+    }
+    log::info!("Packet flow writer exiting");
+}
+
+/// Duplicates `fd`, so it outlives the data plane closing the original.
+pub fn dup_fd(fd: RawFd) -> io::Result<RawFd> {
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(dup)
+}
+
+fn set_nonblocking(fd: i32) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    log::info!("Set fd {fd} to non-blocking mode");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::fixtures;
+
+    /// A connected `SOCK_SEQPACKET` pair, which keeps packet boundaries like a TUN fd.
+    fn socketpair() -> (RawFd, File) {
+        let mut fds = [0; 2];
+        let res =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(res, 0, "socketpair: {}", io::Error::last_os_error());
+        (fds[0], unsafe { File::from_raw_fd(fds[1]) })
+    }
+
+    /// Packet flow serving `reads` batch by batch (then EOF), recording writes.
+    #[derive(Default)]
+    struct FakeFlow {
+        reads: Mutex<std::collections::VecDeque<Vec<Vec<u8>>>>,
+        written: Mutex<Vec<Vec<Vec<u8>>>>,
+    }
+
+    impl PacketFlow for FakeFlow {
+        fn read_packets(&self) -> Vec<Vec<u8>> {
+            self.reads.lock().unwrap().pop_front().unwrap_or_default()
+        }
+
+        fn write_packets(&self, packets: Vec<Vec<u8>>) {
+            self.written.lock().unwrap().push(packets);
+        }
+    }
+
+    #[tokio::test]
+    async fn flow_reads_until_empty_batch() {
+        let flow = Arc::new(FakeFlow::default());
+        flow.reads
+            .lock()
+            .unwrap()
+            .extend([vec![vec![1], vec![], vec![2]], vec![vec![3]]]);
+        let threads = TunThreads::default();
+        let (mut reader, writer) =
+            open(TunBackend::Flow(flow), TunReadStrategy::Epoll, &threads).unwrap();
+        for expected in [&[1][..], &[2], &[3], &[]] {
+            assert_eq!(reader.read().await.unwrap(), expected);
+        }
+        drop((reader, writer));
+        threads.join(Duration::from_secs(5)).await;
+        assert!(threads.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn flow_writes_in_order_on_writer_thread() {
+        let flow = Arc::new(FakeFlow::default());
+        let threads = TunThreads::default();
+        let (reader, writer) = open(
+            TunBackend::Flow(flow.clone()),
+            TunReadStrategy::Epoll,
+            &threads,
+        )
+        .unwrap();
+        for i in 0..100u8 {
+            writer.write(Bytes::from(vec![i])).await.unwrap();
+        }
+        drop((reader, writer));
+        threads.join(Duration::from_secs(5)).await;
+        let written: Vec<u8> = flow
+            .written
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|p| p[0])
+            .collect();
+        assert_eq!(written, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn flow_try_write_reports_full_queue() {
+        let (tx, packets) = mpsc::channel(1);
+        let writer = TunWriter::Flow(tx);
+        writer.try_write(&Bytes::from_static(&[1])).unwrap();
+        let e = writer.try_write(&Bytes::from_static(&[2])).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        drop(packets);
+        let e = writer.try_write(&Bytes::from_static(&[3])).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    /// Reads, writes and sees EOF through a TUN fd backend.
+    async fn fd_reads_writes_and_sees_eof(strategy: TunReadStrategy) {
+        let (tun_fd, peer) = socketpair();
+        let threads = TunThreads::default();
+        let (mut reader, writer) = open(TunBackend::Fd(tun_fd), strategy, &threads).unwrap();
+
+        let syn = fixtures::syn(fixtures::client(), fixtures::server(), Some(1460));
+        (&peer).write_all(&syn).unwrap();
+        assert_eq!(reader.read().await.unwrap(), syn);
+
+        let syn_ack = Bytes::from(fixtures::syn_ack(
+            fixtures::server(),
+            fixtures::client(),
+            Some(1460),
+        ));
+        writer.write(syn_ack.clone()).await.unwrap();
+        writer.try_write(&syn_ack).unwrap();
+        let mut buf = [0; BUFFER_SIZE];
+        for _ in 0..2 {
+            let n = (&peer).read(&mut buf).unwrap();
+            assert_eq!(buf[..n], syn_ack);
+        }
+
+        drop(peer);
+        assert!(reader.read().await.unwrap().is_empty());
+        drop((reader, writer));
+        threads.join(Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn epoll_fd_reads_writes_and_sees_eof() {
+        fd_reads_writes_and_sees_eof(TunReadStrategy::Epoll).await;
+    }
+
+    #[tokio::test]
+    async fn blocking_fd_reads_writes_and_sees_eof() {
+        fd_reads_writes_and_sees_eof(TunReadStrategy::BlockingThread).await;
+    }
+
+    /// Dropping both halves stops the reader thread, if any, and closes the fd.
+    async fn fd_is_released_on_drop(strategy: TunReadStrategy) {
+        let (tun_fd, peer) = socketpair();
+        set_nonblocking(peer.as_raw_fd()).unwrap();
+        let threads = TunThreads::default();
+        let (reader, writer) = open(TunBackend::Fd(tun_fd), strategy, &threads).unwrap();
+        drop((reader, writer));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !threads.0.lock().unwrap().iter().all(|t| t.is_finished()) {
+            assert!(Instant::now() < deadline, "TUN reader thread still running");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        threads.join(Duration::from_secs(5)).await;
+        assert_eq!((&peer).read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn epoll_fd_is_released_on_drop() {
+        fd_is_released_on_drop(TunReadStrategy::Epoll).await;
+    }
+
+    #[tokio::test]
+    async fn blocking_fd_is_released_on_drop() {
+        fd_is_released_on_drop(TunReadStrategy::BlockingThread).await;
+    }
+
+    #[test]
+    fn recommends_blocking_thread_on_old_api_levels() {
+        assert_eq!(
+            recommended_read_strategy(26),
+            TunReadStrategy::BlockingThread
+        );
+        assert_eq!(
+            recommended_read_strategy(28),
+            TunReadStrategy::BlockingThread
+        );
+        assert_eq!(recommended_read_strategy(29), TunReadStrategy::Epoll);
+        assert_eq!(recommended_read_strategy(35), TunReadStrategy::Epoll);
+    }
+
+    /// Compares the read strategies on this machine:
+    /// `cargo test --release -- --ignored --nocapture read_strategy_throughput`.
+    /// Meant to be run on devices of different API levels to back
+    /// [`recommended_read_strategy`]; a socketpair stands in for the TUN fd.
+    #[test]
+    #[ignore]
+    fn read_strategy_throughput() {
+        const PACKETS: usize = 200_000;
+        const PACKET_SIZE: usize = 1400;
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _runtime = rt.enter();
+        for strategy in [TunReadStrategy::Epoll, TunReadStrategy::BlockingThread] {
+            let (tun_fd, peer) = socketpair();
+            let (mut reader, _writer) =
+                open(TunBackend::Fd(tun_fd), strategy, &TunThreads::default()).unwrap();
+            let start = Instant::now();
+            let sender = std::thread::spawn(move || {
+                let packet = [0x45; PACKET_SIZE];
+                for _ in 0..PACKETS {
+                    (&peer).write_all(&packet).unwrap();
+                }
+            });
+            let received = rt.block_on(async {
+                let mut received = 0;
+                while received < PACKETS {
+                    let packet = reader.read().await.unwrap();
+                    assert_eq!(packet.len(), PACKET_SIZE);
+                    received += 1;
+                }
+                received
+            });
+            sender.join().unwrap();
+            let elapsed = start.elapsed();
+            println!(
+                "{strategy:?}: {received} packets in {elapsed:?}, {:.0} packets/s, {:.0} Mbit/s",
+                received as f64 / elapsed.as_secs_f64(),
+                (received * PACKET_SIZE * 8) as f64 / elapsed.as_secs_f64() / 1e6,
+            );
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use edge_tun::client::Outgoing;
+use tokio::sync::Notify;
+
+use crate::mtu::{self, MtuFallback};
+use crate::packet::addresses;
+use crate::stats::Stats;
+use crate::uplink_buffer::UplinkBuffer;
+
+/// Extra attempts made for a packet whose send failed.
+const MAX_SEND_RETRIES: u32 = 3;
+/// Backoff before the first retry; doubled for each further one.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(5);
+/// Packets in a row that couldn't be sent (retries included) before a reconnect is requested.
+const RECONNECT_THRESHOLD: u32 = 8;
+
+/// Where [`Uplink`] sends packets: an edgetun session, or a fake in tests.
+pub trait UplinkSink: Send {
+    fn send_wait(&mut self, packet: Bytes) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl UplinkSink for Outgoing {
+    fn send_wait(&mut self, packet: Bytes) -> impl Future<Output = anyhow::Result<()>> + Send {
+        Outgoing::send_wait(self, packet)
+    }
+}
+
+/// Sending side of the tunnel.
+///
+/// Failed sends are retried with exponential backoff. Packets that still can't be sent
+/// are buffered and replayed in order once sending works again. After
+/// [`RECONNECT_THRESHOLD`] failed packets in a row the tunnel is considered down:
+/// `reconnect` is notified and retries are skipped until a send succeeds again.
+///
+/// A packet rejected as too large for the path is dropped right away: retrying or
+/// replaying it would fail the same way. Such failures are reported to `mtu_fallback`
+/// rather than counted towards a reconnect.
+///
+/// Packets leave in the order they were read from the TUN, across all flows: one task
+/// sends them one at a time, a retried packet holds back everything after it, and the
+/// backlog is replayed oldest first ahead of new packets. Beyond that edgetun carries
+/// them as QUIC datagrams, so the network may reorder them like any IP packets, and
+/// right after a session rotation the first packets on the new session may overtake
+/// the last ones still in flight on the old one.
+pub struct Uplink<S = Outgoing> {
+    edge_write: S,
+    /// Addresses assigned to this client; packets from other sources are not sent.
+    sources: Vec<IpAddr>,
+    backlog: UplinkBuffer,
+    stats: Arc<Stats>,
+    reconnect: Arc<Notify>,
+    mtu_fallback: Arc<MtuFallback>,
+    consecutive_failures: u32,
+    /// Set once a reconnect was requested, until a send succeeds or the session is
+    /// replaced.
+    reconnect_pending: bool,
+}
+
+enum SendOutcome {
+    Sent,
+    Failed,
+    TooLarge,
+}
+
+impl<S: UplinkSink> Uplink<S> {
+    pub fn new(
+        edge_write: S,
+        sources: Vec<IpAddr>,
+        backlog: UplinkBuffer,
+        stats: Arc<Stats>,
+        reconnect: Arc<Notify>,
+        mtu_fallback: Arc<MtuFallback>,
+    ) -> Self {
+        Self {
+            edge_write,
+            sources,
+            backlog,
+            stats,
+            reconnect,
+            mtu_fallback,
+            consecutive_failures: 0,
+            reconnect_pending: false,
+        }
+    }
+
+    /// Switches to a new session and replays the buffered packets on it right away,
+    /// rather than holding them until the next packet is read from the TUN.
+    pub async fn replace(&mut self, edge_write: S, sources: Vec<IpAddr>) {
+        self.edge_write = edge_write;
+        self.sources = sources;
+        self.consecutive_failures = 0;
+        self.reconnect_pending = false;
+        if !self.backlog.is_empty() {
+            self.replay_backlog().await;
+        }
+    }
+
+    /// The addresses assigned to this client.
+    pub fn sources(&self) -> &[IpAddr] {
+        &self.sources
+    }
+
+    /// Whether `packet` comes from one of the addresses assigned to this client.
+    pub fn is_valid_source(&self, packet: &[u8]) -> bool {
+        addresses(packet).is_some_and(|(src, _)| self.sources.contains(&src))
+    }
+
+    /// Sends an uplink packet, buffering it for replay if the tunnel is failing.
+    pub async fn send(&mut self, packet: Bytes) {
+        if !self.backlog.is_empty() && !self.replay_backlog().await {
+            self.buffer(packet);
+            return;
+        }
+        match self.send_with_retry(&packet).await {
+            SendOutcome::Sent => {}
+            SendOutcome::Failed => self.buffer(packet),
+            SendOutcome::TooLarge => self.drop_too_large(&packet),
+        }
+    }
+
+    /// Sends buffered uplink packets in order. Returns false if the tunnel is still failing.
+    async fn replay_backlog(&mut self) -> bool {
+        while let Some(packet) = self.backlog.pop() {
+            match self.send_with_retry(&packet).await {
+                SendOutcome::Sent => {
+                    Stats::add(&self.stats.uplink.replayed_bytes, packet.len());
+                }
+                SendOutcome::Failed => {
+                    self.backlog.unpop(packet);
+                    return false;
+                }
+                SendOutcome::TooLarge => self.drop_too_large(&packet),
+            }
+        }
+        log::info!("Uplink backlog replayed");
+        true
+    }
+
+    async fn send_with_retry(&mut self, packet: &Bytes) -> SendOutcome {
+        let retries = if self.consecutive_failures < RECONNECT_THRESHOLD {
+            MAX_SEND_RETRIES
+        } else {
+            0
+        };
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.edge_write.send_wait(packet.clone()).await {
+                Ok(()) => {
+                    self.consecutive_failures = 0;
+                    self.reconnect_pending = false;
+                    return SendOutcome::Sent;
+                }
+                Err(e) if mtu::is_too_large(&e) => {
+                    log::debug!("Dropping {} byte packet, too large: {e}", packet.len());
+                    return SendOutcome::TooLarge;
+                }
+                Err(e) if attempt < retries => {
+                    log::debug!("UDP send error, retrying in {backoff:?}: {e}");
+                }
+                Err(e) => {
+                    log::error!("UDP send error: {e}");
+                    break;
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+            Stats::add(&self.stats.uplink.tx_retried_packets, 1);
+        }
+
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= RECONNECT_THRESHOLD && !self.reconnect_pending {
+            self.reconnect_pending = true;
+            log::warn!(
+                "{RECONNECT_THRESHOLD} uplink packets in a row failed, requesting reconnect"
+            );
+            self.reconnect.notify_one();
+        }
+        SendOutcome::Failed
+    }
+
+    fn drop_too_large(&self, packet: &Bytes) {
+        Stats::add(&self.stats.uplink.tx_dropped_packets, 1);
+        self.mtu_fallback.too_large(packet.len());
+    }
+
+    fn buffer(&mut self, packet: Bytes) {
+        if let Err(packet) = self.backlog.push(packet) {
+            Stats::add(&self.stats.uplink.buffer_dropped_bytes, packet.len());
+            Stats::add(&self.stats.uplink.tx_dropped_packets, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records sent packets; sends fail while `failing` is set, and the next results
+    /// can be scripted through `results`.
+    #[derive(Clone, Default)]
+    struct FakeSink {
+        sent: Arc<Mutex<Vec<Bytes>>>,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+        results: Arc<Mutex<VecDeque<anyhow::Result<()>>>>,
+    }
+
+    impl UplinkSink for FakeSink {
+        async fn send_wait(&mut self, packet: Bytes) -> anyhow::Result<()> {
+            if let Some(res) = self.results.lock().unwrap().pop_front() {
+                return res.map(|()| self.sent.lock().unwrap().push(packet));
+            }
+            if self.failing.load(Ordering::Relaxed) {
+                anyhow::bail!("network unreachable");
+            }
+            self.sent.lock().unwrap().push(packet);
+            Ok(())
+        }
+    }
+
+    impl FakeSink {
+        fn sent(&self) -> Vec<u8> {
+            self.sent.lock().unwrap().iter().map(|p| p[0]).collect()
+        }
+    }
+
+    fn uplink(sink: FakeSink) -> (Uplink<FakeSink>, Arc<Stats>, Arc<Notify>) {
+        let stats = Arc::new(Stats::default());
+        let reconnect = Arc::new(Notify::new());
+        let uplink = Uplink::new(
+            sink,
+            Vec::new(),
+            UplinkBuffer::new(64 * 1024, 64 * 1024),
+            stats.clone(),
+            reconnect.clone(),
+            Arc::new(MtuFallback::default()),
+        );
+        (uplink, stats, reconnect)
+    }
+
+    /// A packet identified by its first byte; it doesn't need to parse as IP here.
+    fn packet(tag: u8) -> Bytes {
+        Bytes::from(vec![tag; 100])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff() {
+        let sink = FakeSink::default();
+        sink.results
+            .lock()
+            .unwrap()
+            .extend([Err(anyhow::anyhow!("busy")), Err(anyhow::anyhow!("busy"))]);
+        let (mut uplink, stats, _) = uplink(sink.clone());
+
+        let start = tokio::time::Instant::now();
+        uplink.send(packet(1)).await;
+        assert_eq!(sink.sent(), [1]);
+        assert_eq!(start.elapsed(), Duration::from_millis(5 + 10));
+        assert_eq!(stats.uplink.tx_retried_packets.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_retrying_once_the_tunnel_is_down() {
+        let sink = FakeSink::default();
+        sink.failing.store(true, Ordering::Relaxed);
+        let (mut uplink, stats, _) = uplink(sink.clone());
+
+        for tag in 0..RECONNECT_THRESHOLD as u8 {
+            let start = tokio::time::Instant::now();
+            uplink.send(packet(tag)).await;
+            assert_eq!(
+                start.elapsed(),
+                Duration::from_millis(5 + 10 + 20),
+                "packet {tag}"
+            );
+        }
+        let start = tokio::time::Instant::now();
+        uplink.send(packet(100)).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(
+            stats.uplink.tx_retried_packets.load(Ordering::Relaxed),
+            u64::from(RECONNECT_THRESHOLD * MAX_SEND_RETRIES)
+        );
+
+        // A successful send brings retries back.
+        sink.failing.store(false, Ordering::Relaxed);
+        uplink.send(packet(101)).await;
+        sink.failing.store(true, Ordering::Relaxed);
+        let start = tokio::time::Instant::now();
+        uplink.send(packet(102)).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(5 + 10 + 20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_backlog_on_replaced_session() {
+        let dead = FakeSink::default();
+        dead.failing.store(true, Ordering::Relaxed);
+        let (mut uplink, stats, _) = uplink(dead.clone());
+        uplink.send(packet(1)).await;
+        uplink.send(packet(2)).await;
+        assert!(dead.sent().is_empty());
+
+        // Replayed as soon as the new session is there, not with the next packet.
+        let fresh = FakeSink::default();
+        uplink.replace(fresh.clone(), Vec::new()).await;
+        assert_eq!(fresh.sent(), [1, 2]);
+        uplink.send(packet(3)).await;
+        assert_eq!(fresh.sent(), [1, 2, 3]);
+        assert_eq!(stats.uplink.replayed_bytes.load(Ordering::Relaxed), 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_packets_queue_behind_backlog() {
+        let sink = FakeSink::default();
+        sink.failing.store(true, Ordering::Relaxed);
+        let (mut uplink, _, _) = uplink(sink.clone());
+        uplink.send(packet(1)).await;
+        uplink.send(packet(2)).await;
+
+        sink.failing.store(false, Ordering::Relaxed);
+        uplink.send(packet(3)).await;
+        assert_eq!(sink.sent(), [1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_too_large_packets_without_retrying() {
+        let sink = FakeSink::default();
+        sink.results
+            .lock()
+            .unwrap()
+            .push_back(Err(quinn::SendDatagramError::TooLarge.into()));
+        let (mut uplink, stats, _) = uplink(sink.clone());
+        uplink.send(packet(1)).await;
+        uplink.send(packet(2)).await;
+        assert_eq!(sink.sent(), [2]);
+        assert_eq!(stats.uplink.tx_retried_packets.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.uplink.tx_dropped_packets.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_reconnect_after_threshold() {
+        let sink = FakeSink::default();
+        sink.failing.store(true, Ordering::Relaxed);
+        let (mut uplink, _, reconnect) = uplink(sink);
+        let requested = reconnect.notified();
+        tokio::pin!(requested);
+        for tag in 0..RECONNECT_THRESHOLD as u8 {
+            assert!(poll_once(requested.as_mut()).is_none(), "packet {tag}");
+            uplink.send(packet(tag)).await;
+        }
+        assert!(poll_once(requested.as_mut()).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_reconnect_again_after_replace() {
+        let sink = FakeSink::default();
+        sink.failing.store(true, Ordering::Relaxed);
+        let (mut uplink, _, reconnect) = uplink(sink.clone());
+        for tag in 0..RECONNECT_THRESHOLD as u8 {
+            uplink.send(packet(tag)).await;
+        }
+        assert!(poll_once(std::pin::pin!(reconnect.notified())).is_some());
+
+        // Still failing: no further requests while one is pending.
+        uplink.send(packet(100)).await;
+        assert!(poll_once(std::pin::pin!(reconnect.notified())).is_none());
+
+        // The replacement fails as well, which needs another reconnect.
+        uplink.replace(sink, Vec::new()).await;
+        for tag in 0..RECONNECT_THRESHOLD as u8 {
+            uplink.send(packet(tag)).await;
+        }
+        assert!(poll_once(std::pin::pin!(reconnect.notified())).is_some());
+    }
+
+    /// Polls `fut` once.
+    fn poll_once<F: Future>(fut: std::pin::Pin<&mut F>) -> Option<F::Output> {
+        let waker = std::task::Waker::noop();
+        match fut.poll(&mut std::task::Context::from_waker(waker)) {
+            std::task::Poll::Ready(out) => Some(out),
+            std::task::Poll::Pending => None,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{HashMap, VecDeque};
+
+use bytes::Bytes;
+
+use crate::packet::{flow_key, FlowKey};
+
+/// Holds uplink packets while the tunnel can't take them, so they can be replayed
+/// in order once sending works again.
+///
+/// Each flow may occupy at most `per_flow_limit` bytes and the whole buffer at most
+/// `total_limit` bytes; packets beyond that are dropped.
+pub struct UplinkBuffer {
+    per_flow_limit: usize,
+    total_limit: usize,
+    total: usize,
+    packets: VecDeque<(FlowKey, Bytes)>,
+    flow_bytes: HashMap<FlowKey, usize>,
+}
+
+impl UplinkBuffer {
+    pub fn new(per_flow_limit: usize, total_limit: usize) -> Self {
+        Self {
+            per_flow_limit,
+            total_limit,
+            total: 0,
+            packets: VecDeque::new(),
+            flow_bytes: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Buffers a packet. Returns it back if it exceeds the flow or total limit.
+    pub fn push(&mut self, packet: Bytes) -> Result<(), Bytes> {
+        let key = flow_key(&packet);
+        let flow = self.flow_bytes.get(&key).copied().unwrap_or(0);
+        if flow + packet.len() > self.per_flow_limit || self.total + packet.len() > self.total_limit
+        {
+            return Err(packet);
+        }
+        self.total += packet.len();
+        *self.flow_bytes.entry(key).or_default() += packet.len();
+        self.packets.push_back((key, packet));
+        Ok(())
+    }
+
+    /// Takes the oldest buffered packet.
+    pub fn pop(&mut self) -> Option<Bytes> {
+        let (key, packet) = self.packets.pop_front()?;
+        self.total -= packet.len();
+        if let Some(flow) = self.flow_bytes.get_mut(&key) {
+            *flow -= packet.len();
+            if *flow == 0 {
+                self.flow_bytes.remove(&key);
+            }
+        }
+        Some(packet)
+    }
+
+    /// Puts a packet taken with [`UplinkBuffer::pop`] back at the front, bypassing the limits.
+    pub fn unpop(&mut self, packet: Bytes) {
+        let key = flow_key(&packet);
+        self.total += packet.len();
+        *self.flow_bytes.entry(key).or_default() += packet.len();
+        self.packets.push_front((key, packet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::fixtures;
+
+    /// An IPv4/UDP packet of `len` bytes from `src_port`, which identifies its flow.
+    fn packet(src_port: u16, len: usize, tag: u8) -> Bytes {
+        let src = SocketAddr::new(fixtures::client().ip(), src_port);
+        let payload = vec![tag; len.max(28) - 28];
+        Bytes::from(fixtures::udp(src, fixtures::server(), &payload))
+    }
+
+    #[test]
+    fn pops_in_push_order_across_flows() {
+        let mut buffer = UplinkBuffer::new(1000, 1000);
+        for (port, tag) in [(1, 1), (2, 2), (1, 3)] {
+            buffer.push(packet(port, 100, tag)).unwrap();
+        }
+        let tags: Vec<_> = std::iter::from_fn(|| buffer.pop()).map(|p| p[99]).collect();
+        assert_eq!(tags, [1, 2, 3]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn enforces_per_flow_limit() {
+        let mut buffer = UplinkBuffer::new(250, 1000);
+        buffer.push(packet(1, 100, 0)).unwrap();
+        buffer.push(packet(1, 100, 0)).unwrap();
+        assert!(buffer.push(packet(1, 100, 0)).is_err());
+        // Other flows still have room.
+        buffer.push(packet(2, 100, 0)).unwrap();
+    }
+
+    #[test]
+    fn enforces_total_limit() {
+        let mut buffer = UplinkBuffer::new(1000, 250);
+        buffer.push(packet(1, 100, 0)).unwrap();
+        buffer.push(packet(2, 100, 0)).unwrap();
+        let rejected = buffer.push(packet(3, 100, 7)).unwrap_err();
+        assert_eq!(rejected[99], 7);
+    }
+
+    #[test]
+    fn pop_frees_room_and_unpop_bypasses_limits() {
+        let mut buffer = UplinkBuffer::new(200, 200);
+        buffer.push(packet(1, 100, 1)).unwrap();
+        buffer.push(packet(1, 100, 2)).unwrap();
+        let first = buffer.pop().unwrap();
+        buffer.push(packet(1, 100, 3)).unwrap();
+
+        // Back at the front, although the flow is at its limit again.
+        buffer.unpop(first);
+        assert!(buffer.push(packet(1, 1, 0)).is_err());
+        let tags: Vec<_> = std::iter::from_fn(|| buffer.pop()).map(|p| p[99]).collect();
+        assert_eq!(tags, [1, 2, 3]);
+        buffer.push(packet(1, 200, 0)).unwrap();
+    }
+}